@@ -0,0 +1,206 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::llm::LlmError;
+
+/// File in the app config directory holding the API keys.
+pub const API_KEYS_FILE: &str = "api_keys.json";
+
+const KEY_PREFIX: &str = "emchat-";
+/// How much of a key is kept in the clear so users can tell keys apart.
+const VISIBLE_KEY_LEN: usize = 12;
+
+pub type ApiKeys = Arc<Mutex<ApiKeyStore>>;
+
+/// Public description of an API key. The key itself is only shown once, when created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    /// Leading characters of the key, for recognising it.
+    pub prefix: String,
+    pub created_at: String,
+    /// Usage since the app started; not persisted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<String>,
+    #[serde(default)]
+    pub request_count: u64,
+}
+
+/// A freshly generated key, returned once from `create_api_key`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewApiKey {
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredKey {
+    #[serde(flatten)]
+    info: ApiKeyInfo,
+    /// SHA-256 of the key; the key itself is never written to disk.
+    sha256: String,
+}
+
+/// Bearer keys accepted by the local API server, persisted to a JSON file.
+/// The default store lives only in memory.
+#[derive(Debug, Default)]
+pub struct ApiKeyStore {
+    path: Option<PathBuf>,
+    keys: Vec<StoredKey>,
+}
+
+impl ApiKeyStore {
+    /// Load keys from `path`, starting empty if the file is missing or unreadable.
+    pub fn load(path: PathBuf) -> Self {
+        let keys = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str::<Vec<StoredKey>>(&contents).unwrap_or_else(|e| {
+                warn!("⚠️ Ignoring unreadable API key file {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        info!("🔑 Loaded {} API key(s) from {}", keys.len(), path.display());
+
+        let keys = keys
+            .into_iter()
+            .map(|mut key| {
+                key.info.last_used_at = None;
+                key.info.request_count = 0;
+                key
+            })
+            .collect();
+        Self { path: Some(path), keys }
+    }
+
+    pub fn shared(self) -> ApiKeys {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn list(&self) -> Vec<ApiKeyInfo> {
+        self.keys.iter().map(|k| k.info.clone()).collect()
+    }
+
+    pub fn create(&mut self, name: &str) -> Result<NewApiKey, LlmError> {
+        let key = format!("{}{}", KEY_PREFIX, uuid::Uuid::new_v4().simple());
+        let info = ApiKeyInfo {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            prefix: key[..VISIBLE_KEY_LEN].to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            last_used_at: None,
+            request_count: 0,
+        };
+
+        self.keys.push(StoredKey {
+            info: info.clone(),
+            sha256: hash(&key),
+        });
+        self.save()?;
+        info!("🔑 Created API key '{}' ({})", info.name, info.prefix);
+        Ok(NewApiKey { info, key })
+    }
+
+    pub fn revoke(&mut self, id: &str) -> Result<ApiKeyInfo, LlmError> {
+        let index = self
+            .keys
+            .iter()
+            .position(|k| k.info.id == id)
+            .ok_or_else(|| LlmError::ConfigError(format!("No API key with id {}", id)))?;
+        let removed = self.keys.remove(index);
+        self.save()?;
+        info!("🔑 Revoked API key '{}' ({})", removed.info.name, removed.info.prefix);
+        Ok(removed.info)
+    }
+
+    /// Look up the key and record its use.
+    fn authenticate(&mut self, key: &str) -> Option<ApiKeyInfo> {
+        let sha256 = hash(key);
+        let stored = self.keys.iter_mut().find(|k| k.sha256 == sha256)?;
+        stored.info.last_used_at = Some(chrono::Utc::now().to_rfc3339());
+        stored.info.request_count += 1;
+        Some(stored.info.clone())
+    }
+
+    fn save(&self) -> Result<(), LlmError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(&self.keys)?)?;
+        Ok(())
+    }
+}
+
+fn hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// State for `require_api_key`.
+#[derive(Clone)]
+pub(crate) struct AuthState {
+    pub keys: ApiKeys,
+    /// Keys are always required when the server is reachable from the network.
+    pub allow_lan: bool,
+}
+
+/// Check the bearer key and log each request against the key that made it.
+///
+/// A loopback-only server with no keys configured stays open, as before keys
+/// existed; once a key is created, or the server is exposed on the LAN, every
+/// request must present one.
+pub(crate) async fn require_api_key(State(auth): State<AuthState>, request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    let caller = {
+        let mut keys = auth.keys.lock().unwrap();
+        match token.and_then(|token| keys.authenticate(token)) {
+            Some(key) => format!("key '{}' ({})", key.name, key.prefix),
+            None if !auth.allow_lan && keys.is_empty() => "local client".to_string(),
+            None => {
+                warn!("🚫 {} {} rejected: {} API key", method, path, if token.is_some() { "invalid" } else { "missing" });
+                return unauthorized(&path);
+            }
+        }
+    };
+
+    let response = next.run(request).await;
+    info!("🔑 {} {} → {} by {}", method, path, response.status().as_u16(), caller);
+    response
+}
+
+fn unauthorized(path: &str) -> Response {
+    let message = "Invalid or missing API key";
+    // Ollama clients expect a bare string, OpenAI clients an error object.
+    let body = if path.starts_with("/api/") {
+        serde_json::json!({ "error": message })
+    } else {
+        serde_json::json!({ "error": { "message": message, "type": "invalid_request_error" } })
+    };
+    (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], Json(body)).into_response()
+}
@@ -2,8 +2,10 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use btleplug::api::{Central, Manager as _, Peripheral as _, ScanFilter};
-use btleplug::platform::{Adapter, Manager};
+use tokio::task::JoinHandle;
+use btleplug::api::{bleuuid::uuid_from_u16, Central, CharPropFlags, Manager as _, Peripheral as _, ScanFilter};
+use btleplug::platform::{Adapter, Manager, Peripheral};
+use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BluetoothDevice {
@@ -15,6 +17,23 @@ pub struct BluetoothDevice {
     pub services: Vec<String>,
 }
 
+/// A GATT characteristic of a connected device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BluetoothCharacteristic {
+    pub uuid: String,
+    pub service: String,
+    /// Supported operations, such as "read" or "notify".
+    pub properties: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CharacteristicValue {
+    pub uuid: String,
+    pub hex: String,
+    /// The value as text, when it is valid UTF-8.
+    pub text: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ScanResult {
     pub success: bool,
@@ -57,6 +76,7 @@ pub struct BluetoothScanner {
     adapter: Option<Adapter>,
     discovered_devices: Arc<Mutex<HashMap<String, BluetoothDevice>>>,
     is_scanning: Arc<Mutex<bool>>,
+    scan_task: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 impl BluetoothScanner {
@@ -65,6 +85,7 @@ impl BluetoothScanner {
             adapter: None,
             discovered_devices: Arc::new(Mutex::new(HashMap::new())),
             is_scanning: Arc::new(Mutex::new(false)),
+            scan_task: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -102,7 +123,7 @@ impl BluetoothScanner {
         let devices_map = Arc::clone(&self.discovered_devices);
         let scanning_flag = Arc::clone(&self.is_scanning);
 
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             while *scanning_flag.lock().await {
                 let peripherals = adapter_clone.peripherals().await.unwrap_or_default();
                 let mut devices = devices_map.lock().await;
@@ -126,6 +147,7 @@ impl BluetoothScanner {
                 tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
             }
         });
+        *self.scan_task.lock().await = Some(task);
 
         Ok("Bluetooth scan started successfully".to_string())
     }
@@ -147,6 +169,22 @@ impl BluetoothScanner {
         Ok("Bluetooth scan stopped successfully".to_string())
     }
 
+    /// Stop any active scan and wait for the polling task to exit.
+    ///
+    /// Unlike `stop_scan`, this never fails: the adapter is told to stop if
+    /// possible, and the background task is aborted regardless.
+    pub async fn shutdown(&self) {
+        if let Err(e) = self.stop_scan().await {
+            log::warn!("Failed to stop Bluetooth scan during shutdown: {}", e.message);
+            *self.is_scanning.lock().await = false;
+        }
+
+        if let Some(task) = self.scan_task.lock().await.take() {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+
     pub async fn get_discovered_devices(&self) -> Vec<BluetoothDevice> {
         let devices = self.discovered_devices.lock().await;
         devices.values().cloned().collect()
@@ -160,4 +198,87 @@ impl BluetoothScanner {
         let mut devices = self.discovered_devices.lock().await;
         devices.clear();
     }
+
+    /// The handle of a discovered device. Connecting to it and reading it is done
+    /// by `list_characteristics` and `read_characteristic`, so that callers need
+    /// not keep the scanner locked while the device answers.
+    pub async fn peripheral(&self, device_id: &str) -> Result<Peripheral, BluetoothError> {
+        let adapter = self.adapter.as_ref().ok_or_else(|| BluetoothError {
+            error_type: "NotInitialized".to_string(),
+            message: "Bluetooth adapter not initialized".to_string(),
+        })?;
+        adapter
+            .peripherals()
+            .await?
+            .into_iter()
+            .find(|peripheral| peripheral.id().to_string() == device_id)
+            .ok_or_else(|| BluetoothError {
+                error_type: "DeviceNotFound".to_string(),
+                message: format!("No discovered device with id {}", device_id),
+            })
+    }
+}
+
+/// Connect to a device if needed and discover its GATT services.
+async fn connect(peripheral: &Peripheral) -> Result<(), BluetoothError> {
+    if !peripheral.is_connected().await? {
+        peripheral.connect().await?;
+    }
+    peripheral.discover_services().await?;
+    Ok(())
+}
+
+pub async fn list_characteristics(peripheral: &Peripheral) -> Result<Vec<BluetoothCharacteristic>, BluetoothError> {
+    connect(peripheral).await?;
+    Ok(peripheral
+        .characteristics()
+        .into_iter()
+        .map(|characteristic| BluetoothCharacteristic {
+            uuid: characteristic.uuid.to_string(),
+            service: characteristic.service_uuid.to_string(),
+            properties: characteristic
+                .properties
+                .iter_names()
+                .map(|(name, _)| name.to_lowercase())
+                .collect(),
+        })
+        .collect())
+}
+
+/// Read a characteristic, given its full UUID or a 16-bit short form such as `2a19`.
+pub async fn read_characteristic(peripheral: &Peripheral, uuid: &str) -> Result<CharacteristicValue, BluetoothError> {
+    let uuid = parse_uuid(uuid)?;
+    connect(peripheral).await?;
+    let characteristic = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|characteristic| characteristic.uuid == uuid)
+        .ok_or_else(|| BluetoothError {
+            error_type: "CharacteristicNotFound".to_string(),
+            message: format!("Device {} has no characteristic {}", peripheral.id(), uuid),
+        })?;
+    if !characteristic.properties.contains(CharPropFlags::READ) {
+        return Err(BluetoothError {
+            error_type: "NotReadable".to_string(),
+            message: format!("Characteristic {} cannot be read", uuid),
+        });
+    }
+
+    let value = peripheral.read(&characteristic).await?;
+    Ok(CharacteristicValue {
+        uuid: uuid.to_string(),
+        hex: value.iter().map(|b| format!("{:02x}", b)).collect(),
+        text: String::from_utf8(value).ok(),
+    })
+}
+
+fn parse_uuid(uuid: &str) -> Result<Uuid, BluetoothError> {
+    let invalid = || BluetoothError {
+        error_type: "InvalidUuid".to_string(),
+        message: format!("'{}' is not a Bluetooth UUID", uuid),
+    };
+    match uuid.len() {
+        4 => u16::from_str_radix(uuid, 16).map(uuid_from_u16).map_err(|_| invalid()),
+        _ => Uuid::parse_str(uuid).map_err(|_| invalid()),
+    }
 }
@@ -0,0 +1,163 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::bluetooth::{self, BluetoothDevice, BluetoothError, BluetoothScanner};
+use crate::llm::{FunctionCall, FunctionDefinition, Tool};
+use crate::tools::ToolExecutor;
+
+const LIST_DEVICES: &str = "list_bluetooth_devices";
+const FIND_DEVICE: &str = "find_bluetooth_device";
+const START_SCAN: &str = "start_bluetooth_scan";
+const STOP_SCAN: &str = "stop_bluetooth_scan";
+const LIST_CHARACTERISTICS: &str = "list_bluetooth_characteristics";
+const READ_CHARACTERISTIC: &str = "read_bluetooth_characteristic";
+
+/// Built-in tools that give the model access to the `BluetoothScanner`.
+pub fn definitions() -> Vec<Tool> {
+    let tool = |name: &str, description: &str, parameters: Value| Tool {
+        kind: "function".to_string(),
+        function: FunctionDefinition {
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            parameters: Some(parameters),
+        },
+    };
+    let no_parameters = json!({ "type": "object", "properties": {} });
+    vec![
+        tool(
+            LIST_DEVICES,
+            "List the Bluetooth devices discovered so far, strongest signal (RSSI) first.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "limit": { "type": "integer", "description": "Return at most this many devices." }
+                }
+            }),
+        ),
+        tool(
+            FIND_DEVICE,
+            "Look up discovered Bluetooth devices whose name contains the given text, ignoring case.",
+            json!({
+                "type": "object",
+                "properties": { "name": { "type": "string" } },
+                "required": ["name"]
+            }),
+        ),
+        tool(START_SCAN, "Start scanning for nearby Bluetooth devices.", no_parameters.clone()),
+        tool(STOP_SCAN, "Stop scanning for Bluetooth devices.", no_parameters),
+        tool(
+            LIST_CHARACTERISTICS,
+            "Connect to a discovered device and list its GATT characteristics.",
+            json!({
+                "type": "object",
+                "properties": { "device_id": { "type": "string", "description": "The id from list_bluetooth_devices." } },
+                "required": ["device_id"]
+            }),
+        ),
+        tool(
+            READ_CHARACTERISTIC,
+            "Read the value of a device's GATT characteristic, such as 2a19 for the battery level.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "device_id": { "type": "string", "description": "The id from list_bluetooth_devices." },
+                    "uuid": { "type": "string", "description": "Full UUID or 16-bit short form." }
+                },
+                "required": ["device_id", "uuid"]
+            }),
+        ),
+    ]
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ListArguments {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FindArguments {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CharacteristicArguments {
+    device_id: String,
+    #[serde(default)]
+    uuid: String,
+}
+
+/// `devices` sorted by signal strength, strongest first, with unknown RSSI last.
+pub fn by_signal(mut devices: Vec<BluetoothDevice>, limit: Option<usize>) -> Vec<BluetoothDevice> {
+    devices.sort_by_key(|device| std::cmp::Reverse(device.rssi.unwrap_or(i16::MIN)));
+    devices.truncate(limit.unwrap_or(usize::MAX));
+    devices
+}
+
+/// Devices whose name contains `name`, ignoring case.
+pub fn named(devices: Vec<BluetoothDevice>, name: &str) -> Vec<BluetoothDevice> {
+    let name = name.to_lowercase();
+    let matching = devices
+        .into_iter()
+        .filter(|device| device.name.as_ref().is_some_and(|n| n.to_lowercase().contains(&name)))
+        .collect();
+    by_signal(matching, None)
+}
+
+/// The built-in Bluetooth tools, for `tools::chat`.
+pub struct BluetoothTools<'a>(pub &'a Mutex<BluetoothScanner>);
+
+#[async_trait]
+impl ToolExecutor for BluetoothTools<'_> {
+    fn tools(&self) -> Vec<Tool> {
+        definitions()
+    }
+
+    async fn call(&self, function: &FunctionCall) -> String {
+        info!("🔧 Calling tool {}({})", function.name, function.arguments);
+        match run(self.0, function).await {
+            Ok(result) => result.to_string(),
+            Err(message) => {
+                warn!("⚠️ Tool {} failed: {}", function.name, message);
+                json!({ "error": message }).to_string()
+            }
+        }
+    }
+}
+
+/// Run one of `definitions()`, describing any failure.
+pub async fn run(scanner: &Mutex<BluetoothScanner>, function: &FunctionCall) -> Result<Value, String> {
+    let arguments = if function.arguments.trim().is_empty() { "{}" } else { &function.arguments };
+    let bluetooth_error = |e: BluetoothError| e.message;
+    match function.name.as_str() {
+        LIST_DEVICES => {
+            let arguments: ListArguments = serde_json::from_str(arguments).map_err(|e| e.to_string())?;
+            let scanner = scanner.lock().await;
+            let devices = by_signal(scanner.get_discovered_devices().await, arguments.limit);
+            Ok(json!({ "scanning": scanner.is_scanning().await, "devices": devices }))
+        }
+        FIND_DEVICE => {
+            let arguments: FindArguments = serde_json::from_str(arguments).map_err(|e| e.to_string())?;
+            let devices = scanner.lock().await.get_discovered_devices().await;
+            Ok(json!({ "devices": named(devices, &arguments.name) }))
+        }
+        START_SCAN => scanner.lock().await.start_scan().await.map(|message| json!({ "message": message })).map_err(bluetooth_error),
+        STOP_SCAN => scanner.lock().await.stop_scan().await.map(|message| json!({ "message": message })).map_err(bluetooth_error),
+        LIST_CHARACTERISTICS => {
+            let arguments: CharacteristicArguments = serde_json::from_str(arguments).map_err(|e| e.to_string())?;
+            // Only the lookup needs the scanner; the device is talked to without it locked.
+            let peripheral = scanner.lock().await.peripheral(&arguments.device_id).await.map_err(bluetooth_error)?;
+            let characteristics = bluetooth::list_characteristics(&peripheral).await.map_err(bluetooth_error)?;
+            Ok(json!({ "characteristics": characteristics }))
+        }
+        READ_CHARACTERISTIC => {
+            let arguments: CharacteristicArguments = serde_json::from_str(arguments).map_err(|e| e.to_string())?;
+            let peripheral = scanner.lock().await.peripheral(&arguments.device_id).await.map_err(bluetooth_error)?;
+            let value = bluetooth::read_characteristic(&peripheral, &arguments.uuid).await.map_err(bluetooth_error)?;
+            Ok(json!(value))
+        }
+        name => Err(format!("Unknown tool '{}'", name)),
+    }
+}
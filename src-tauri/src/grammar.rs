@@ -0,0 +1,33 @@
+use std::ffi::CString;
+
+use crate::llm::LlmError;
+
+/// Rule that generation is constrained to.
+pub const GRAMMAR_ROOT: &str = "root";
+
+/// Check that `grammar` is GBNF llama.cpp will accept, by building a grammar
+/// sampler from it. No vocabulary is needed to parse the rules.
+///
+/// llama.cpp only logs why a grammar was rejected, to stderr.
+pub fn validate(grammar: &str) -> Result<(), LlmError> {
+    if grammar.trim().is_empty() {
+        return Err(invalid("grammar is empty"));
+    }
+    let grammar = CString::new(grammar).map_err(|_| invalid("grammar contains a NUL character"))?;
+    let root = CString::new(GRAMMAR_ROOT).expect("GRAMMAR_ROOT has no NUL");
+
+    // SAFETY: both strings are valid C strings for the duration of the call,
+    // and llama.cpp only keeps the vocabulary pointer for sampling, which a
+    // sampler freed right away never does.
+    let sampler = unsafe { llama_cpp_sys_2::llama_sampler_init_grammar(std::ptr::null(), grammar.as_ptr(), root.as_ptr()) };
+    if sampler.is_null() {
+        return Err(invalid("llama.cpp rejected it, see the log for why"));
+    }
+    // SAFETY: the sampler was just created and is not used anywhere else.
+    unsafe { llama_cpp_sys_2::llama_sampler_free(sampler) };
+    Ok(())
+}
+
+fn invalid(message: &str) -> LlmError {
+    LlmError::ConfigError(format!("Invalid grammar: {}", message))
+}
@@ -0,0 +1,445 @@
+use std::collections::{HashMap, HashSet};
+use serde_json::{Map, Value};
+
+use crate::grammar::GRAMMAR_ROOT;
+use crate::llm::LlmError;
+
+/// Keywords that describe a schema without constraining values.
+const ANNOTATIONS: &[&str] = &[
+    "$schema", "$id", "$comment", "title", "description", "default", "examples", "format", "deprecated", "readOnly",
+    "writeOnly",
+];
+
+/// Keywords understood by both `to_grammar` and `validate`. Numeric bounds are
+/// only checked by `validate`.
+const KEYWORDS: &[&str] = &[
+    "type", "enum", "const", "properties", "required", "additionalProperties", "items", "minItems", "maxItems",
+    "minLength", "maxLength", "minimum", "maximum", "exclusiveMinimum", "exclusiveMaximum", "anyOf", "oneOf", "$ref",
+    "$defs", "definitions",
+];
+
+/// Build a GBNF grammar whose `root` rule matches JSON that `schema` accepts.
+///
+/// Objects are generated with their declared properties only, in key order.
+pub fn to_grammar(schema: &Value) -> Result<String, LlmError> {
+    to_grammar_rules(schema, GRAMMAR_ROOT)
+}
+
+/// Like `to_grammar`, but the JSON is matched by the rule `name`, for use
+/// inside a larger grammar.
+pub fn to_grammar_rules(schema: &Value, name: &str) -> Result<String, LlmError> {
+    let mut converter = Converter {
+        root: schema,
+        rules: Vec::new(),
+        names: HashSet::new(),
+        refs: HashMap::new(),
+    };
+    converter.rule(schema, name)?;
+    Ok(converter
+        .rules
+        .iter()
+        .map(|(name, body)| format!("{} ::= {}\n", name, body))
+        .collect())
+}
+
+/// Check `value` against `schema`, describing the first violation found.
+pub fn validate(value: &Value, schema: &Value) -> Result<(), String> {
+    check(value, schema, schema, "$")
+}
+
+fn unsupported(message: String) -> LlmError {
+    LlmError::ConfigError(format!("Unsupported JSON schema: {}", message))
+}
+
+/// Follow a local `$ref` such as `#/$defs/Device`.
+fn resolve<'a>(root: &'a Value, reference: &str) -> Result<&'a Value, LlmError> {
+    let pointer = reference
+        .strip_prefix('#')
+        .ok_or_else(|| unsupported(format!("only local $ref is supported, got '{}'", reference)))?;
+    root.pointer(pointer)
+        .ok_or_else(|| unsupported(format!("$ref '{}' does not resolve", reference)))
+}
+
+/// GBNF literal matching `text` exactly.
+fn literal(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn bound(object: &Map<String, Value>, key: &str) -> Option<u64> {
+    object.get(key).and_then(Value::as_u64)
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+    /// Rules in definition order.
+    rules: Vec<(String, String)>,
+    names: HashSet<String>,
+    /// Rule names of the `$ref`s visited so far, so recursive schemas terminate.
+    refs: HashMap<String, String>,
+}
+
+impl<'a> Converter<'a> {
+    /// Reserve a rule name based on `name`.
+    fn unique(&mut self, name: &str) -> String {
+        let base: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.names.contains(&name) {
+            name = format!("{}{}", base, n);
+            n += 1;
+        }
+        self.names.insert(name.clone());
+        name
+    }
+
+    fn rule(&mut self, schema: &'a Value, name: &str) -> Result<String, LlmError> {
+        let name = self.unique(name);
+        let body = self.body(schema, &name)?;
+        self.rules.push((name.clone(), body));
+        Ok(name)
+    }
+
+    /// Shared rules for JSON building blocks, added on first use.
+    fn primitive(&mut self, name: &'static str) -> String {
+        if self.names.contains(name) {
+            return name.to_string();
+        }
+        let (body, deps): (&str, &[&'static str]) = match name {
+            "space" => (r#"| " " | "\n" [ \t]{0,20}"#, &[]),
+            "boolean" => (r#"("true" | "false") space"#, &["space"]),
+            "null" => (r#""null" space"#, &["space"]),
+            "char" => (r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\/bfnrt] | "u" [0-9a-fA-F]{4})"#, &[]),
+            "string" => (r#""\"" char* "\"" space"#, &["char", "space"]),
+            "integral-part" => (r#"[0] | [1-9] [0-9]{0,15}"#, &[]),
+            "decimal-part" => (r#"[0-9]{1,16}"#, &[]),
+            "integer" => (r#"("-"? integral-part) space"#, &["integral-part", "space"]),
+            "number" => (
+                r#"("-"? integral-part) ("." decimal-part)? ([eE] [-+]? integral-part)? space"#,
+                &["integral-part", "decimal-part", "space"],
+            ),
+            "value" => (
+                "object | array | string | number | boolean | null",
+                &["object", "array", "string", "number", "boolean", "null"],
+            ),
+            "object" => (
+                r#""{" space (string ":" space value ("," space string ":" space value)*)? "}" space"#,
+                &["string", "value", "space"],
+            ),
+            "array" => (r#""[" space (value ("," space value)*)? "]" space"#, &["value", "space"]),
+            _ => unreachable!("unknown primitive {}", name),
+        };
+        self.names.insert(name.to_string());
+        for dep in deps {
+            self.primitive(dep);
+        }
+        self.rules.push((name.to_string(), body.to_string()));
+        name.to_string()
+    }
+
+    fn body(&mut self, schema: &'a Value, name: &str) -> Result<String, LlmError> {
+        let object = match schema {
+            Value::Bool(true) => return Ok(self.primitive("value")),
+            Value::Object(object) => object,
+            _ => return Err(unsupported(format!("{} is not a usable schema", schema))),
+        };
+        if let Some(key) = object.keys().find(|k| !KEYWORDS.contains(&k.as_str()) && !ANNOTATIONS.contains(&k.as_str())) {
+            return Err(unsupported(format!("keyword '{}' is not supported", key)));
+        }
+
+        if let Some(reference) = object.get("$ref") {
+            let reference = reference
+                .as_str()
+                .ok_or_else(|| unsupported("$ref must be a string".to_string()))?;
+            if let Some(name) = self.refs.get(reference) {
+                return Ok(name.clone());
+            }
+            let target = resolve(self.root, reference)?;
+            let name = self.unique(&format!("ref-{}", reference.rsplit('/').next().unwrap_or_default()));
+            self.refs.insert(reference.to_string(), name.clone());
+            let body = self.body(target, &name)?;
+            self.rules.push((name.clone(), body));
+            return Ok(name);
+        }
+
+        if let Some(value) = object.get("const") {
+            let space = self.primitive("space");
+            return Ok(format!("{} {}", literal(&value.to_string()), space));
+        }
+        if let Some(values) = object.get("enum") {
+            let values = values
+                .as_array()
+                .filter(|values| !values.is_empty())
+                .ok_or_else(|| unsupported("enum must be a non-empty array".to_string()))?;
+            let space = self.primitive("space");
+            let alternatives: Vec<String> = values.iter().map(|v| literal(&v.to_string())).collect();
+            return Ok(format!("({}) {}", alternatives.join(" | "), space));
+        }
+        if let Some(schemas) = object.get("anyOf").or_else(|| object.get("oneOf")) {
+            let schemas = schemas
+                .as_array()
+                .filter(|schemas| !schemas.is_empty())
+                .ok_or_else(|| unsupported("anyOf/oneOf must be a non-empty array".to_string()))?;
+            let alternatives = schemas
+                .iter()
+                .enumerate()
+                .map(|(i, schema)| self.rule(schema, &format!("{}-{}", name, i)))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(alternatives.join(" | "));
+        }
+
+        let types: Vec<&str> = match object.get("type") {
+            Some(Value::String(kind)) => vec![kind.as_str()],
+            Some(Value::Array(kinds)) => kinds.iter().filter_map(Value::as_str).collect(),
+            Some(_) => return Err(unsupported("type must be a string or an array of strings".to_string())),
+            None if object.contains_key("properties") || object.contains_key("additionalProperties") => vec!["object"],
+            None if object.contains_key("items") => vec!["array"],
+            None => return Ok(self.primitive("value")),
+        };
+        match types.as_slice() {
+            [] => Err(unsupported("type must name at least one type".to_string())),
+            [kind] => self.typed_body(object, kind, name),
+            kinds => {
+                let alternatives = kinds
+                    .iter()
+                    .map(|kind| self.typed_body(object, kind, &format!("{}-{}", name, kind)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!("({})", alternatives.join(" | ")))
+            }
+        }
+    }
+
+    fn typed_body(&mut self, object: &'a Map<String, Value>, kind: &str, name: &str) -> Result<String, LlmError> {
+        match kind {
+            "string" => {
+                let (min, max) = (bound(object, "minLength"), bound(object, "maxLength"));
+                if min.is_none() && max.is_none() {
+                    return Ok(self.primitive("string"));
+                }
+                let char_rule = self.primitive("char");
+                let space = self.primitive("space");
+                let max = max.map(|max| max.to_string()).unwrap_or_default();
+                Ok(format!(r#""\"" {}{{{},{}}} "\"" {}"#, char_rule, min.unwrap_or(0), max, space))
+            }
+            "number" | "integer" | "boolean" | "null" => Ok(self.primitive(match kind {
+                "number" => "number",
+                "integer" => "integer",
+                "boolean" => "boolean",
+                _ => "null",
+            })),
+            "object" => self.object_body(object, name),
+            "array" => self.array_body(object, name),
+            other => Err(unsupported(format!("unknown type '{}'", other))),
+        }
+    }
+
+    fn object_body(&mut self, object: &'a Map<String, Value>, name: &str) -> Result<String, LlmError> {
+        let space = self.primitive("space");
+        let properties = object.get("properties").and_then(Value::as_object).filter(|p| !p.is_empty());
+        let Some(properties) = properties else {
+            // A map with arbitrary keys.
+            let value = match object.get("additionalProperties") {
+                Some(Value::Bool(false)) => return Ok(format!(r#""{{" {space} "}}" {space}"#)),
+                Some(schema @ Value::Object(_)) => self.rule(schema, &format!("{}-value", name))?,
+                _ => self.primitive("value"),
+            };
+            let string = self.primitive("string");
+            let pair = format!(r#"{string} ":" {space} {value}"#);
+            return Ok(format!(r#""{{" {space} ({pair} ("," {space} {pair})*)? "}}" {space}"#));
+        };
+
+        let required: Vec<&str> = object
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|keys| keys.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let mut required_pairs = Vec::new();
+        let mut optional_pairs = Vec::new();
+        for key in required.iter().filter(|key| !properties.contains_key(**key)) {
+            let value = self.primitive("value");
+            required_pairs.push(format!(r#"{} {space} ":" {space} {value}"#, literal(&Value::from(*key).to_string())));
+        }
+        for (key, schema) in properties {
+            let value = self.rule(schema, &format!("{}-{}", name, key))?;
+            let pair = format!(r#"{} {space} ":" {space} {value}"#, literal(&Value::from(key.as_str()).to_string()));
+            if required.contains(&key.as_str()) {
+                required_pairs.push(pair);
+            } else {
+                optional_pairs.push(pair);
+            }
+        }
+
+        let separator = format!(r#" "," {space} "#);
+        let optional_after = |pairs: &[String]| -> String {
+            pairs.iter().map(|pair| format!(r#" ("," {space} {pair})?"#)).collect()
+        };
+        let members = if !required_pairs.is_empty() {
+            format!(" {}{}", required_pairs.join(&separator), optional_after(&optional_pairs))
+        } else if !optional_pairs.is_empty() {
+            // Whichever optional property comes first has no leading comma.
+            let alternatives: Vec<String> = (0..optional_pairs.len())
+                .map(|i| format!("{}{}", optional_pairs[i], optional_after(&optional_pairs[i + 1..])))
+                .collect();
+            format!(" ({})?", alternatives.join(" | "))
+        } else {
+            String::new()
+        };
+        Ok(format!(r#""{{" {space}{members} "}}" {space}"#))
+    }
+
+    fn array_body(&mut self, object: &'a Map<String, Value>, name: &str) -> Result<String, LlmError> {
+        let space = self.primitive("space");
+        let item = match object.get("items") {
+            Some(schema) => self.rule(schema, &format!("{}-item", name))?,
+            None => self.primitive("value"),
+        };
+        let min = bound(object, "minItems").unwrap_or(0);
+        let max = bound(object, "maxItems");
+        if max == Some(0) {
+            return Ok(format!(r#""[" {space} "]" {space}"#));
+        }
+
+        let rest_min = min.saturating_sub(1);
+        let repeat = match max {
+            Some(max) => format!("{{{},{}}}", rest_min, max - 1),
+            None if rest_min == 0 => "*".to_string(),
+            None => format!("{{{},}}", rest_min),
+        };
+        let items = format!(r#"{item} ("," {space} {item}){repeat}"#);
+        if min == 0 {
+            Ok(format!(r#""[" {space} ({items})? "]" {space}"#))
+        } else {
+            Ok(format!(r#""[" {space} {items} "]" {space}"#))
+        }
+    }
+}
+
+fn type_matches(value: &Value, kind: &str) -> bool {
+    match kind {
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        _ => false,
+    }
+}
+
+fn check(value: &Value, schema: &Value, root: &Value, path: &str) -> Result<(), String> {
+    let object = match schema {
+        Value::Bool(false) => return Err(format!("{}: no value is allowed", path)),
+        Value::Object(object) => object,
+        _ => return Ok(()),
+    };
+
+    if let Some(reference) = object.get("$ref").and_then(Value::as_str) {
+        let target = resolve(root, reference).map_err(|e| e.to_string())?;
+        check(value, target, root, path)?;
+    }
+    if let Some(expected) = object.get("const") {
+        if value != expected {
+            return Err(format!("{}: expected {}", path, expected));
+        }
+    }
+    if let Some(Value::Array(values)) = object.get("enum") {
+        if !values.contains(value) {
+            return Err(format!("{}: {} is not one of {}", path, value, Value::Array(values.clone())));
+        }
+    }
+    if let Some(kinds) = object.get("type") {
+        let kinds: Vec<&str> = match kinds {
+            Value::String(kind) => vec![kind.as_str()],
+            Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !kinds.iter().any(|kind| type_matches(value, kind)) {
+            return Err(format!("{}: expected {}, got {}", path, kinds.join(" or "), value));
+        }
+    }
+    if let Some(Value::Array(schemas)) = object.get("anyOf") {
+        if !schemas.iter().any(|schema| check(value, schema, root, path).is_ok()) {
+            return Err(format!("{}: does not match any of the anyOf schemas", path));
+        }
+    }
+    if let Some(Value::Array(schemas)) = object.get("oneOf") {
+        let matches = schemas.iter().filter(|schema| check(value, schema, root, path).is_ok()).count();
+        if matches != 1 {
+            return Err(format!("{}: matches {} of the oneOf schemas instead of one", path, matches));
+        }
+    }
+
+    match value {
+        Value::String(text) => {
+            let length = text.chars().count() as u64;
+            if bound(object, "minLength").is_some_and(|min| length < min) {
+                return Err(format!("{}: shorter than minLength", path));
+            }
+            if bound(object, "maxLength").is_some_and(|max| length > max) {
+                return Err(format!("{}: longer than maxLength", path));
+            }
+        }
+        Value::Number(number) => {
+            let n = number.as_f64().unwrap_or_default();
+            let limit = |key: &str| object.get(key).and_then(Value::as_f64);
+            if limit("minimum").is_some_and(|min| n < min)
+                || limit("maximum").is_some_and(|max| n > max)
+                || limit("exclusiveMinimum").is_some_and(|min| n <= min)
+                || limit("exclusiveMaximum").is_some_and(|max| n >= max)
+            {
+                return Err(format!("{}: {} is out of range", path, n));
+            }
+        }
+        Value::Array(items) => {
+            let count = items.len() as u64;
+            if bound(object, "minItems").is_some_and(|min| count < min) {
+                return Err(format!("{}: fewer than minItems items", path));
+            }
+            if bound(object, "maxItems").is_some_and(|max| count > max) {
+                return Err(format!("{}: more than maxItems items", path));
+            }
+            if let Some(schema) = object.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(item, schema, root, &format!("{}[{}]", path, i))?;
+                }
+            }
+        }
+        Value::Object(members) => {
+            if let Some(Value::Array(required)) = object.get("required") {
+                if let Some(missing) = required.iter().filter_map(Value::as_str).find(|key| !members.contains_key(*key)) {
+                    return Err(format!("{}: missing required property '{}'", path, missing));
+                }
+            }
+            let properties = object.get("properties").and_then(Value::as_object);
+            for (key, member) in members {
+                let member_path = format!("{}.{}", path, key);
+                match properties.and_then(|p| p.get(key)) {
+                    Some(schema) => check(member, schema, root, &member_path)?,
+                    None => {
+                        if let Some(schema) = object.get("additionalProperties") {
+                            check(member, schema, root, &member_path)
+                                .map_err(|e| if schema == &Value::Bool(false) { format!("{}: unexpected property", member_path) } else { e })?;
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
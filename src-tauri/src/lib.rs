@@ -1,14 +1,52 @@
+mod auth;
 mod bluetooth;
+mod bluetooth_tools;
+mod grammar;
+mod json_schema;
+mod llama;
 mod llm;
+mod mcp;
+mod mcp_server;
+#[cfg(test)]
+mod mock;
+mod ollama;
+mod openai;
+mod provider;
+mod reasoning;
+mod server;
+mod tools;
+mod worker;
 #[cfg(test)]
 mod tests;
 
-use bluetooth::{BluetoothScanner, BluetoothDevice, BluetoothError};
-use llm::{LlmService, LlmConfig, LlmError, ChatRequest, ChatResponse, ModelsResponse, LlmServiceStatus};
-use tokio::sync::Mutex;
-use tauri::State;
+use auth::{ApiKeyInfo, ApiKeyStore, ApiKeys, NewApiKey};
+use bluetooth::{BluetoothCharacteristic, BluetoothScanner, BluetoothDevice, BluetoothError, CharacteristicValue};
+use bluetooth_tools::BluetoothTools;
+use llm::{
+    LlmService, LlmConfig, LlmError, ChatRequest, ChatResponse, ModelsResponse, LlmServiceStatus, CancelToken,
+    CompletionRequest, CompletionResponse, EmbeddingInput, EmbeddingPooling, EmbeddingsRequest, EmbeddingsResponse,
+    LoraAdapterConfig, TokenCount,
+};
+use mcp::{
+    McpManager, McpServerConfig, McpServerInfo, McpTools, PendingApprovals, ResourceContents, ToolApprovalRequest,
+    ToolApprover,
+};
+use server::{ApiServer, ApiServerConfig};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
+use tauri::{AppHandle, Emitter, Manager, RunEvent, State};
 
 type BluetoothState = std::sync::Arc<tokio::sync::Mutex<BluetoothScanner>>;
+type LlmState = std::sync::Arc<RwLock<LlmService>>;
+type ApiServerState = Mutex<Option<ApiServer>>;
+type McpState = Mutex<McpManager>;
+
+/// Event asking the frontend to approve an MCP tool call; answered with `resolve_tool_approval`.
+const TOOL_APPROVAL_EVENT: &str = "tool-approval-request";
+
+/// Upper bound on how long exit may block while releasing hardware and the model.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tauri::command]
 async fn initialize_bluetooth(scanner: State<'_, BluetoothState>) -> Result<String, BluetoothError> {
@@ -48,50 +86,276 @@ async fn clear_bluetooth_devices(scanner: State<'_, BluetoothState>) -> Result<S
     Ok("Device list cleared".to_string())
 }
 
+#[tauri::command]
+async fn list_bluetooth_characteristics(scanner: State<'_, BluetoothState>, device_id: String) -> Result<Vec<BluetoothCharacteristic>, BluetoothError> {
+    let peripheral = scanner.lock().await.peripheral(&device_id).await?;
+    bluetooth::list_characteristics(&peripheral).await
+}
+
+#[tauri::command]
+async fn read_bluetooth_characteristic(scanner: State<'_, BluetoothState>, device_id: String, uuid: String) -> Result<CharacteristicValue, BluetoothError> {
+    let peripheral = scanner.lock().await.peripheral(&device_id).await?;
+    bluetooth::read_characteristic(&peripheral, &uuid).await
+}
+
 // LLM Commands
 #[tauri::command]
-async fn initialize_llm(llm_service: State<'_, Mutex<LlmService>>, config: LlmConfig) -> Result<String, LlmError> {
-    let mut service = llm_service.lock().await;
-    *service = LlmService::new(config);
+async fn initialize_llm(llm_service: State<'_, LlmState>, cancel_token: State<'_, CancelToken>, config: LlmConfig) -> Result<String, LlmError> {
+    let mut service = llm_service.write().await;
+    *service = LlmService::new(config).with_cancel_token(cancel_token.inner().clone());
     Ok("LLM service initialized successfully".to_string())
 }
 
 #[tauri::command]
-async fn start_llm_service(llm_service: State<'_, Mutex<LlmService>>) -> Result<String, LlmError> {
-    let mut service = llm_service.lock().await;
+async fn start_llm_service(llm_service: State<'_, LlmState>) -> Result<String, LlmError> {
+    let mut service = llm_service.write().await;
     service.start().await
 }
 
 #[tauri::command]
-async fn stop_llm_service(llm_service: State<'_, Mutex<LlmService>>) -> Result<String, LlmError> {
-    let mut service = llm_service.lock().await;
+async fn stop_llm_service(llm_service: State<'_, LlmState>) -> Result<String, LlmError> {
+    let mut service = llm_service.write().await;
     service.stop().await
 }
 
 #[tauri::command]
-async fn get_llm_status(llm_service: State<'_, Mutex<LlmService>>) -> Result<LlmServiceStatus, LlmError> {
-    let service = llm_service.lock().await;
-    Ok(service.get_status())
+async fn get_llm_status(llm_service: State<'_, LlmState>, api_server: State<'_, ApiServerState>) -> Result<LlmServiceStatus, LlmError> {
+    let mut status = llm_service.read().await.get_status();
+    if let Some(server) = api_server.lock().await.as_ref() {
+        status.port = server.addr().port();
+        status.base_url = server.base_url();
+    }
+    Ok(status)
 }
 
 #[tauri::command]
-async fn chat_with_llm(llm_service: State<'_, Mutex<LlmService>>, request: ChatRequest) -> Result<ChatResponse, LlmError> {
-    let service = llm_service.lock().await;
+async fn chat_with_llm(llm_service: State<'_, LlmState>, request: ChatRequest) -> Result<ChatResponse, LlmError> {
+    let service = llm_service.read().await;
     service.chat_completion(request).await
 }
 
 #[tauri::command]
-async fn list_llm_models(llm_service: State<'_, Mutex<LlmService>>) -> Result<ModelsResponse, LlmError> {
-    let service = llm_service.lock().await;
+async fn chat_with_tools(
+    app: AppHandle,
+    llm_service: State<'_, LlmState>,
+    scanner: State<'_, BluetoothState>,
+    mcp: State<'_, McpState>,
+    approvals: State<'_, PendingApprovals>,
+    request: ChatRequest,
+) -> Result<ChatResponse, LlmError> {
+    let approver = EventApprover { app: &app, pending: &approvals };
+    let mcp_tools = McpTools::new(&mcp, &approver).await;
+    tools::chat(&llm_service, request, &[&BluetoothTools(&scanner), &mcp_tools]).await
+}
+
+/// Puts each tool call to the user through the frontend.
+struct EventApprover<'a> {
+    app: &'a AppHandle,
+    pending: &'a PendingApprovals,
+}
+
+#[async_trait::async_trait]
+impl ToolApprover for EventApprover<'_> {
+    async fn approve(&self, request: &ToolApprovalRequest) -> bool {
+        let answer = self.pending.register(&request.id);
+        if let Err(e) = self.app.emit(TOOL_APPROVAL_EVENT, request) {
+            log::error!("❌ Failed to ask for tool approval: {}", e);
+            return false;
+        }
+        self.pending.wait(&request.id, answer).await
+    }
+}
+
+#[tauri::command]
+async fn resolve_tool_approval(approvals: State<'_, PendingApprovals>, id: String, approved: bool) -> Result<(), LlmError> {
+    approvals.resolve(&id, approved)
+}
+
+#[tauri::command]
+async fn connect_mcp_server(mcp: State<'_, McpState>, config: McpServerConfig) -> Result<McpServerInfo, LlmError> {
+    mcp.lock().await.connect(config).await
+}
+
+#[tauri::command]
+async fn disconnect_mcp_server(mcp: State<'_, McpState>, name: String) -> Result<String, LlmError> {
+    if mcp.lock().await.disconnect(&name).await {
+        Ok(format!("MCP server '{}' disconnected", name))
+    } else {
+        Err(LlmError::ConfigError(format!("No MCP server named '{}' is connected", name)))
+    }
+}
+
+#[tauri::command]
+async fn list_mcp_servers(mcp: State<'_, McpState>) -> Result<Vec<McpServerInfo>, LlmError> {
+    Ok(mcp.lock().await.servers())
+}
+
+#[tauri::command]
+async fn read_mcp_resource(mcp: State<'_, McpState>, server: String, uri: String) -> Result<Vec<ResourceContents>, LlmError> {
+    let client = mcp.lock().await.client(&server)?;
+    client.read_resource(&uri).await
+}
+
+#[tauri::command]
+async fn complete_text(llm_service: State<'_, LlmState>, request: CompletionRequest) -> Result<CompletionResponse, LlmError> {
+    let service = llm_service.read().await;
+    service.text_completion(request).await
+}
+
+#[tauri::command]
+async fn infill_text(llm_service: State<'_, LlmState>, request: CompletionRequest) -> Result<CompletionResponse, LlmError> {
+    if request.suffix.is_none() {
+        return Err(LlmError::ConfigError("Infill needs a suffix: the text after the gap".to_string()));
+    }
+    let service = llm_service.read().await;
+    service.text_completion(request).await
+}
+
+#[tauri::command]
+async fn embed_texts(llm_service: State<'_, LlmState>, texts: Vec<String>, pooling: Option<EmbeddingPooling>, normalize: Option<bool>) -> Result<EmbeddingsResponse, LlmError> {
+    let service = llm_service.read().await;
+    let request = EmbeddingsRequest {
+        model: service.embedding_model_name().to_string(),
+        input: EmbeddingInput::Batch(texts),
+        pooling,
+        normalize,
+    };
+    service.embeddings(request).await
+}
+
+#[tauri::command]
+async fn tokenize_text(llm_service: State<'_, LlmState>, text: String, add_bos: Option<bool>, parse_special: Option<bool>) -> Result<Vec<i32>, LlmError> {
+    let service = llm_service.read().await;
+    service.tokenize(&text, add_bos.unwrap_or(true), parse_special.unwrap_or(true)).await
+}
+
+#[tauri::command]
+async fn detokenize_tokens(llm_service: State<'_, LlmState>, tokens: Vec<i32>, special: Option<bool>) -> Result<String, LlmError> {
+    let service = llm_service.read().await;
+    service.detokenize(&tokens, special.unwrap_or(false)).await
+}
+
+#[tauri::command]
+async fn count_chat_tokens(llm_service: State<'_, LlmState>, request: ChatRequest) -> Result<TokenCount, LlmError> {
+    let service = llm_service.read().await;
+    service.count_tokens(&request).await
+}
+
+#[tauri::command]
+async fn list_lora_adapters(llm_service: State<'_, LlmState>) -> Result<Vec<LoraAdapterConfig>, LlmError> {
+    let service = llm_service.read().await;
+    service.list_lora_adapters().await
+}
+
+#[tauri::command]
+async fn attach_lora_adapter(llm_service: State<'_, LlmState>, path: PathBuf, scale: Option<f32>) -> Result<(), LlmError> {
+    let service = llm_service.read().await;
+    service.attach_lora_adapter(LoraAdapterConfig { path, scale: scale.unwrap_or(1.0) }).await
+}
+
+#[tauri::command]
+async fn detach_lora_adapter(llm_service: State<'_, LlmState>, path: PathBuf) -> Result<(), LlmError> {
+    let service = llm_service.read().await;
+    service.detach_lora_adapter(&path).await
+}
+
+#[tauri::command]
+async fn list_llm_models(llm_service: State<'_, LlmState>) -> Result<ModelsResponse, LlmError> {
+    let service = llm_service.read().await;
     service.list_models().await
 }
 
 #[tauri::command]
-async fn check_llm_health(llm_service: State<'_, Mutex<LlmService>>) -> Result<String, LlmError> {
-    let service = llm_service.lock().await;
+async fn check_llm_health(llm_service: State<'_, LlmState>) -> Result<String, LlmError> {
+    let service = llm_service.read().await;
     service.check_llm_health().await
 }
 
+#[tauri::command]
+async fn start_api_server(llm_service: State<'_, LlmState>, bluetooth_scanner: State<'_, BluetoothState>, api_server: State<'_, ApiServerState>, api_keys: State<'_, ApiKeys>, config: Option<ApiServerConfig>) -> Result<String, LlmError> {
+    let mut api_server = api_server.lock().await;
+    if let Some(server) = api_server.as_ref() {
+        return Ok(format!("API server already running at {}", server.base_url()));
+    }
+
+    let server = ApiServer::start(llm_service.inner().clone(), bluetooth_scanner.inner().clone(), api_keys.inner().clone(), &config.unwrap_or_default()).await?;
+    let msg = format!("API server listening at {}", server.base_url());
+    *api_server = Some(server);
+    Ok(msg)
+}
+
+#[tauri::command]
+async fn stop_api_server(api_server: State<'_, ApiServerState>) -> Result<String, LlmError> {
+    let server = api_server.lock().await.take().ok_or(LlmError::NotRunning)?;
+    server.stop().await;
+    Ok("API server stopped".to_string())
+}
+
+#[tauri::command]
+async fn create_api_key(api_keys: State<'_, ApiKeys>, name: String) -> Result<NewApiKey, LlmError> {
+    api_keys.lock().unwrap().create(&name)
+}
+
+#[tauri::command]
+async fn revoke_api_key(api_keys: State<'_, ApiKeys>, id: String) -> Result<String, LlmError> {
+    let key = api_keys.lock().unwrap().revoke(&id)?;
+    Ok(format!("API key '{}' revoked", key.name))
+}
+
+#[tauri::command]
+async fn list_api_keys(api_keys: State<'_, ApiKeys>) -> Result<Vec<ApiKeyInfo>, LlmError> {
+    Ok(api_keys.lock().unwrap().list())
+}
+
+/// Stop background work and release the model before the process exits.
+async fn shutdown(app_handle: &tauri::AppHandle) {
+    shutdown_services(
+        &app_handle.state::<CancelToken>(),
+        &app_handle.state::<BluetoothState>(),
+        &app_handle.state::<ApiServerState>(),
+        &app_handle.state::<LlmState>(),
+        &app_handle.state::<McpState>(),
+    )
+    .await;
+}
+
+async fn shutdown_services(
+    cancel_token: &CancelToken,
+    scanner: &BluetoothState,
+    api_server: &ApiServerState,
+    llm_service: &LlmState,
+    mcp: &McpState,
+) {
+    log::info!("🛑 Shutting down: cancelling generations, stopping Bluetooth scan and API server, unloading model, disconnecting MCP servers");
+
+    // Cancel first so a running chat_completion releases the service lock.
+    cancel_token.cancel();
+
+    scanner.lock().await.shutdown().await;
+
+    if let Some(server) = api_server.lock().await.take() {
+        server.stop().await;
+    }
+
+    llm_service.write().await.shutdown().await;
+
+    mcp.lock().await.shutdown().await;
+
+    log::info!("✅ Shutdown complete");
+}
+
+pub use worker::WORKER_SUBCOMMAND;
+
+/// Entry point for the `inference-worker` subcommand. Logs go to stderr, which
+/// the parent inherits; stdout is reserved for the request/reply pipe.
+pub fn run_inference_worker() {
+    env_logger::Builder::from_default_env()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    worker::run_worker();
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     // Initialize logging
@@ -103,12 +367,23 @@ pub fn run() {
     log::info!("🚀 Starting Tauri application with LLM and Bluetooth support");
 
     let bluetooth_scanner = std::sync::Arc::new(tokio::sync::Mutex::new(BluetoothScanner::new()));
-    let llm_service = Mutex::new(LlmService::new(LlmConfig::default()));
+    let cancel_token = CancelToken::default();
+    let llm_service: LlmState = std::sync::Arc::new(RwLock::new(LlmService::new(LlmConfig::default()).with_cancel_token(cancel_token.clone())));
+    let api_server: ApiServerState = Mutex::new(None);
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .manage(bluetooth_scanner)
         .manage(llm_service)
+        .manage(cancel_token)
+        .manage(api_server)
+        .manage(McpState::default())
+        .manage(PendingApprovals::default())
+        .setup(|app| {
+            let path = app.path().app_config_dir()?.join(auth::API_KEYS_FILE);
+            app.manage(ApiKeyStore::load(path).shared());
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             initialize_bluetooth,
             start_bluetooth_scan,
@@ -116,14 +391,45 @@ pub fn run() {
             get_bluetooth_devices,
             is_bluetooth_scanning,
             clear_bluetooth_devices,
+            list_bluetooth_characteristics,
+            read_bluetooth_characteristic,
             initialize_llm,
             start_llm_service,
             stop_llm_service,
             get_llm_status,
             chat_with_llm,
+            chat_with_tools,
+            resolve_tool_approval,
+            connect_mcp_server,
+            disconnect_mcp_server,
+            list_mcp_servers,
+            read_mcp_resource,
+            complete_text,
+            infill_text,
+            embed_texts,
+            tokenize_text,
+            detokenize_tokens,
+            count_chat_tokens,
+            list_lora_adapters,
+            attach_lora_adapter,
+            detach_lora_adapter,
             list_llm_models,
-            check_llm_health
+            check_llm_health,
+            start_api_server,
+            stop_api_server,
+            create_api_key,
+            revoke_api_key,
+            list_api_keys
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            if let RunEvent::Exit = event {
+                tauri::async_runtime::block_on(async {
+                    if tokio::time::timeout(SHUTDOWN_TIMEOUT, shutdown(app_handle)).await.is_err() {
+                        log::warn!("⚠️ Shutdown did not finish within {:?}, exiting anyway", SHUTDOWN_TIMEOUT);
+                    }
+                });
+            }
+        });
 }
@@ -0,0 +1,1284 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::sync::Mutex;
+use std::time::Instant;
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use llama_cpp_2::{
+    context::params::{LlamaContextParams, LlamaPoolingType},
+    context::LlamaContext,
+    llama_backend::LlamaBackend,
+    llama_batch::LlamaBatch,
+    model::params::LlamaModelParams,
+    model::{LlamaLoraAdapter, LlamaModel},
+    model::{AddBos, Special},
+    sampling::LlamaSampler,
+    token::{logit_bias::LlamaLogitBias, LlamaToken},
+};
+use encoding_rs::UTF_8;
+
+use crate::llm::{
+    CancelToken, ChatChoice, ChatMessage, ChatRequest, ChatResponse, ChatUsage, ChoiceLogprobs, CompletionChoice,
+    CompletionRequest, CompletionResponse, CompletionTokensDetails, Embedding, EmbeddingPooling, EmbeddingsRequest, EmbeddingsResponse,
+    EmbeddingsUsage, LlmConfig, LlmError, LoraAdapterConfig, SamplingConfig, TokenLogprob, Tool, TopLogprob,
+};
+use crate::grammar::GRAMMAR_ROOT;
+use crate::provider::{ChatProvider, LoraAdapters, TokenSink, Tokenizer};
+use crate::reasoning::{THINK_CLOSE, THINK_OPEN};
+use crate::tools::{self, CallFilter};
+
+/// llama.cpp picks a fresh random seed for samplers given this one.
+const RANDOM_SEED: u32 = u32::MAX;
+
+/// Tokens Mirostat 1.0 estimates the distribution from, as in its paper.
+const MIROSTAT_M: i32 = 100;
+
+/// `params` with room for `n_seq_max` sequences in the KV cache. llama-cpp-2
+/// has no setter for it, so it is written into the llama.cpp struct the
+/// wrapper holds.
+fn with_n_seq_max(mut params: LlamaContextParams, n_seq_max: u32) -> LlamaContextParams {
+    // That struct is the wrapper's only field, so with the sizes equal it is
+    // all of the wrapper, from offset 0.
+    const _: () = assert!(
+        std::mem::size_of::<LlamaContextParams>() == std::mem::size_of::<llama_cpp_sys_2::llama_context_params>()
+    );
+    // SAFETY: per the above, the pointer is to a valid `llama_context_params`,
+    // and `n_seq_max` is plain data.
+    unsafe {
+        let raw = (&mut params as *mut LlamaContextParams).cast::<llama_cpp_sys_2::llama_context_params>();
+        (*raw).n_seq_max = n_seq_max;
+    }
+    params
+}
+
+/// Run CPU-bound llama.cpp work without stalling the other tasks of this
+/// runtime thread. `block_in_place` needs the multi-threaded runtime the app
+/// runs on; on any other, such as the worker process's, the work runs as is.
+fn blocking<T>(work: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(tokio::runtime::RuntimeFlavor::MultiThread) => tokio::task::block_in_place(work),
+        _ => work(),
+    }
+}
+
+/// One reply generated by `LlamaProvider::run`.
+struct Generation {
+    text: String,
+    finish_reason: &'static str,
+    /// Log-probability of the reply's tokens, when `RunOptions::score` is set.
+    logprob: f64,
+    /// Each token's log-probability, when `RunOptions::logprobs` is set.
+    logprobs: Vec<TokenLogprob>,
+}
+
+/// How `LlamaProvider::run` generates from a prompt.
+struct RunOptions<'a> {
+    max_tokens: Option<i32>,
+    /// Reasoning in <think> tags is closed after this many tokens.
+    thinking_budget: Option<u32>,
+    /// Must already have passed `grammar::validate`.
+    grammar: Option<&'a str>,
+    /// Replies to generate from the one decoded prompt.
+    sequences: usize,
+    /// Zero picks the most likely token, unless Mirostat is on.
+    temperature: f32,
+    top_p: f32,
+    sampling: SamplingConfig,
+    /// Track each reply's log-probability.
+    score: bool,
+    /// Report each token's log-probability along with this many alternatives.
+    logprobs: Option<usize>,
+    /// The request's own cancel token, checked along with the provider's.
+    cancel: CancelToken,
+}
+
+/// A reply `LlamaProvider::run` is generating, in its own KV cache sequence.
+struct Sequence {
+    id: i32,
+    sampler: LlamaSampler,
+    decoder: encoding_rs::Decoder,
+    text: String,
+    /// Position of the next token.
+    pos: i32,
+    /// Generated tokens in the KV cache.
+    tokens: Vec<LlamaToken>,
+    /// Drafted tokens in the batch, after the last token added for certain.
+    draft: Vec<LlamaToken>,
+    /// Batch indexes of the logits to sample from: after that last token,
+    /// then after each drafted one.
+    logits: Vec<i32>,
+    drafted: u32,
+    accepted: u32,
+    thinking_tokens: u32,
+    /// Whether `text` holds the end of the reasoning, from the model or the budget.
+    thinking_closed: bool,
+    logprob: f64,
+    logprobs: Vec<TokenLogprob>,
+    finish_reason: Option<&'static str>,
+}
+
+/// Log of the softmax denominator of `logits`: a token's log-probability is
+/// its logit minus this.
+fn log_normalizer(logits: &[f32]) -> f64 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f64 = logits.iter().map(|&logit| f64::from(logit - max).exp()).sum();
+    f64::from(max) + sum.ln()
+}
+
+/// The `top` likeliest tokens under `logits`, most likely first.
+fn top_tokens(logits: &[f32], top: usize) -> Vec<LlamaToken> {
+    let mut ids: Vec<usize> = (0..logits.len()).collect();
+    let by_logit = |a: &usize, b: &usize| logits[*b].total_cmp(&logits[*a]);
+    if top < ids.len() {
+        ids.select_nth_unstable_by(top, by_logit);
+        ids.truncate(top);
+    }
+    ids.sort_by(by_logit);
+    ids.into_iter().map(|id| LlamaToken::new(id as i32)).collect()
+}
+
+/// Most tokens decoded at once.
+const BATCH_SIZE: usize = 512;
+
+/// Most tokens each of `replies` may generate after a prompt of
+/// `prompt_tokens`, so that the prompt, decoded once, and every reply fit in
+/// the context. Negative `max_tokens` allows none, as in `MockProvider`.
+pub(crate) fn reply_budget(ctx_size: u32, prompt_tokens: usize, replies: usize, max_tokens: i32) -> i32 {
+    let room = (ctx_size as i32 - prompt_tokens as i32).max(0) / replies.max(1) as i32;
+    max_tokens.clamp(0, room)
+}
+
+/// A small model that proposes how the main model's reply goes on, for
+/// `LlamaProvider::run` to verify in one batch.
+struct Draft<'a> {
+    model: &'a LlamaModel,
+    context: LlamaContext<'a>,
+    sampler: LlamaSampler,
+    prompt: Vec<LlamaToken>,
+    /// Tokens in the draft context's KV cache.
+    cached: Vec<LlamaToken>,
+}
+
+impl Draft<'_> {
+    /// Greedily propose up to `n` tokens to follow the prompt and `generated`.
+    fn propose(&mut self, generated: &[LlamaToken], n: usize) -> Result<Vec<LlamaToken>, LlmError> {
+        let history: Vec<LlamaToken> = self.prompt.iter().chain(generated).copied().collect();
+        // Keep what the cache has right, but always decode the last token to sample after it.
+        let kept = self.cached.iter().zip(&history).take_while(|(a, b)| a == b).count().min(history.len() - 1);
+        self.context.clear_kv_cache_seq(Some(0), Some(kept as u32), None).map_err(|e| {
+            LlmError::LlamaCppError(format!("Failed to clear draft KV cache: {}", e))
+        })?;
+        self.cached.truncate(kept);
+
+        let mut proposed = Vec::with_capacity(n);
+        let mut pending = history[kept..].to_vec();
+        while proposed.len() < n {
+            let mut batch = LlamaBatch::new(BATCH_SIZE, 1);
+            for chunk in pending.chunks(BATCH_SIZE) {
+                batch.clear();
+                for (i, &token) in chunk.iter().enumerate() {
+                    let pos = (self.cached.len() + i) as i32;
+                    batch.add(token, pos, &[0], i == chunk.len() - 1).map_err(|e| {
+                        LlmError::LlamaCppError(format!("Failed to add token to draft batch: {}", e))
+                    })?;
+                }
+                self.context.decode(&mut batch).map_err(|e| {
+                    LlmError::LlamaCppError(format!("Failed to decode draft: {}", e))
+                })?;
+                self.cached.extend_from_slice(chunk);
+            }
+            let token = self.sampler.sample(&self.context, batch.n_tokens() - 1);
+            if self.model.is_eog_token(token) {
+                break;
+            }
+            proposed.push(token);
+            pending = vec![token];
+        }
+        Ok(proposed)
+    }
+}
+
+/// Fill-in-the-middle tokens declared in a GGUF file's tokenizer metadata.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FimTokens {
+    pub prefix: LlamaToken,
+    pub suffix: LlamaToken,
+    pub middle: LlamaToken,
+}
+
+impl FimTokens {
+    /// Metadata keys for each token, current name first, then the older one.
+    const PREFIX_KEYS: [&'static str; 2] = ["tokenizer.ggml.fim_pre_token_id", "tokenizer.ggml.prefix_token_id"];
+    const SUFFIX_KEYS: [&'static str; 2] = ["tokenizer.ggml.fim_suf_token_id", "tokenizer.ggml.suffix_token_id"];
+    const MIDDLE_KEYS: [&'static str; 2] = ["tokenizer.ggml.fim_mid_token_id", "tokenizer.ggml.middle_token_id"];
+
+    fn from_model(model: &LlamaModel) -> Option<Self> {
+        let token = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|key| model.meta_val_str(key).ok())
+                .and_then(|value| value.trim().parse::<i32>().ok())
+                .filter(|&id| id >= 0)
+                .map(LlamaToken)
+        };
+        Some(Self {
+            prefix: token(&Self::PREFIX_KEYS)?,
+            suffix: token(&Self::SUFFIX_KEYS)?,
+            middle: token(&Self::MIDDLE_KEYS)?,
+        })
+    }
+
+    /// Prefix-suffix-middle prompt: the model generates the middle.
+    pub fn prompt(&self, bos: &[LlamaToken], prefix: &[LlamaToken], suffix: &[LlamaToken]) -> Vec<LlamaToken> {
+        let mut tokens = Vec::with_capacity(bos.len() + prefix.len() + suffix.len() + 3);
+        tokens.extend_from_slice(bos);
+        tokens.push(self.prefix);
+        tokens.extend_from_slice(prefix);
+        tokens.push(self.suffix);
+        tokens.extend_from_slice(suffix);
+        tokens.push(self.middle);
+        tokens
+    }
+}
+
+/// A LoRA adapter read for `LlamaProvider::model`. Detached adapters stay
+/// loaded so attaching them again does not read the file.
+struct LoadedAdapter {
+    path: PathBuf,
+    adapter: LlamaLoraAdapter,
+    /// Scale it is applied with, while attached.
+    scale: Option<f32>,
+}
+
+// SAFETY: llama.cpp does not change an adapter once it is loaded, and it lives
+// as long as the model it was loaded for.
+unsafe impl Send for LoadedAdapter {}
+
+/// In-process llama.cpp inference on a GGUF model loaded from disk.
+pub struct LlamaProvider {
+    config: LlmConfig,
+    backend: LlamaBackend,
+    model: LlamaModel,
+    /// Present when the model supports infill.
+    fim: Option<FimTokens>,
+    /// Whether the model's chat template uses the Hermes tool call format.
+    hermes_tools: bool,
+    /// Dedicated model for `embeddings`, when configured.
+    embedding_model: Option<LlamaModel>,
+    /// Small model for speculative decoding, when configured.
+    draft_model: Option<LlamaModel>,
+    /// LoRA adapters loaded for `model`, attached or not.
+    adapters: Mutex<Vec<LoadedAdapter>>,
+    /// Held while the model works on a request; it takes one at a time.
+    slot: tokio::sync::Mutex<()>,
+    cancel_token: CancelToken,
+}
+
+impl LlamaProvider {
+    pub fn load(
+        config: LlmConfig,
+        model_path: PathBuf,
+        embedding_model_path: Option<PathBuf>,
+        draft_model_path: Option<PathBuf>,
+        cancel_token: CancelToken,
+    ) -> Result<Self, LlmError> {
+        // Initialize the backend
+        info!("🔧 Initializing LLaMA backend...");
+        let backend_start = Instant::now();
+        let backend = LlamaBackend::init()
+            .map_err(|e| {
+                error!("❌ Failed to initialize backend: {}", e);
+                LlmError::LlamaCppError(format!("Failed to initialize backend: {}", e))
+            })?;
+        info!("✅ Backend initialized in {:?}", backend_start.elapsed());
+
+
+        // Set up model parameters
+        info!("⚙️ Setting up model parameters...");
+        let mut model_params = LlamaModelParams::default();
+        if config.n_gpu_layers > 0 {
+            info!("🎮 Configuring {} GPU layers", config.n_gpu_layers);
+            model_params = model_params.with_n_gpu_layers(config.n_gpu_layers as u32);
+        } else {
+            info!("💻 Using CPU-only inference");
+        }
+
+        // Load the model
+        info!("📚 Loading model from file...");
+        let model_start = Instant::now();
+        let model = LlamaModel::load_from_file(&backend, model_path, &model_params)
+            .map_err(|e| {
+                error!("❌ Failed to load model: {}", e);
+                LlmError::LlamaCppError(format!("Failed to load model: {}", e))
+            })?;
+        let model_duration = model_start.elapsed();
+        info!("✅ Model loaded successfully in {:?}", model_duration);
+
+        let fim = FimTokens::from_model(&model);
+        match &fim {
+            Some(fim) => info!("🧩 Model declares FIM tokens (prefix {}, suffix {}, middle {})", fim.prefix, fim.suffix, fim.middle),
+            None => debug!("🧩 Model declares no FIM tokens; infill is unavailable"),
+        }
+
+        let hermes_tools = model
+            .meta_val_str("tokenizer.chat_template")
+            .is_ok_and(|template| template.contains(tools::CALL_OPEN));
+        debug!("🧰 Hermes tool calls in chat template: {}", hermes_tools);
+
+        let embedding_model = match embedding_model_path {
+            Some(path) => {
+                info!("📚 Loading embedding model from {}", path.display());
+                let model = LlamaModel::load_from_file(&backend, path, &model_params)
+                    .map_err(|e| {
+                        error!("❌ Failed to load embedding model: {}", e);
+                        LlmError::LlamaCppError(format!("Failed to load embedding model: {}", e))
+                    })?;
+                Some(model)
+            }
+            None => None,
+        };
+
+        let draft_model = match draft_model_path {
+            Some(path) => {
+                info!("📚 Loading draft model from {}", path.display());
+                let draft = LlamaModel::load_from_file(&backend, path, &model_params)
+                    .map_err(|e| {
+                        error!("❌ Failed to load draft model: {}", e);
+                        LlmError::LlamaCppError(format!("Failed to load draft model: {}", e))
+                    })?;
+                // Drafted token ids are checked by the main model as they are.
+                if draft.n_vocab() != model.n_vocab() {
+                    return Err(LlmError::ModelError(format!(
+                        "Draft model has {} tokens in its vocabulary but the model has {}",
+                        draft.n_vocab(),
+                        model.n_vocab()
+                    )));
+                }
+                Some(draft)
+            }
+            None => None,
+        };
+
+        let adapters = config
+            .lora_adapters
+            .iter()
+            .map(|adapter| {
+                Ok(LoadedAdapter {
+                    path: adapter.path.clone(),
+                    adapter: Self::load_adapter(&model, &adapter.path)?,
+                    scale: Some(adapter.scale),
+                })
+            })
+            .collect::<Result<Vec<_>, LlmError>>()?;
+
+        Ok(Self {
+            config,
+            backend,
+            model,
+            fim,
+            hermes_tools,
+            embedding_model,
+            draft_model,
+            adapters: Mutex::new(adapters),
+            slot: tokio::sync::Mutex::new(()),
+            cancel_token,
+        })
+    }
+
+    fn load_adapter(model: &LlamaModel, path: &Path) -> Result<LlamaLoraAdapter, LlmError> {
+        info!("🧬 Loading LoRA adapter from {}", path.display());
+        model.lora_adapter_init(path).map_err(|e| {
+            error!("❌ Failed to load LoRA adapter: {}", e);
+            LlmError::LlamaCppError(format!("Failed to load LoRA adapter {}: {}", path.display(), e))
+        })
+    }
+
+    /// Apply the ChatML template to `messages`, leaving the assistant turn open.
+    /// A trailing assistant message is left open for the model to continue.
+    /// When `tools` are offered they are described in the system turn.
+    fn build_prompt(messages: &[ChatMessage], tools: &[&Tool]) -> String {
+        info!("🔨 Building prompt from {} messages", messages.len());
+        let mut prompt = String::new();
+        let mut messages = messages.iter().peekable();
+        if !tools.is_empty() {
+            let system = messages.next_if(|message| message.role == "system");
+            let system = tools::system_prompt(system.map(|message| message.content.as_str()), tools);
+            prompt.push_str(&format!("<|im_start|>system\n{}<|im_end|>\n", system));
+        }
+        let mut i = 0;
+        let mut continuing = false;
+        while let Some(message) = messages.next() {
+            let formatted_message = match message.role.as_str() {
+                "assistant" if messages.peek().is_none() => {
+                    continuing = true;
+                    format!("<|im_start|>assistant\n{}", tools::assistant_content(message))
+                }
+                "system" => format!("<|im_start|>system\n{}<|im_end|>\n", message.content),
+                "user" => format!("<|im_start|>user\n{}<|im_end|>\n", message.content),
+                "assistant" => format!("<|im_start|>assistant\n{}<|im_end|>\n", tools::assistant_content(message)),
+                "tool" => {
+                    // Results of the same turn's calls share one user turn.
+                    let mut results = tools::tool_response(&message.content);
+                    while let Some(next) = messages.next_if(|next| next.role == "tool") {
+                        results.push('\n');
+                        results.push_str(&tools::tool_response(&next.content));
+                    }
+                    format!("<|im_start|>user\n{}<|im_end|>\n", results)
+                }
+                _ => {
+                    warn!("⚠️ Unknown message role '{}', treating as user", message.role);
+                    format!("<|im_start|>user\n{}<|im_end|>\n", message.content)
+                }
+            };
+            i += 1;
+            debug!("🔧 Formatted message {}: {} chars", i, formatted_message.len());
+            prompt.push_str(&formatted_message);
+        }
+        if !continuing {
+            prompt.push_str("<|im_start|>assistant\n");
+        }
+        prompt
+    }
+
+    /// Biases for `logit_bias`, whose keys are token ids or text whose tokens are all biased.
+    fn logit_biases(&self, logit_bias: &HashMap<String, f32>) -> Result<Vec<LlamaLogitBias>, LlmError> {
+        let n_vocab = self.model.n_vocab();
+        let mut biases = Vec::new();
+        for (key, &bias) in logit_bias {
+            match key.parse::<i32>() {
+                Ok(id) if (0..n_vocab).contains(&id) => biases.push(LlamaLogitBias::new(LlamaToken::new(id), bias)),
+                Ok(id) => {
+                    return Err(LlmError::ConfigError(format!(
+                        "logit_bias names token {} but the vocabulary has {} tokens",
+                        id, n_vocab
+                    )))
+                }
+                Err(_) => {
+                    let tokens = self.str_to_tokens(key, AddBos::Never)?;
+                    biases.extend(tokens.into_iter().map(|token| LlamaLogitBias::new(token, bias)));
+                }
+            }
+        }
+        Ok(biases)
+    }
+
+    fn str_to_tokens(&self, text: &str, add_bos: AddBos) -> Result<Vec<LlamaToken>, LlmError> {
+        self.model.str_to_token(text, add_bos).map_err(|e| {
+            error!("❌ Failed to tokenize text: {}", e);
+            LlmError::LlamaCppError(format!("Failed to tokenize text: {}", e))
+        })
+    }
+
+    /// Like `str_to_tokens`, but special-token text is only parsed as the
+    /// special token with `parse_special` set. llama-cpp-2 always parses it, so
+    /// otherwise llama.cpp's tokenizer is called directly.
+    fn tokenize_text(&self, text: &str, add_bos: bool, parse_special: bool) -> Result<Vec<LlamaToken>, LlmError> {
+        if parse_special {
+            return self.str_to_tokens(text, if add_bos { AddBos::Always } else { AddBos::Never });
+        }
+        let failed = |reason: String| {
+            error!("❌ Failed to tokenize text: {}", reason);
+            LlmError::LlamaCppError(format!("Failed to tokenize text: {}", reason))
+        };
+        let text = CString::new(text).map_err(|e| failed(e.to_string()))?;
+        let len = i32::try_from(text.as_bytes().len()).map_err(|e| failed(e.to_string()))?;
+
+        // SAFETY: `LlamaModel` is a `#[repr(transparent)]` wrapper around its
+        // `llama_model` pointer, which stays valid while `self.model` lives.
+        let model = unsafe { *(&self.model as *const LlamaModel as *const NonNull<llama_cpp_sys_2::llama_model>) };
+        let vocab = unsafe { llama_cpp_sys_2::llama_model_get_vocab(model.as_ptr()) };
+        let mut tokens: Vec<llama_cpp_sys_2::llama_token> = vec![0; text.as_bytes().len() + 1];
+        for _ in 0..2 {
+            // SAFETY: `text` is a valid C string of `len` bytes and `tokens` has
+            // room for the number of tokens passed.
+            let count = unsafe {
+                llama_cpp_sys_2::llama_tokenize(vocab, text.as_ptr(), len, tokens.as_mut_ptr(), tokens.len() as i32, add_bos, false)
+            };
+            // A negative count is the room that was missing.
+            match usize::try_from(count) {
+                Ok(count) => {
+                    tokens.truncate(count);
+                    return Ok(tokens.into_iter().map(LlamaToken).collect());
+                }
+                Err(_) => tokens.resize(count.unsigned_abs() as usize, 0),
+            }
+        }
+        Err(failed("the token count changed between calls".to_string()))
+    }
+
+    fn generate(&self, request: ChatRequest, on_token: TokenSink<'_>) -> Result<ChatResponse, LlmError> {
+        let tools = tools::offered(&request)?;
+        if !tools.is_empty() {
+            info!("🧰 Offering {} tools", tools.len());
+            if !self.hermes_tools {
+                warn!("⚠️ Model {} was not trained on Hermes-style tool calls, they may be unreliable", self.config.model_name);
+            }
+        }
+        let prompt = Self::build_prompt(&request.messages, &tools);
+        info!("📄 Final prompt built: {} total characters", prompt.len());
+        debug!("📋 Complete prompt: '{}'", prompt);
+
+        let tokens = self.str_to_tokens(&prompt, AddBos::Always)?;
+        let grammar = request.effective_grammar()?;
+        let (n, best_of) = request.choice_counts()?;
+        let options = RunOptions {
+            max_tokens: request.max_tokens,
+            thinking_budget: request.thinking_budget,
+            grammar: grammar.as_deref(),
+            sequences: best_of,
+            temperature: request.temperature.map_or(self.config.temperature, |t| t as f32),
+            top_p: request.top_p.map_or(self.config.top_p, |p| p as f32),
+            score: best_of > n,
+            logprobs: request.logprob_count()?,
+            sampling: request.sampling.over(&self.config.sampling),
+            cancel: request.cancel.clone(),
+        };
+        let (mut generations, usage) = if tools.is_empty() {
+            self.run(tokens, &options, on_token)?
+        } else {
+            let filter = Mutex::new(CallFilter::default());
+            let sink = |piece: &str| {
+                let text = filter.lock().unwrap().push(piece);
+                if !text.is_empty() {
+                    on_token(&text);
+                }
+            };
+            let run = self.run(tokens, &options, &sink)?;
+            let rest = filter.into_inner().unwrap().finish();
+            if !rest.is_empty() {
+                on_token(&rest);
+            }
+            run
+        };
+        if best_of > n {
+            info!("🏆 Keeping the {} most likely of {} replies", n, best_of);
+            generations.sort_by(|a, b| b.logprob.total_cmp(&a.logprob));
+            generations.truncate(n);
+        }
+
+        let choices = generations
+            .into_iter()
+            .enumerate()
+            .map(|(index, generation)| {
+                let (content, tool_calls) = if tools.is_empty() {
+                    (generation.text, None)
+                } else {
+                    let (content, calls) = tools::parse_calls(&generation.text);
+                    info!("🔧 Model made {} tool calls", calls.len());
+                    (content, Some(calls).filter(|calls| !calls.is_empty()))
+                };
+                let finish_reason = if tool_calls.is_some() { "tool_calls" } else { generation.finish_reason };
+                let logprobs = options.logprobs.map(|_| ChoiceLogprobs { content: generation.logprobs });
+                ChatChoice {
+                    index: index as u32,
+                    message: ChatMessage {
+                        role: "assistant".to_string(),
+                        content,
+                        tool_calls,
+                        ..Default::default()
+                    },
+                    logprobs,
+                    finish_reason: Some(finish_reason.to_string()),
+                }
+            })
+            .collect();
+
+        let chat_response = ChatResponse {
+            id: "chat-completion".to_string(),
+            object: "chat.completion".to_string(),
+            created: chrono::Utc::now().timestamp() as u64,
+            model: self.config.model_name.clone(),
+            choices,
+            usage: Some(usage),
+            provider: None,
+        };
+
+        info!("🎉 Chat completion successful! Returning response to frontend");
+        debug!("📋 Complete response structure: {:?}", chat_response);
+
+        Ok(chat_response)
+    }
+
+    /// Continue `request.prompt` as is, without a chat template. With a suffix,
+    /// the model fills in the text between the two using its FIM tokens.
+    fn complete(&self, request: CompletionRequest, on_token: TokenSink<'_>) -> Result<CompletionResponse, LlmError> {
+        let tokens = match &request.suffix {
+            None => {
+                info!("📄 Raw prompt: {} total characters", request.prompt.len());
+                debug!("📋 Complete prompt: '{}'", request.prompt);
+                self.str_to_tokens(&request.prompt, AddBos::Always)?
+            }
+            Some(suffix) => {
+                let fim = self.fim.ok_or_else(|| {
+                    LlmError::ConfigError(format!("Model {} has no fill-in-the-middle tokens", self.config.model_name))
+                })?;
+                info!("🧩 Infill: {} characters before, {} after", request.prompt.len(), suffix.len());
+                // Tokenizing nothing yields just the BOS token, if this model uses one.
+                let bos = self.str_to_tokens("", AddBos::Always)?;
+                let prefix = self.str_to_tokens(&request.prompt, AddBos::Never)?;
+                let suffix = self.str_to_tokens(suffix, AddBos::Never)?;
+                fim.prompt(&bos, &prefix, &suffix)
+            }
+        };
+
+        let options = RunOptions {
+            max_tokens: request.max_tokens,
+            thinking_budget: None,
+            grammar: None,
+            sequences: 1,
+            temperature: request.temperature.map_or(self.config.temperature, |t| t as f32),
+            top_p: request.top_p.map_or(self.config.top_p, |p| p as f32),
+            score: false,
+            logprobs: None,
+            sampling: request.sampling.over(&self.config.sampling),
+            cancel: request.cancel.clone(),
+        };
+        let (generations, usage) = self.run(tokens, &options, on_token)?;
+        let generation = generations.into_iter().next().ok_or_else(|| LlmError::ModelError("No text was generated".to_string()))?;
+
+        info!("🎉 Text completion successful!");
+        Ok(CompletionResponse {
+            id: "text-completion".to_string(),
+            object: "text_completion".to_string(),
+            created: chrono::Utc::now().timestamp() as u64,
+            model: self.config.model_name.clone(),
+            choices: vec![CompletionChoice {
+                index: 0,
+                text: generation.text,
+                finish_reason: Some(generation.finish_reason.to_string()),
+            }],
+            usage: Some(usage),
+            provider: None,
+        })
+    }
+
+    /// Decode the prompt `tokens_list` once, then sample `options.sequences` replies from it until end of
+    /// generation, `max_tokens` or the end of the context. Tokens only reach `on_token` for a single reply.
+    fn run(&self, tokens_list: Vec<LlamaToken>, options: &RunOptions<'_>, on_token: TokenSink<'_>) -> Result<(Vec<Generation>, ChatUsage), LlmError> {
+        let start_time = Instant::now();
+        let backend = &self.backend;
+        let model = &self.model;
+
+        let max_tokens = options.max_tokens.unwrap_or(self.config.max_tokens);
+        let n_seqs = options.sequences.max(1);
+        let prompt_tokens_len = tokens_list.len();
+        if tokens_list.is_empty() {
+            return Err(LlmError::ConfigError("The prompt is empty".to_string()));
+        }
+        if prompt_tokens_len >= self.config.ctx_size as usize {
+            error!("❌ Prompt of {} tokens does not fit in context of {}", prompt_tokens_len, self.config.ctx_size);
+            return Err(LlmError::ContextOverflow(format!(
+                "prompt is {} tokens but the context holds {}",
+                prompt_tokens_len, self.config.ctx_size
+            )));
+        }
+        // Never generate past the end of the context window: positions from
+        // n_len on are not written to the KV cache.
+        let n_len = prompt_tokens_len as i32 + reply_budget(self.config.ctx_size, prompt_tokens_len, n_seqs, max_tokens);
+
+        info!("🔤 Prompt is {} tokens", prompt_tokens_len);
+        debug!("🎯 Generation parameters: max_tokens={}, total_limit={}, sequences={}", max_tokens, n_len, n_seqs);
+
+        // Create a context for this request
+        info!("🧠 Creating context for inference...");
+        let context_start = Instant::now();
+        let mut ctx_params = LlamaContextParams::default()
+            .with_n_ctx(Some(std::num::NonZeroU32::new(self.config.ctx_size).unwrap()));
+        // Every reply is decoded as its own sequence.
+        ctx_params = with_n_seq_max(ctx_params, n_seqs as u32);
+
+        if let Some(threads) = self.config.n_threads {
+            debug!("🔧 Using {} threads for processing", threads);
+            ctx_params = ctx_params.with_n_threads(threads);
+        } else {
+            debug!("🔧 Using default thread count");
+        }
+
+        let mut draft = match &self.draft_model {
+            Some(draft_model) if n_seqs == 1 => {
+                let context = draft_model.new_context(backend, ctx_params.clone()).map_err(|e| {
+                    error!("❌ Failed to create draft context: {}", e);
+                    LlmError::LlamaCppError(format!("Failed to create draft context: {}", e))
+                })?;
+                Some(Draft {
+                    model: draft_model,
+                    context,
+                    sampler: LlamaSampler::greedy(),
+                    prompt: tokens_list.clone(),
+                    cached: Vec::new(),
+                })
+            }
+            Some(_) => {
+                debug!("📝 Not drafting for {} sequences at once", n_seqs);
+                None
+            }
+            None => None,
+        };
+        let draft_tokens = (self.config.speculative.draft_tokens as usize).min(BATCH_SIZE / 2);
+
+        let mut context = model
+            .new_context(backend, ctx_params)
+            .map_err(|e| {
+                error!("❌ Failed to create context: {}", e);
+                LlmError::LlamaCppError(format!("Failed to create context: {}", e))
+            })?;
+
+        // Adapters attached later only apply to the generations after this one.
+        for loaded in self.adapters.lock().unwrap().iter_mut() {
+            if let Some(scale) = loaded.scale {
+                debug!("🧬 Applying LoRA adapter {} at {}", loaded.path.display(), scale);
+                context.lora_adapter_set(&mut loaded.adapter, scale).map_err(|e| {
+                    error!("❌ Failed to apply LoRA adapter: {}", e);
+                    LlmError::LlamaCppError(format!("Failed to apply LoRA adapter {}: {}", loaded.path.display(), e))
+                })?;
+            }
+        }
+
+        let context_duration = context_start.elapsed();
+        info!("✅ Context created successfully in {:?}", context_duration);
+
+        // Process the prompt, a batch at a time, with logits for its last token only
+        info!("⚡ Processing prompt through model...");
+        let decode_start = Instant::now();
+        let mut batch = LlamaBatch::new(BATCH_SIZE, 1);
+        let last_index = prompt_tokens_len as i32 - 1;
+        for (start, chunk) in (0_i32..).step_by(BATCH_SIZE).zip(tokens_list.chunks(BATCH_SIZE)) {
+            batch.clear();
+            for (i, &token) in (start..).zip(chunk) {
+                batch.add(token, i, &[0], i == last_index)
+                    .map_err(|e| {
+                        error!("❌ Failed to add token {} to batch: {}", i, e);
+                        LlmError::LlamaCppError(format!("Failed to add token to batch: {}", e))
+                    })?;
+            }
+            context.decode(&mut batch)
+                .map_err(|e| {
+                    error!("❌ Failed to decode prompt: {}", e);
+                    LlmError::LlamaCppError(format!("Failed to decode prompt: {}", e))
+                })?;
+        }
+
+        let decode_duration = decode_start.elapsed();
+        info!("✅ Prompt processed in {:?}", decode_duration);
+
+        // Further sequences share the prompt's KV cache rather than decoding it again.
+        for id in 1..n_seqs as i32 {
+            context.copy_kv_cache_seq(0, id, None, None).map_err(|e| {
+                error!("❌ Failed to copy the prompt to sequence {}: {}", id, e);
+                LlmError::LlamaCppError(format!("Failed to copy KV cache: {}", e))
+            })?;
+        }
+
+        if options.grammar.is_some() {
+            info!("📐 Constraining generation with a grammar");
+        }
+        let sampling = &options.sampling;
+        let biases = self.logit_biases(&sampling.logit_bias)?;
+        // The penalties sampler takes -1 as 0 rather than as the whole context.
+        let repeat_last_n = if sampling.repeat_last_n == -1 { self.config.ctx_size as i32 } else { sampling.repeat_last_n };
+        let penalized = sampling.repeat_penalty != 1.0 || sampling.frequency_penalty != 0.0 || sampling.presence_penalty != 0.0;
+        debug!("🎛️ Sampling with {:?}", sampling);
+        let sampler = || {
+            let mut samplers = Vec::new();
+            if let Some(grammar) = options.grammar {
+                samplers.push(LlamaSampler::grammar(model, grammar, GRAMMAR_ROOT));
+            }
+            if !biases.is_empty() {
+                samplers.push(LlamaSampler::logit_bias(model.n_vocab(), &biases));
+            }
+            if penalized {
+                samplers.push(LlamaSampler::penalties(
+                    repeat_last_n,
+                    sampling.repeat_penalty,
+                    sampling.frequency_penalty,
+                    sampling.presence_penalty,
+                ));
+            }
+            if sampling.dry_multiplier > 0.0 {
+                samplers.push(LlamaSampler::dry(
+                    model,
+                    sampling.dry_multiplier,
+                    sampling.dry_base,
+                    sampling.dry_allowed_length,
+                    sampling.dry_penalty_last_n,
+                    &sampling.dry_sequence_breakers,
+                ));
+            }
+            match sampling.mirostat {
+                1 => {
+                    samplers.push(LlamaSampler::temp(options.temperature));
+                    samplers.push(LlamaSampler::mirostat(model.n_vocab(), RANDOM_SEED, sampling.mirostat_tau, sampling.mirostat_eta, MIROSTAT_M));
+                }
+                2 => {
+                    samplers.push(LlamaSampler::temp(options.temperature));
+                    samplers.push(LlamaSampler::mirostat_v2(RANDOM_SEED, sampling.mirostat_tau, sampling.mirostat_eta));
+                }
+                _ if options.temperature > 0.0 => {
+                    samplers.push(LlamaSampler::top_p(options.top_p, 1));
+                    samplers.push(LlamaSampler::temp(options.temperature));
+                    samplers.push(LlamaSampler::dist(RANDOM_SEED));
+                }
+                _ => samplers.push(LlamaSampler::greedy()),
+            }
+            LlamaSampler::chain_simple(samplers)
+        };
+        let initial_tokens = prompt_tokens_len as i32;
+        let mut sequences: Vec<Sequence> = (0..n_seqs as i32)
+            .map(|id| Sequence {
+                id,
+                sampler: sampler(),
+                decoder: UTF_8.new_decoder(),
+                text: String::new(),
+                pos: initial_tokens,
+                tokens: Vec::new(),
+                draft: Vec::new(),
+                logits: vec![batch.n_tokens() - 1],
+                drafted: 0,
+                accepted: 0,
+                thinking_tokens: 0,
+                thinking_closed: false,
+                logprob: 0.0,
+                logprobs: Vec::new(),
+                finish_reason: (initial_tokens >= n_len).then_some("length"),
+            })
+            .collect();
+        let generation_start = Instant::now();
+
+        info!("🎯 Starting token generation (max {} tokens)...", max_tokens);
+        debug!("📊 Initial state: initial_tokens={}, target_length={}", initial_tokens, n_len);
+
+        // Generate response tokens
+        let mut tokens_generated = 0;
+        while sequences.iter().any(|seq| seq.finish_reason.is_none()) {
+            if self.cancel_token.is_cancelled() || options.cancel.is_cancelled() {
+                warn!("🛑 Generation cancelled after {} tokens", tokens_generated);
+                return Err(LlmError::Cancelled);
+            }
+
+            batch.clear();
+            for seq in sequences.iter_mut().filter(|seq| seq.finish_reason.is_none()) {
+                // Sample after the last certain token, then after each drafted
+                // one for as long as the draft matches what was sampled.
+                let mut next = Vec::new();
+                let mut agreed = 0;
+                for (i, &index) in seq.logits.iter().enumerate() {
+                    // Sampling also accepts the token into the sampler chain.
+                    let token = seq.sampler.sample(&context, index);
+                    let logits = context.get_logits_ith(index);
+                    let normalizer = (options.score || options.logprobs.is_some()).then(|| log_normalizer(logits));
+                    if let Some(normalizer) = normalizer {
+                        seq.logprob += f64::from(logits[token.0 as usize]) - normalizer;
+                    }
+
+                    // Check for end of generation
+                    if model.is_eog_token(token) {
+                        info!("🏁 End of generation token encountered at position {} of sequence {}", seq.pos, seq.id);
+                        seq.finish_reason = Some("stop");
+                        break;
+                    }
+
+                    // Convert token to text
+                    let token_bytes = |token: LlamaToken| model.token_to_bytes(token, Special::Tokenize)
+                        .map_err(|e| {
+                            error!("❌ Failed to convert token {} to bytes: {}", token, e);
+                            LlmError::LlamaCppError(format!("Failed to convert token to bytes: {}", e))
+                        });
+                    let output_bytes = token_bytes(token)?;
+
+                    if let (Some(top), Some(normalizer)) = (options.logprobs, normalizer) {
+                        let logprob = |token: LlamaToken| f64::from(logits[token.0 as usize]) - normalizer;
+                        let top_logprobs = top_tokens(logits, top)
+                            .into_iter()
+                            .map(|alternative| {
+                                let bytes = token_bytes(alternative)?;
+                                Ok(TopLogprob {
+                                    token: String::from_utf8_lossy(&bytes).into_owned(),
+                                    logprob: logprob(alternative),
+                                    bytes,
+                                })
+                            })
+                            .collect::<Result<_, LlmError>>()?;
+                        seq.logprobs.push(TokenLogprob {
+                            token: String::from_utf8_lossy(&output_bytes).into_owned(),
+                            logprob: logprob(token),
+                            bytes: output_bytes.clone(),
+                            top_logprobs,
+                        });
+                    }
+
+                    let mut output_string = String::with_capacity(32);
+                    let _decode_result = seq.decoder.decode_to_string(&output_bytes, &mut output_string, false);
+                    seq.text.push_str(&output_string);
+                    if n_seqs == 1 && !output_string.is_empty() {
+                        on_token(&output_string);
+                    }
+
+                    tokens_generated += 1;
+
+                    // Log progress every 10 tokens or for first few tokens
+                    if tokens_generated <= 5 || tokens_generated % 10 == 0 {
+                        debug!("🔄 Token {}: '{}' (sequence {} length: {} chars)",
+                            tokens_generated,
+                            output_string.replace('\n', "\\n"),
+                            seq.id,
+                            seq.text.len()
+                        );
+                    }
+
+                    next.push(token);
+
+                    // A thinking model that has used up its budget is made to stop reasoning and answer.
+                    if let Some(budget) = options.thinking_budget {
+                        // Only this token can have completed the closing tag.
+                        let tail = seq.text.len().saturating_sub(output_string.len() + THINK_CLOSE.len());
+                        seq.thinking_closed |= seq.text.as_bytes()[tail..].windows(THINK_CLOSE.len()).any(|w| w == THINK_CLOSE.as_bytes());
+                        let thinking = !seq.thinking_closed && seq.text.trim_start().starts_with(THINK_OPEN);
+                        if thinking {
+                            seq.thinking_tokens += 1;
+                        }
+                        if thinking && seq.thinking_tokens >= budget {
+                            info!("💭 Thinking budget of {} tokens used up, closing the reasoning", budget);
+                            let close = format!("\n{}\n\n", THINK_CLOSE);
+                            let close_tokens = self.str_to_tokens(&close, AddBos::Never)?;
+                            // The samplers see the tag as if it had been sampled,
+                            // so penalties and grammar state stay in step.
+                            seq.sampler.accept_many(&close_tokens);
+                            next.extend(close_tokens);
+                            seq.text.push_str(&close);
+                            seq.thinking_closed = true;
+                            if n_seqs == 1 {
+                                on_token(&close);
+                            }
+                            break;
+                        }
+                    }
+
+                    // A drafted token the model agrees with is already in the KV cache.
+                    if seq.draft.get(i) != Some(&token) {
+                        break;
+                    }
+                    next.clear();
+                    seq.tokens.push(token);
+                    agreed += 1;
+                    seq.pos += 1;
+                    if seq.pos >= n_len {
+                        seq.finish_reason = Some("length");
+                        break;
+                    }
+                }
+                seq.accepted += agreed as u32;
+                if seq.finish_reason.is_some() {
+                    continue;
+                }
+                // A closing tag for the reasoning may not fit either.
+                next.truncate((n_len - seq.pos) as usize);
+                // A reply that has used its budget ends without decoding its last tokens.
+                if seq.pos + next.len() as i32 >= n_len {
+                    seq.pos += next.len() as i32;
+                    seq.tokens.extend_from_slice(&next);
+                    seq.finish_reason = Some("length");
+                    continue;
+                }
+                if agreed < seq.draft.len() {
+                    context.clear_kv_cache_seq(Some(seq.id as u32), Some(seq.pos as u32), None).map_err(|e| {
+                        error!("❌ Failed to drop rejected draft tokens: {}", e);
+                        LlmError::LlamaCppError(format!("Failed to clear KV cache: {}", e))
+                    })?;
+                }
+
+                // Prepare for next iteration
+                for (i, &token) in next.iter().enumerate() {
+                    batch.add(token, seq.pos, &[seq.id], i == next.len() - 1)
+                        .map_err(|e| {
+                            error!("❌ Failed to add token {} to batch at position {}: {}", token, seq.pos, e);
+                            LlmError::LlamaCppError(format!("Failed to add token to batch: {}", e))
+                        })?;
+                    seq.pos += 1;
+                }
+                seq.tokens.extend_from_slice(&next);
+
+                // Let the draft model guess the tokens after that, to check them in the same batch.
+                seq.draft = match draft.as_mut() {
+                    Some(draft) => draft.propose(&seq.tokens, draft_tokens.min((n_len - seq.pos) as usize))?,
+                    None => Vec::new(),
+                };
+                seq.drafted += seq.draft.len() as u32;
+                for (i, &token) in (seq.pos..).zip(&seq.draft) {
+                    batch.add(token, i, &[seq.id], true)
+                        .map_err(|e| {
+                            error!("❌ Failed to add draft token {} to batch at position {}: {}", token, i, e);
+                            LlmError::LlamaCppError(format!("Failed to add token to batch: {}", e))
+                        })?;
+                }
+                let last = batch.n_tokens() - 1;
+                seq.logits = (last - seq.draft.len() as i32..=last).collect();
+            }
+            if batch.n_tokens() == 0 {
+                break;
+            }
+
+            // Decode the next tokens
+            context.decode(&mut batch)
+                .map_err(|e| {
+                    error!("❌ Failed to decode token at step {}: {}", tokens_generated, e);
+                    LlmError::LlamaCppError(format!("Failed to decode token: {}", e))
+                })?;
+        }
+
+        let generation_duration = generation_start.elapsed();
+        let completion_tokens: i32 = sequences.iter().map(|seq| seq.pos - initial_tokens).sum();
+
+        info!("✅ Token generation complete: {} tokens in {:?} ({:.2} tokens/sec)",
+            completion_tokens,
+            generation_duration,
+            completion_tokens as f64 / generation_duration.as_secs_f64()
+        );
+
+        let total_duration = start_time.elapsed();
+        let prompt_tokens = prompt_tokens_len as u32;
+        let completion_tokens = completion_tokens as u32;
+        let total_tokens = prompt_tokens + completion_tokens;
+
+        // Log response statistics
+        info!("📊 Response statistics:");
+        info!("   • Prompt tokens: {}", prompt_tokens);
+        info!("   • Completion tokens: {}", completion_tokens);
+        info!("   • Total tokens: {}", total_tokens);
+        info!("   • Response length: {} characters", sequences.iter().map(|seq| seq.text.len()).sum::<usize>());
+        info!("   • Total processing time: {:?}", total_duration);
+
+        // Log the complete response content
+        info!("💬 Generated response content:");
+        for seq in &sequences {
+            debug!("📝 Full response {}: '{}'", seq.id, seq.text);
+        }
+
+        let completion_tokens_details = draft.is_some().then(|| {
+            let drafted: u32 = sequences.iter().map(|seq| seq.drafted).sum();
+            let accepted: u32 = sequences.iter().map(|seq| seq.accepted).sum();
+            info!("   • Draft tokens accepted: {} of {} ({:.0}%)",
+                accepted,
+                drafted,
+                100.0 * accepted as f64 / drafted.max(1) as f64
+            );
+            CompletionTokensDetails {
+                accepted_prediction_tokens: accepted,
+                rejected_prediction_tokens: drafted - accepted,
+            }
+        });
+
+        let generations = sequences
+            .into_iter()
+            .map(|seq| Generation {
+                text: seq.text,
+                finish_reason: seq.finish_reason.unwrap_or("stop"),
+                logprob: seq.logprob,
+                logprobs: seq.logprobs,
+            })
+            .collect();
+        Ok((generations, ChatUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens,
+            completion_tokens_details,
+        }))
+    }
+
+    /// One pooled embedding per input text, from the embedding model if one is
+    /// loaded and the chat model otherwise.
+    fn embed(&self, request: EmbeddingsRequest) -> Result<EmbeddingsResponse, LlmError> {
+        let model = self.embedding_model.as_ref().unwrap_or(&self.model);
+        let pooling = match request.pooling.unwrap_or_default() {
+            EmbeddingPooling::Mean => LlamaPoolingType::Mean,
+            EmbeddingPooling::Cls => LlamaPoolingType::Cls,
+            EmbeddingPooling::Last => LlamaPoolingType::Last,
+            EmbeddingPooling::Model => LlamaPoolingType::Unspecified,
+        };
+        let texts = request.input.into_texts();
+        info!("🧮 Embedding {} input(s) with {:?} pooling", texts.len(), pooling);
+        let embed_start = Instant::now();
+
+        // The whole input is decoded in one batch, so the batch must hold a full context.
+        let mut ctx_params = LlamaContextParams::default()
+            .with_n_ctx(Some(std::num::NonZeroU32::new(self.config.ctx_size).unwrap()))
+            .with_n_batch(self.config.ctx_size)
+            .with_n_ubatch(self.config.ctx_size)
+            .with_embeddings(true)
+            .with_pooling_type(pooling);
+        if let Some(threads) = self.config.n_threads {
+            ctx_params = ctx_params.with_n_threads(threads);
+        }
+
+        let mut context = model
+            .new_context(&self.backend, ctx_params)
+            .map_err(|e| {
+                error!("❌ Failed to create embedding context: {}", e);
+                LlmError::LlamaCppError(format!("Failed to create embedding context: {}", e))
+            })?;
+
+        let mut data = Vec::with_capacity(texts.len());
+        let mut prompt_tokens = 0;
+        for (index, text) in (0_u32..).zip(texts) {
+            if self.cancel_token.is_cancelled() {
+                warn!("🛑 Embedding cancelled after {} input(s)", index);
+                return Err(LlmError::Cancelled);
+            }
+
+            let tokens = model
+                .str_to_token(&text, AddBos::Always)
+                .map_err(|e| LlmError::LlamaCppError(format!("Failed to tokenize input: {}", e)))?;
+            if tokens.len() > self.config.ctx_size as usize {
+                return Err(LlmError::ContextOverflow(format!(
+                    "input {} is {} tokens but the context holds {}",
+                    index, tokens.len(), self.config.ctx_size
+                )));
+            }
+            prompt_tokens += tokens.len() as u32;
+
+            let mut batch = LlamaBatch::new(tokens.len(), 1);
+            batch.add_sequence(&tokens, 0, false)
+                .map_err(|e| LlmError::LlamaCppError(format!("Failed to add input to batch: {}", e)))?;
+
+            context.clear_kv_cache();
+            context.decode(&mut batch)
+                .map_err(|e| LlmError::LlamaCppError(format!("Failed to decode input: {}", e)))?;
+
+            let embedding = context.embeddings_seq_ith(0)
+                .map_err(|e| LlmError::LlamaCppError(format!("Failed to read embedding: {}", e)))?;
+            debug!("🔧 Input {}: {} tokens, {} dimensions", index, tokens.len(), embedding.len());
+
+            data.push(Embedding {
+                object: "embedding".to_string(),
+                index,
+                embedding: embedding.to_vec(),
+                tokens: tokens.len() as u32,
+            });
+        }
+
+        info!("✅ Embedded {} input(s) ({} tokens) in {:?}", data.len(), prompt_tokens, embed_start.elapsed());
+
+        Ok(EmbeddingsResponse {
+            object: "list".to_string(),
+            data,
+            model: self.config.embeddings.model.clone().unwrap_or_else(|| self.config.model_name.clone()),
+            usage: EmbeddingsUsage {
+                prompt_tokens,
+                total_tokens: prompt_tokens,
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl ChatProvider for LlamaProvider {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    async fn chat_completion(&self, request: ChatRequest) -> Result<ChatResponse, LlmError> {
+        self.chat_completion_stream(request, &|_| {}).await
+    }
+
+    async fn chat_completion_stream(&self, request: ChatRequest, on_token: TokenSink<'_>) -> Result<ChatResponse, LlmError> {
+        let _slot = self.slot.lock().await;
+        blocking(|| self.generate(request, on_token))
+    }
+
+    async fn text_completion(&self, request: CompletionRequest) -> Result<CompletionResponse, LlmError> {
+        self.text_completion_stream(request, &|_| {}).await
+    }
+
+    async fn text_completion_stream(&self, request: CompletionRequest, on_token: TokenSink<'_>) -> Result<CompletionResponse, LlmError> {
+        let _slot = self.slot.lock().await;
+        blocking(|| self.complete(request, on_token))
+    }
+
+    async fn embeddings(&self, request: EmbeddingsRequest) -> Result<EmbeddingsResponse, LlmError> {
+        let _slot = self.slot.lock().await;
+        blocking(|| self.embed(request))
+    }
+
+    fn is_busy(&self) -> bool {
+        self.slot.try_lock().is_err()
+    }
+
+    fn tokenizer(&self) -> Option<&dyn Tokenizer> {
+        Some(self)
+    }
+
+    fn lora_adapters(&self) -> Option<&dyn LoraAdapters> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl LoraAdapters for LlamaProvider {
+    async fn list(&self) -> Result<Vec<LoraAdapterConfig>, LlmError> {
+        let adapters = self.adapters.lock().unwrap();
+        Ok(adapters
+            .iter()
+            .filter_map(|loaded| {
+                loaded.scale.map(|scale| LoraAdapterConfig {
+                    path: loaded.path.clone(),
+                    scale,
+                })
+            })
+            .collect())
+    }
+
+    async fn attach(&self, adapter: LoraAdapterConfig) -> Result<(), LlmError> {
+        let mut adapters = self.adapters.lock().unwrap();
+        match adapters.iter_mut().find(|loaded| loaded.path == adapter.path) {
+            Some(loaded) => loaded.scale = Some(adapter.scale),
+            None => adapters.push(LoadedAdapter {
+                adapter: Self::load_adapter(&self.model, &adapter.path)?,
+                path: adapter.path,
+                scale: Some(adapter.scale),
+            }),
+        }
+        Ok(())
+    }
+
+    async fn detach(&self, path: &Path) -> Result<(), LlmError> {
+        let mut adapters = self.adapters.lock().unwrap();
+        match adapters.iter_mut().find(|loaded| loaded.path == path && loaded.scale.is_some()) {
+            Some(loaded) => {
+                loaded.scale = None;
+                Ok(())
+            }
+            None => Err(LlmError::ConfigError(format!("LoRA adapter {} is not attached", path.display()))),
+        }
+    }
+}
+
+#[async_trait]
+impl Tokenizer for LlamaProvider {
+    async fn tokenize(&self, text: &str, add_bos: bool, parse_special: bool) -> Result<Vec<i32>, LlmError> {
+        Ok(self.tokenize_text(text, add_bos, parse_special)?.into_iter().map(|t| t.0).collect())
+    }
+
+    async fn detokenize(&self, tokens: &[i32], special: bool) -> Result<String, LlmError> {
+        let n_vocab = self.model.n_vocab();
+        let special = if special { Special::Tokenize } else { Special::Plaintext };
+        let mut bytes = Vec::new();
+        for &token in tokens {
+            // llama.cpp does not bounds-check token ids.
+            if !(0..n_vocab).contains(&token) {
+                return Err(LlmError::ConfigError(format!(
+                    "Token id {} is outside the vocabulary (0..{})",
+                    token, n_vocab
+                )));
+            }
+            let piece = self.model.token_to_bytes(LlamaToken(token), special)
+                .map_err(|e| LlmError::LlamaCppError(format!("Failed to convert token to bytes: {}", e)))?;
+            bytes.extend_from_slice(&piece);
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    async fn count_chat_tokens(&self, request: &ChatRequest) -> Result<u32, LlmError> {
+        let prompt = Self::build_prompt(&request.messages, &tools::offered(request)?);
+        Ok(self.str_to_tokens(&prompt, AddBos::Always)?.len() as u32)
+    }
+}
@@ -1,21 +1,24 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use thiserror::Error;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
 use std::fs;
-use std::time::Instant;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use log::{debug, info, warn, error};
-use llama_cpp_2::{
-    context::params::LlamaContextParams,
-    llama_backend::LlamaBackend,
-    llama_batch::LlamaBatch,
-    model::params::LlamaModelParams,
-    model::LlamaModel,
-    model::{AddBos, Special},
-    sampling::LlamaSampler,
-
-};
-use encoding_rs::UTF_8;
+
+use crate::grammar;
+use crate::json_schema;
+use crate::reasoning::{self, ReasoningFilter};
+use crate::tools;
+use crate::llama::LlamaProvider;
+use crate::openai::OpenAiProvider;
+use crate::provider::{ChatProvider, LoraAdapters, TokenSink, Tokenizer};
+use crate::worker::WorkerProvider;
 
 #[derive(Debug, Error, Serialize, Deserialize)]
 pub enum LlmError {
@@ -35,6 +38,16 @@ pub enum LlmError {
     LlamaCppError(String),
     #[error("IO error: {0}")]
     IoError(String),
+    #[error("Generation cancelled")]
+    Cancelled,
+    #[error("Inference worker crashed: {0}")]
+    WorkerCrashed(String),
+    #[error("Context overflow: {0}")]
+    ContextOverflow(String),
+    #[error("Reply does not match the response schema: {0}")]
+    SchemaMismatch(String),
+    #[error("MCP error: {0}")]
+    McpError(String),
 }
 
 
@@ -57,6 +70,12 @@ impl From<llama_cpp_2::LLamaCppError> for LlmError {
     }
 }
 
+impl From<reqwest::Error> for LlmError {
+    fn from(error: reqwest::Error) -> Self {
+        LlmError::HttpError(error.to_string())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LlmConfig {
     pub model_name: String,
@@ -67,6 +86,24 @@ pub struct LlmConfig {
     pub ctx_size: u32,
     pub n_threads: Option<i32>,
     pub n_gpu_layers: i32,
+    /// Run inference in a child process so a llama.cpp crash cannot take down the app.
+    /// Only applies to the local provider.
+    #[serde(default)]
+    pub isolate_inference: bool,
+    #[serde(default)]
+    pub provider: ProviderConfig,
+    /// Providers tried in order when `provider` is unavailable or fails a request.
+    #[serde(default)]
+    pub fallback_providers: Vec<ProviderConfig>,
+    #[serde(default)]
+    pub embeddings: EmbeddingConfig,
+    #[serde(default)]
+    pub sampling: SamplingConfig,
+    #[serde(default)]
+    pub speculative: SpeculativeConfig,
+    /// Applied to the local model, in order, on top of its own weights.
+    #[serde(default)]
+    pub lora_adapters: Vec<LoraAdapterConfig>,
 }
 
 impl Default for LlmConfig {
@@ -80,41 +117,567 @@ impl Default for LlmConfig {
             ctx_size: 4096,
             n_threads: None,
             n_gpu_layers: 0,
+            isolate_inference: false,
+            provider: ProviderConfig::Local,
+            fallback_providers: Vec::new(),
+            embeddings: EmbeddingConfig::default(),
+            sampling: SamplingConfig::default(),
+            speculative: SpeculativeConfig::default(),
+            lora_adapters: Vec::new(),
         }
     }
 }
 
+/// How per-token embeddings are pooled into one vector per input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingPooling {
+    #[default]
+    Mean,
+    /// First token, for BERT-style models.
+    Cls,
+    Last,
+    /// Whatever the GGUF metadata declares.
+    Model,
+}
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmbeddingConfig {
+    /// Dedicated embedding model from the models directory; the chat model is
+    /// used when unset.
+    pub model: Option<String>,
+    pub pooling: EmbeddingPooling,
+    /// Scale vectors to unit length.
+    pub normalize: bool,
+}
 
-// Our own ChatMessage struct for API compatibility
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            model: None,
+            pooling: EmbeddingPooling::Mean,
+            normalize: true,
+        }
+    }
+}
+
+/// Speculative decoding: a small draft model proposes tokens that the main
+/// model checks in a single batch, keeping those it would have picked itself.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeculativeConfig {
+    /// Draft model from the models directory, sharing the main model's
+    /// vocabulary, such as a 0.5B variant of a 3B model. Off when unset.
+    pub draft_model: Option<String>,
+    /// Most tokens drafted per verification pass.
+    pub draft_tokens: u32,
+}
+
+impl Default for SpeculativeConfig {
+    fn default() -> Self {
+        Self {
+            draft_model: None,
+            draft_tokens: 8,
+        }
+    }
+}
+
+/// A LoRA adapter applied to the local model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoraAdapterConfig {
+    /// GGUF adapter file, by its name in the models directory.
+    pub path: PathBuf,
+    /// How strongly the adapter applies; 1.0 as trained.
+    #[serde(default = "LoraAdapterConfig::default_scale")]
+    pub scale: f32,
+}
+
+impl LoraAdapterConfig {
+    fn default_scale() -> f32 {
+        1.0
+    }
+}
+
+/// Samplers applied by the local model on top of temperature and top_p,
+/// named as llama-server and Ollama name them. All are off by default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplingConfig {
+    /// Divides the logits of recent tokens; 1.0 is off.
+    pub repeat_penalty: f32,
+    /// How many recent tokens the penalties look at; -1 for the whole context.
+    pub repeat_last_n: i32,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    /// Strength of the DRY penalty on repeated sequences; 0.0 is off.
+    pub dry_multiplier: f32,
+    pub dry_base: f32,
+    /// Longest repeat that goes unpenalized.
+    pub dry_allowed_length: i32,
+    /// How many recent tokens DRY looks at; -1 for the whole context.
+    pub dry_penalty_last_n: i32,
+    /// Text that ends a sequence DRY is matching.
+    pub dry_sequence_breakers: Vec<String>,
+    /// Mirostat version to sample with, 1 or 2; 0 is off.
+    pub mirostat: u8,
+    pub mirostat_tau: f32,
+    pub mirostat_eta: f32,
+    /// Added to the logits of tokens, keyed by token id or by text, whose
+    /// tokens are all biased.
+    pub logit_bias: HashMap<String, f32>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            repeat_penalty: 1.0,
+            repeat_last_n: 64,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            dry_multiplier: 0.0,
+            dry_base: 1.75,
+            dry_allowed_length: 2,
+            dry_penalty_last_n: -1,
+            dry_sequence_breakers: ["\n", ":", "\"", "*"].map(String::from).to_vec(),
+            mirostat: 0,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+            logit_bias: HashMap::new(),
+        }
+    }
+}
+
+impl SamplingConfig {
+    pub fn validate(&self) -> Result<(), LlmError> {
+        if self.mirostat > 2 {
+            return Err(LlmError::ConfigError(format!("mirostat must be 0, 1 or 2, not {}", self.mirostat)));
+        }
+        if self.repeat_last_n < -1 || self.dry_penalty_last_n < -1 {
+            return Err(LlmError::ConfigError("repeat_last_n and dry_penalty_last_n must be -1 or more".to_string()));
+        }
+        if self.repeat_penalty <= 0.0 {
+            return Err(LlmError::ConfigError("repeat_penalty must be above 0".to_string()));
+        }
+        // llama.cpp takes the breakers as C strings.
+        if self.dry_sequence_breakers.iter().any(|breaker| breaker.contains('\0')) {
+            return Err(LlmError::ConfigError("dry_sequence_breakers must not contain NUL characters".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Per-request overrides of `SamplingConfig`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SamplingOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_last_n: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_multiplier: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_base: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_allowed_length: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_penalty_last_n: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_sequence_breakers: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirostat: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirostat_tau: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirostat_eta: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<String, f32>>,
+}
+
+impl SamplingOptions {
+    /// `defaults` with these options applied.
+    pub fn over(&self, defaults: &SamplingConfig) -> SamplingConfig {
+        SamplingConfig {
+            repeat_penalty: self.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.unwrap_or(defaults.repeat_last_n),
+            frequency_penalty: self.frequency_penalty.unwrap_or(defaults.frequency_penalty),
+            presence_penalty: self.presence_penalty.unwrap_or(defaults.presence_penalty),
+            dry_multiplier: self.dry_multiplier.unwrap_or(defaults.dry_multiplier),
+            dry_base: self.dry_base.unwrap_or(defaults.dry_base),
+            dry_allowed_length: self.dry_allowed_length.unwrap_or(defaults.dry_allowed_length),
+            dry_penalty_last_n: self.dry_penalty_last_n.unwrap_or(defaults.dry_penalty_last_n),
+            dry_sequence_breakers: self.dry_sequence_breakers.clone().unwrap_or_else(|| defaults.dry_sequence_breakers.clone()),
+            mirostat: self.mirostat.unwrap_or(defaults.mirostat),
+            mirostat_tau: self.mirostat_tau.unwrap_or(defaults.mirostat_tau),
+            mirostat_eta: self.mirostat_eta.unwrap_or(defaults.mirostat_eta),
+            logit_bias: self.logit_bias.clone().unwrap_or_else(|| defaults.logit_bias.clone()),
+        }
+    }
+}
+
+/// Where chat completions are served from.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderConfig {
+    /// llama.cpp running inside the app on a model from the models directory.
+    #[default]
+    Local,
+    /// A server speaking the OpenAI chat completions API (Ollama, llama-server, vLLM, ...).
+    #[serde(rename = "openai")]
+    OpenAi {
+        host: String,
+        port: u16,
+        #[serde(default)]
+        api_key: Option<String>,
+        /// Model to request from the server; defaults to the model named in each request.
+        #[serde(default)]
+        model: Option<String>,
+    },
+}
+
+impl ProviderConfig {
+    pub fn base_url(&self) -> String {
+        match self {
+            ProviderConfig::Local => "local".to_string(),
+            ProviderConfig::OpenAi { host, port, .. } => {
+                format!("{}:{}", host.trim_end_matches('/'), port)
+            }
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            ProviderConfig::Local => 0, // Not applicable for local models
+            ProviderConfig::OpenAi { port, .. } => *port,
+        }
+    }
+}
+
+
+
+// Our own ChatMessage struct for API compatibility
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub role: String,
+    /// OpenAI clients send `null` content for assistant turns that only call tools.
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub content: String,
+    /// Tools the assistant asked to call in this turn.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// For `tool` messages, the call this is the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// What a thinking model reasoned before its reply, taken out of `content`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+}
+
+fn null_as_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Option::<String>::deserialize(deserializer).map(Option::unwrap_or_default)
+}
+
+/// A function the model may call, as in the OpenAI chat API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON schema of the arguments object.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// Whether the model may, must or must not call tools.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    /// Call this particular function.
+    Function {
+        #[serde(rename = "type", default = "function_type")]
+        kind: String,
+        function: ToolChoiceFunction,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    None,
+    Auto,
+    Required,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolChoiceFunction {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    #[serde(default)]
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// Arguments as a JSON string. Ollama sends an object, which is accepted too.
+    #[serde(deserialize_with = "json_string")]
+    pub arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+fn json_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(text) => text,
+        value => value.to_string(),
+    })
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ChatRequest {
     pub model: String,
     pub messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>,
+    /// GBNF grammar the reply must match, starting from its `root` rule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grammar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+    /// Most tokens a thinking model may reason for before it has to answer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking_budget: Option<u32>,
+    /// Leave the reasoning of earlier assistant turns out of the prompt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_reasoning: Option<bool>,
+    /// Replies to return, all generated from the same prompt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    /// Replies to generate, of which the `n` most likely are returned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<u32>,
+    /// Return the log-probability of each generated token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    /// Also return this many of the likeliest tokens at each position.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
+    /// Penalties, DRY, Mirostat and logit bias for this request.
+    #[serde(flatten)]
+    pub sampling: SamplingOptions,
+    /// Stops this request alone, e.g. once its stream has no reader.
+    #[serde(skip)]
+    pub cancel: CancelToken,
+}
+
+impl ChatRequest {
+    /// `messages` as providers are prompted with them, see `reasoning::history`.
+    pub fn prompt_messages(&self) -> Vec<ChatMessage> {
+        reasoning::history(&self.messages, self.strip_reasoning.unwrap_or(false))
+    }
+
+    /// How many replies to return and how many to generate: `n`, which
+    /// defaults to one, and `best_of`, which defaults to `n`.
+    pub fn choice_counts(&self) -> Result<(usize, usize), LlmError> {
+        let n = self.n.unwrap_or(1);
+        let best_of = self.best_of.unwrap_or(n);
+        if n == 0 {
+            return Err(LlmError::ConfigError("n must be at least 1".to_string()));
+        }
+        if best_of < n {
+            return Err(LlmError::ConfigError(format!("best_of ({}) must be at least n ({})", best_of, n)));
+        }
+        if best_of > MAX_CHOICES {
+            return Err(LlmError::ConfigError(format!("At most {} replies can be generated at once", MAX_CHOICES)));
+        }
+        Ok((n as usize, best_of as usize))
+    }
+
+    /// How many alternatives to report with each token's log-probability, or
+    /// `None` when `logprobs` is not requested.
+    pub fn logprob_count(&self) -> Result<Option<usize>, LlmError> {
+        let top = self.top_logprobs.unwrap_or(0);
+        if top > MAX_TOP_LOGPROBS {
+            return Err(LlmError::ConfigError(format!("top_logprobs must be at most {}", MAX_TOP_LOGPROBS)));
+        }
+        match self.logprobs.unwrap_or(false) {
+            true => Ok(Some(top as usize)),
+            false if self.top_logprobs.is_some() => Err(LlmError::ConfigError("top_logprobs needs logprobs".to_string())),
+            false => Ok(None),
+        }
+    }
+
+    /// The partial assistant reply to continue, when the conversation ends with one.
+    pub fn prefill(&self) -> Option<&ChatMessage> {
+        self.messages.last().filter(|message| message.role == "assistant")
+    }
+
+    /// JSON schema the reply must satisfy, if `response_format` asks for JSON.
+    pub fn response_schema(&self) -> Result<Option<serde_json::Value>, LlmError> {
+        match &self.response_format {
+            Some(format) => format.schema(),
+            None => Ok(None),
+        }
+    }
+
+    /// Grammar to constrain generation with: `grammar` itself, one built
+    /// from the `response_format` schema, or one forcing a tool call when
+    /// `tool_choice` requires it.
+    pub fn effective_grammar(&self) -> Result<Option<String>, LlmError> {
+        match (&self.grammar, self.response_schema()?, tools::required_grammar(self)?) {
+            (Some(_), Some(_), _) => Err(LlmError::ConfigError(
+                "grammar cannot be combined with a JSON response_format".to_string(),
+            )),
+            (Some(_), None, Some(_)) | (None, Some(_), Some(_)) => Err(LlmError::ConfigError(
+                "A tool_choice that requires a tool call cannot be combined with grammar or response_format".to_string(),
+            )),
+            (Some(grammar), None, None) => Ok(Some(grammar.clone())),
+            (None, Some(schema), None) => json_schema::to_grammar(&schema).map(Some),
+            (None, None, tool_grammar) => Ok(tool_grammar),
+        }
+    }
+}
+
+/// Requested shape of the reply, as in the OpenAI chat API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    /// Any JSON object.
+    JsonObject,
+    JsonSchema {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        json_schema: Option<JsonSchemaFormat>,
+        /// Shorthand for `json_schema.schema`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schema: Option<serde_json::Value>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonSchemaFormat {
+    #[serde(default = "JsonSchemaFormat::default_name")]
+    pub name: String,
+    pub schema: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+impl JsonSchemaFormat {
+    fn default_name() -> String {
+        "response".to_string()
+    }
+}
+
+impl ResponseFormat {
+    pub fn schema(&self) -> Result<Option<serde_json::Value>, LlmError> {
+        match self {
+            ResponseFormat::Text => Ok(None),
+            ResponseFormat::JsonObject => Ok(Some(serde_json::json!({ "type": "object" }))),
+            ResponseFormat::JsonSchema { json_schema, schema } => schema
+                .clone()
+                .or_else(|| json_schema.as_ref().map(|format| format.schema.clone()))
+                .map(Some)
+                .ok_or_else(|| LlmError::ConfigError("json_schema response_format needs a schema".to_string())),
+        }
+    }
+
+    /// OpenAI's form of the format, with the `schema` shorthand moved into `json_schema`.
+    pub fn into_openai(self) -> Self {
+        match self {
+            ResponseFormat::JsonSchema { json_schema, schema: Some(schema) } => ResponseFormat::JsonSchema {
+                json_schema: Some(JsonSchemaFormat {
+                    schema,
+                    ..json_schema.unwrap_or(JsonSchemaFormat {
+                        name: JsonSchemaFormat::default_name(),
+                        schema: serde_json::Value::Null,
+                        strict: None,
+                    })
+                }),
+                schema: None,
+            },
+            format => format,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatChoice {
     pub index: u32,
     pub message: ChatMessage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<ChoiceLogprobs>,
     pub finish_reason: Option<String>,
 }
 
+/// Log-probabilities of every generated token of a choice, reasoning included.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChoiceLogprobs {
+    pub content: Vec<TokenLogprob>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    /// UTF-8 bytes of the token, which may be part of a character.
+    pub bytes: Vec<u8>,
+    /// The likeliest tokens at this position, most likely first.
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
+    pub bytes: Vec<u8>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatUsage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    /// Drafted tokens the model accepted and rejected, when a draft model is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionTokensDetails {
+    pub accepted_prediction_tokens: u32,
+    pub rejected_prediction_tokens: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -125,6 +688,114 @@ pub struct ChatResponse {
     pub model: String,
     pub choices: Vec<ChatChoice>,
     pub usage: Option<ChatUsage>,
+    /// Name of the provider that produced this response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+}
+
+/// Raw text completion, as in the OpenAI `/v1/completions` API: the prompt is
+/// passed to the model as is, without a chat template.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub prompt: String,
+    /// Text that follows the completion, for inserting into existing text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    /// Penalties, DRY, Mirostat and logit bias for this request.
+    #[serde(flatten)]
+    pub sampling: SamplingOptions,
+    /// Stops this request alone, e.g. once its stream has no reader.
+    #[serde(skip)]
+    pub cancel: CancelToken,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionChoice {
+    pub index: u32,
+    pub text: String,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<CompletionChoice>,
+    pub usage: Option<ChatUsage>,
+    /// Name of the provider that produced this response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+}
+
+/// Text to embed: a single string or a batch, as in the OpenAI embeddings API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+impl EmbeddingInput {
+    pub fn into_texts(self) -> Vec<String> {
+        match self {
+            EmbeddingInput::Single(text) => vec![text],
+            EmbeddingInput::Batch(texts) => texts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingsRequest {
+    pub model: String,
+    pub input: EmbeddingInput,
+    /// Overrides `EmbeddingConfig::pooling`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pooling: Option<EmbeddingPooling>,
+    /// Overrides `EmbeddingConfig::normalize`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalize: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Embedding {
+    pub object: String,
+    pub index: u32,
+    pub embedding: Vec<f32>,
+    /// Tokens in this input; not reported by every provider.
+    #[serde(default)]
+    pub tokens: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingsUsage {
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingsResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingsUsage,
+}
+
+/// Size of a templated chat prompt against the context window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenCount {
+    pub prompt_tokens: u32,
+    pub context_size: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -149,33 +820,88 @@ pub struct LlmServiceStatus {
     pub base_url: String,
 }
 
+/// Shared flag that lets in-flight generations be aborted without taking the
+/// service's write lock, which waits for every running `chat_completion`.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Resolve once the token is cancelled, for use in `tokio::select!`.
+    pub async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            tokio::time::sleep(CANCEL_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// How often `CancelToken::cancelled` re-checks the flag.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Most replies one chat request may generate, through `n` or `best_of`.
+pub const MAX_CHOICES: u32 = 16;
+
+/// Most alternatives `top_logprobs` may ask for, as with OpenAI.
+pub const MAX_TOP_LOGPROBS: u32 = 20;
+
+/// One provider's attempt at a request, as made by `LlmService::with_fallback`.
+type ProviderCall<'p, T> = Pin<Box<dyn Future<Output = Result<T, LlmError>> + Send + 'p>>;
+
 pub struct LlmService {
     config: LlmConfig,
-    backend: Option<LlamaBackend>,
-    model: Option<LlamaModel>,
-    is_initialized: bool,
+    /// Started providers in fallback order.
+    providers: Vec<Box<dyn ChatProvider>>,
+    /// Positions in the fallback chain of providers that failed to start; the
+    /// next `start` tries them again.
+    failed: Vec<usize>,
+    cancel_token: CancelToken,
 }
 
 impl LlmService {
     pub fn new(config: LlmConfig) -> Self {
         Self {
             config,
-            backend: None,
-            model: None,
-            is_initialized: false,
+            providers: Vec::new(),
+            failed: Vec::new(),
+            cancel_token: CancelToken::default(),
         }
     }
 
+    /// Use an externally owned cancel token so it survives re-initialization.
+    pub fn with_cancel_token(mut self, cancel_token: CancelToken) -> Self {
+        self.cancel_token = cancel_token;
+        self
+    }
+
+    /// Run with already constructed providers instead of starting them from config.
+    #[cfg(test)]
+    pub(crate) fn with_providers(mut self, providers: Vec<Box<dyn ChatProvider>>) -> Self {
+        self.providers = providers;
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn cancel_token(&self) -> CancelToken {
+        self.cancel_token.clone()
+    }
+
     pub fn is_running(&self) -> bool {
-        self.is_initialized && self.backend.is_some() && self.model.is_some()
+        !self.providers.is_empty()
     }
 
     pub fn get_status(&self) -> LlmServiceStatus {
         LlmServiceStatus {
             is_running: self.is_running(),
-            port: 0, // Not applicable for local models
+            port: self.config.provider.port(),
             model_name: self.config.model_name.clone(),
-            base_url: "local".to_string(),
+            base_url: self.config.provider.base_url(),
         }
     }
 
@@ -200,19 +926,9 @@ impl LlmService {
             )));
         }
 
-        // Try different possible filenames for the model
-        let possible_names = vec![
-            format!("{}.gguf", model_name),
-            format!("{}.bin", model_name),
-            model_name.to_string(),
-        ];
-
-        for name in possible_names {
-            let path = models_dir.join(&name);
-            if path.exists() {
-                log::info!("Found model file: {}", path.display());
-                return Ok(path);
-            }
+        if let Some(path) = Self::model_file_in(&models_dir, model_name) {
+            log::info!("Found model file: {}", path.display());
+            return Ok(path);
         }
 
         // If not found, list available models for better error message
@@ -231,6 +947,37 @@ impl LlmService {
         )))
     }
 
+    /// The file of `model_name` in `models_dir`. Names can come from API
+    /// clients, so only a plain file name is looked up: one with a path
+    /// separator, `..` or a root could reach outside the directory.
+    fn model_file_in(models_dir: &Path, model_name: &str) -> Option<PathBuf> {
+        let is_file_name = !model_name.contains(['/', '\\'])
+            && matches!(Path::new(model_name).components().collect::<Vec<_>>()[..], [Component::Normal(_)]);
+        if !is_file_name {
+            warn!("⚠️ Refusing to look up '{}' outside the models directory", model_name);
+            return None;
+        }
+
+        // Try different possible filenames for the model
+        let possible_names = [
+            format!("{}.gguf", model_name),
+            format!("{}.bin", model_name),
+            model_name.to_string(),
+        ];
+
+        possible_names
+            .iter()
+            .map(|name| models_dir.join(name))
+            .find(|path| path.exists())
+    }
+
+    /// Location and file metadata of a model in the models directory.
+    pub fn model_file_metadata(&self, model_name: &str) -> Option<(PathBuf, fs::Metadata)> {
+        let path = Self::model_file_in(&Self::get_models_directory(), model_name)?;
+        let metadata = fs::metadata(&path).ok()?;
+        Some((path, metadata))
+    }
+
     fn scan_available_models(&self) -> Vec<ModelInfo> {
         let models_dir = Self::get_models_directory();
         let mut models = Vec::new();
@@ -294,71 +1041,134 @@ impl LlmService {
     }
 
     pub async fn start(&mut self) -> Result<String, LlmError> {
-        if self.is_running() {
+        let chain: Vec<ProviderConfig> = std::iter::once(self.config.provider.clone())
+            .chain(self.config.fallback_providers.iter().cloned())
+            .collect();
+        if !self.is_running() {
+            self.failed = (0..chain.len()).collect();
+        } else if self.failed.is_empty() {
             let msg = "LLM service already initialized".to_string();
             info!("⚠️ {}", msg);
             return Ok(msg);
-        }
-
-        info!("🚀 Initializing LLM service with model: {}", self.config.model_name);
-        debug!("🔧 Configuration: ctx_size={}, n_gpu_layers={}, n_threads={:?}",
-            self.config.ctx_size, self.config.n_gpu_layers, self.config.n_threads);
-
-        // Initialize the backend
-        info!("🔧 Initializing LLaMA backend...");
-        let backend_start = Instant::now();
-        let backend = LlamaBackend::init()
-            .map_err(|e| {
-                error!("❌ Failed to initialize backend: {}", e);
-                LlmError::LlamaCppError(format!("Failed to initialize backend: {}", e))
-            })?;
-        info!("✅ Backend initialized in {:?}", backend_start.elapsed());
-
-        // Find the model file
-        info!("🔍 Searching for model file: {}", self.config.model_name);
-        let model_path = self.find_model_file(&self.config.model_name)?;
-        info!("📁 Model file found: {}", model_path.display());
-
-        // Set up model parameters
-        info!("⚙️ Setting up model parameters...");
-        let mut model_params = LlamaModelParams::default();
-        if self.config.n_gpu_layers > 0 {
-            info!("🎮 Configuring {} GPU layers", self.config.n_gpu_layers);
-            model_params = model_params.with_n_gpu_layers(self.config.n_gpu_layers as u32);
         } else {
-            info!("💻 Using CPU-only inference");
+            info!("🔄 Retrying {} provider(s) that failed to start", self.failed.len());
         }
 
-        // Load the model
-        info!("📚 Loading model from file...");
-        let model_start = Instant::now();
-        let model = LlamaModel::load_from_file(&backend, model_path, &model_params)
-            .map_err(|e| {
-                error!("❌ Failed to load model: {}", e);
-                LlmError::LlamaCppError(format!("Failed to load model: {}", e))
-            })?;
-        let model_duration = model_start.elapsed();
-        info!("✅ Model loaded successfully in {:?}", model_duration);
+        // Start the primary provider and every fallback. One that fails to
+        // start (e.g. a local model that cannot be loaded) is skipped so the
+        // rest of the chain can still answer.
+        let mut first_error = None;
+        for position in self.failed.clone() {
+            match self.start_provider(&chain[position]).await {
+                Ok(provider) => {
+                    self.failed.retain(|&failed| failed != position);
+                    let index = position - self.failed.iter().filter(|&&failed| failed < position).count();
+                    self.providers.insert(index, provider);
+                }
+                Err(e) => {
+                    warn!("⚠️ Failed to start provider {}: {}", chain[position].base_url(), e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
 
-        // Store everything
-        self.backend = Some(backend);
-        self.model = Some(model);
-        self.is_initialized = true;
+        if self.providers.is_empty() {
+            return Err(first_error.unwrap_or(LlmError::NotRunning));
+        }
 
+        let names: Vec<&str> = self.providers.iter().map(|p| p.name()).collect();
         let success_msg = format!(
-            "LLM service initialized successfully with model: {} (context size: {})",
-            self.config.model_name, self.config.ctx_size
+            "LLM service initialized successfully with model: {} (providers: {}, context size: {})",
+            self.config.model_name, names.join(" → "), self.config.ctx_size
         );
         info!("🎉 {}", success_msg);
         Ok(success_msg)
     }
 
+    /// Whether to pass over the provider at `index` because it is busy with
+    /// another request and a later provider can take this one.
+    fn skip_busy(&self, index: usize) -> bool {
+        let provider = &self.providers[index];
+        let skip = index + 1 < self.providers.len() && provider.is_busy();
+        if skip {
+            info!("⏳ {} provider is busy, trying the next one", provider.name());
+        }
+        skip
+    }
+
+    /// Config for the local model, with LoRA adapter paths resolved.
+    fn local_config(&self) -> Result<LlmConfig, LlmError> {
+        let mut config = self.config.clone();
+        for adapter in &mut config.lora_adapters {
+            adapter.path = Self::lora_adapter_path(&adapter.path)?;
+        }
+        Ok(config)
+    }
+
+    fn lora_adapter_path(path: &Path) -> Result<PathBuf, LlmError> {
+        Self::model_file_in(&Self::get_models_directory(), &path.to_string_lossy())
+            .ok_or_else(|| LlmError::ModelError(format!("LoRA adapter '{}' not found", path.display())))
+    }
+
+    async fn start_provider(&self, provider_config: &ProviderConfig) -> Result<Box<dyn ChatProvider>, LlmError> {
+        let provider: Box<dyn ChatProvider> = match provider_config {
+            ProviderConfig::Local if self.config.isolate_inference => {
+                info!("🚀 Initializing LLM service in an isolated worker with model: {}", self.config.model_name);
+                Box::new(WorkerProvider::start(self.local_config()?, self.cancel_token.clone()).await?)
+            }
+            ProviderConfig::Local => {
+                info!("🚀 Initializing LLM service with model: {}", self.config.model_name);
+                debug!("🔧 Configuration: ctx_size={}, n_gpu_layers={}, n_threads={:?}",
+                    self.config.ctx_size, self.config.n_gpu_layers, self.config.n_threads);
+
+                // Find the model file
+                info!("🔍 Searching for model file: {}", self.config.model_name);
+                let model_path = self.find_model_file(&self.config.model_name)?;
+                info!("📁 Model file found: {}", model_path.display());
+
+                let embedding_model_path = match &self.config.embeddings.model {
+                    Some(name) => {
+                        let path = Self::model_file_in(&Self::get_models_directory(), name).ok_or_else(|| {
+                            LlmError::ModelError(format!("Embedding model '{}' not found", name))
+                        })?;
+                        info!("📁 Embedding model file found: {}", path.display());
+                        Some(path)
+                    }
+                    None => None,
+                };
+                let draft_model_path = match &self.config.speculative.draft_model {
+                    Some(name) => {
+                        let path = Self::model_file_in(&Self::get_models_directory(), name).ok_or_else(|| {
+                            LlmError::ModelError(format!("Draft model '{}' not found", name))
+                        })?;
+                        info!("📁 Draft model file found: {}", path.display());
+                        Some(path)
+                    }
+                    None => None,
+                };
+
+                Box::new(LlamaProvider::load(
+                    self.local_config()?,
+                    model_path,
+                    embedding_model_path,
+                    draft_model_path,
+                    self.cancel_token.clone(),
+                )?)
+            }
+            ProviderConfig::OpenAi { api_key, model, .. } => {
+                let base_url = provider_config.base_url();
+                info!("🚀 Initializing LLM service against OpenAI-compatible server at {}", base_url);
+                Box::new(OpenAiProvider::new(base_url, api_key.clone(), model.clone(), self.cancel_token.clone())?)
+            }
+        };
+        Ok(provider)
+    }
+
     pub async fn stop(&mut self) -> Result<String, LlmError> {
-        if self.is_initialized {
+        if self.is_running() {
+            self.providers.clear();
+            self.failed.clear();
             info!("🛑 Stopping LLM service...");
-            self.backend = None;
-            self.model = None;
-            self.is_initialized = false;
             let msg = "LLM service stopped successfully".to_string();
             info!("✅ {}", msg);
             Ok(msg)
@@ -369,11 +1179,27 @@ impl LlmService {
         }
     }
 
-
+    /// Release the provider (and with it any loaded model) as part of
+    /// application shutdown. In-flight generations should be cancelled through
+    /// the service's `CancelToken` first, otherwise the caller cannot acquire the lock.
+    pub async fn shutdown(&mut self) {
+        if self.is_running() {
+            let _ = self.stop().await;
+        }
+    }
 
     pub async fn chat_completion(&self, request: ChatRequest) -> Result<ChatResponse, LlmError> {
-        let start_time = Instant::now();
+        self.chat_completion_stream(request, &|_| {}, &|_| {}).await
+    }
 
+    /// Run a chat completion, passing each generated piece of the reply to
+    /// `on_token` and of a thinking model's reasoning to `on_reasoning`.
+    pub async fn chat_completion_stream(
+        &self,
+        mut request: ChatRequest,
+        on_token: TokenSink<'_>,
+        on_reasoning: TokenSink<'_>,
+    ) -> Result<ChatResponse, LlmError> {
         // Log incoming chat request
         info!("🚀 Chat completion request received");
         debug!("📋 Request details: model={}, message_count={}, temperature={:?}, top_p={:?}, max_tokens={:?}",
@@ -394,222 +1220,232 @@ impl LlmService {
             debug!("📝 Message {} content: '{}'", i + 1, message.content);
         }
 
-        let backend = self.backend.as_ref().ok_or_else(|| {
-            error!("❌ Backend not initialized");
-            LlmError::NotRunning
-        })?;
+        if !self.is_running() {
+            error!("❌ No provider running");
+            return Err(LlmError::NotRunning);
+        }
 
-        let model = self.model.as_ref().ok_or_else(|| {
-            error!("❌ Model not loaded");
-            LlmError::NotRunning
-        })?;
+        // Check constraints up front, so a bad grammar or schema is reported
+        // instead of each provider failing on it.
+        let schema = request.response_schema()?;
+        request.choice_counts()?;
+        request.logprob_count()?;
+        request.sampling.over(&self.config.sampling).validate()?;
+        if let Some(grammar) = request.effective_grammar()? {
+            grammar::validate(&grammar)?;
+            debug!("📐 Constraining reply to a {} character grammar", grammar.len());
+        }
 
-        info!("✅ Backend and model are ready for processing");
+        request.messages = request.prompt_messages();
+        let prefill = request.prefill().map(|message| message.content.clone()).unwrap_or_default();
+        if !prefill.is_empty() {
+            info!("↪️ Continuing a partial assistant reply of {} chars", prefill.len());
+        }
 
-        // Build the prompt from messages
-        info!("🔨 Building prompt from {} messages", request.messages.len());
-        let mut prompt = String::new();
-        for (i, message) in request.messages.iter().enumerate() {
-            let formatted_message = match message.role.as_str() {
-                "system" => format!("<|im_start|>system\n{}<|im_end|>\n", message.content),
-                "user" => format!("<|im_start|>user\n{}<|im_end|>\n", message.content),
-                "assistant" => format!("<|im_start|>assistant\n{}<|im_end|>\n", message.content),
-                _ => {
-                    warn!("⚠️ Unknown message role '{}', treating as user", message.role);
-                    format!("<|im_start|>user\n{}<|im_end|>\n", message.content)
-                }
-            };
-            debug!("🔧 Formatted message {}: {} chars", i + 1, formatted_message.len());
-            prompt.push_str(&formatted_message);
-        }
-        prompt.push_str("<|im_start|>assistant\n");
-
-        info!("📄 Final prompt built: {} total characters", prompt.len());
-        debug!("📋 Complete prompt: '{}'", prompt);
-
-        // Tokenize the prompt
-        info!("🔤 Tokenizing prompt...");
-        let tokenize_start = Instant::now();
-        let tokens_list = model
-            .str_to_token(&prompt, AddBos::Always)
-            .map_err(|e| {
-                error!("❌ Failed to tokenize prompt: {}", e);
-                LlmError::LlamaCppError(format!("Failed to tokenize prompt: {}", e))
-            })?;
-
-        let tokenize_duration = tokenize_start.elapsed();
-        let max_tokens = request.max_tokens.unwrap_or(self.config.max_tokens);
-        let prompt_tokens_len = tokens_list.len();
-        let n_len = prompt_tokens_len as i32 + max_tokens;
-
-        info!("✅ Tokenization complete: {} prompt tokens in {:?}", prompt_tokens_len, tokenize_duration);
-        debug!("🎯 Generation parameters: max_tokens={}, total_limit={}", max_tokens, n_len);
-
-        // Create a batch for processing
-        info!("📦 Creating token batch for processing...");
-        let mut batch = LlamaBatch::new(512, 1);
-        let last_index = prompt_tokens_len as i32 - 1;
-
-        for (i, token) in (0_i32..).zip(tokens_list.into_iter()) {
-            let is_last = i == last_index;
-            batch.add(token, i, &[0], is_last)
-                .map_err(|e| {
-                    error!("❌ Failed to add token {} to batch: {}", i, e);
-                    LlmError::LlamaCppError(format!("Failed to add token to batch: {}", e))
-                })?;
-        }
-
-        debug!("✅ Batch created with {} tokens", batch.n_tokens());
-
-        // Create a context for this request
-        info!("🧠 Creating context for inference...");
-        let context_start = Instant::now();
-        let mut ctx_params = LlamaContextParams::default()
-            .with_n_ctx(Some(std::num::NonZeroU32::new(self.config.ctx_size).unwrap()));
-
-        if let Some(threads) = self.config.n_threads {
-            debug!("🔧 Using {} threads for processing", threads);
-            ctx_params = ctx_params.with_n_threads(threads);
-        } else {
-            debug!("🔧 Using default thread count");
-        }
-
-        let mut context = model
-            .new_context(backend, ctx_params)
-            .map_err(|e| {
-                error!("❌ Failed to create context: {}", e);
-                LlmError::LlamaCppError(format!("Failed to create context: {}", e))
-            })?;
-
-        let context_duration = context_start.elapsed();
-        info!("✅ Context created successfully in {:?}", context_duration);
-
-        // Process the prompt
-        info!("⚡ Processing prompt through model...");
-        let decode_start = Instant::now();
-        context.decode(&mut batch)
-            .map_err(|e| {
-                error!("❌ Failed to decode prompt: {}", e);
-                LlmError::LlamaCppError(format!("Failed to decode prompt: {}", e))
-            })?;
-
-        let decode_duration = decode_start.elapsed();
-        info!("✅ Prompt processed in {:?}", decode_duration);
-
-        let mut n_cur = batch.n_tokens();
-        let initial_tokens = n_cur;
-        let mut response_content = String::new();
-        let mut decoder = UTF_8.new_decoder();
-        let mut sampler = LlamaSampler::greedy();
-        let generation_start = Instant::now();
-
-        info!("🎯 Starting token generation (max {} tokens)...", max_tokens);
-        debug!("📊 Initial state: n_cur={}, initial_tokens={}, target_length={}", n_cur, initial_tokens, n_len);
-
-        // Generate response tokens
-        let mut tokens_generated = 0;
-        while n_cur <= n_len {
-            let token = sampler.sample(&context, batch.n_tokens() - 1);
-            sampler.accept(token);
-
-            // Check for end of generation
-            if model.is_eog_token(token) {
-                info!("🏁 End of generation token encountered at position {}", n_cur);
-                break;
+        let filter = std::sync::Mutex::new(ReasoningFilter::after(&prefill));
+        let relay = |(reasoning, content): (String, String)| {
+            if !reasoning.is_empty() {
+                on_reasoning(&reasoning);
+            }
+            if !content.is_empty() {
+                on_token(&content);
+            }
+        };
+        let sink = |token: &str| relay(filter.lock().unwrap().push(token));
+        let mut response = self.dispatch_chat(request, &sink).await?;
+        relay(filter.into_inner().unwrap().finish());
+
+        for choice in &mut response.choices {
+            let message = &mut choice.message;
+            if message.reasoning_content.is_none() {
+                let (reasoning, content) = reasoning::split(&prefill, &message.content);
+                message.reasoning_content = reasoning;
+                message.content = content;
             }
+        }
 
-            // Convert token to text
-            let output_bytes = model.token_to_bytes(token, Special::Tokenize)
-                .map_err(|e| {
-                    error!("❌ Failed to convert token {} to bytes: {}", token, e);
-                    LlmError::LlamaCppError(format!("Failed to convert token to bytes: {}", e))
-                })?;
-
-            let mut output_string = String::with_capacity(32);
-            let _decode_result = decoder.decode_to_string(&output_bytes, &mut output_string, false);
-            response_content.push_str(&output_string);
-
-            tokens_generated += 1;
-
-            // Log progress every 10 tokens or for first few tokens
-            if tokens_generated <= 5 || tokens_generated % 10 == 0 {
-                debug!("🔄 Token {}: '{}' (total response length: {} chars)",
-                    tokens_generated,
-                    output_string.replace('\n', "\\n"),
-                    response_content.len()
-                );
+        // A tool call is not the final reply, so there is nothing to check yet.
+        if let Some(schema) = schema.as_ref() {
+            for choice in response.choices.iter().filter(|c| c.message.tool_calls.is_none()) {
+                let value: serde_json::Value = serde_json::from_str(choice.message.content.trim())
+                    .map_err(|e| LlmError::SchemaMismatch(format!("reply is not JSON: {}", e)))?;
+                json_schema::validate(&value, schema).map_err(LlmError::SchemaMismatch)?;
             }
+            debug!("✅ Reply matches the response schema");
+        }
+        Ok(response)
+    }
 
-            // Prepare for next iteration
-            batch.clear();
-            batch.add(token, n_cur, &[0], true)
-                .map_err(|e| {
-                    error!("❌ Failed to add token {} to batch at position {}: {}", token, n_cur, e);
-                    LlmError::LlamaCppError(format!("Failed to add token to batch: {}", e))
-                })?;
+    /// Try each provider in order with `call`; anything but a cancellation
+    /// falls through to the next one, unless part of the reply has already
+    /// been streamed to `on_token`. A provider busy with another request is
+    /// passed over for a later one.
+    async fn with_fallback<T>(
+        &self,
+        what: &str,
+        on_token: TokenSink<'_>,
+        call: impl for<'p> Fn(&'p dyn ChatProvider, TokenSink<'p>) -> ProviderCall<'p, T> + Send + Sync,
+    ) -> Result<T, LlmError> {
+        let streamed = AtomicBool::new(false);
+        let sink = |token: &str| {
+            streamed.store(true, Ordering::SeqCst);
+            on_token(token);
+        };
+        let mut last_error = None;
+        for (index, provider) in self.providers.iter().enumerate() {
+            if self.skip_busy(index) {
+                continue;
+            }
+            info!("📨 Dispatching {} to {} provider", what, provider.name());
+            match call(provider.as_ref(), &sink).await {
+                Ok(response) => return Ok(response),
+                Err(LlmError::Cancelled) => return Err(LlmError::Cancelled),
+                Err(e) if streamed.load(Ordering::SeqCst) => {
+                    error!("❌ {} provider failed mid-stream: {}", provider.name(), e);
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!("⚠️ {} provider failed: {}", provider.name(), e);
+                    last_error = Some(e);
+                }
+            }
+        }
 
-            n_cur += 1;
+        Err(last_error.unwrap_or(LlmError::NotRunning))
+    }
 
-            // Decode the next token
-            context.decode(&mut batch)
-                .map_err(|e| {
-                    error!("❌ Failed to decode token at position {}: {}", n_cur, e);
-                    LlmError::LlamaCppError(format!("Failed to decode token: {}", e))
-                })?;
-        }
+    async fn dispatch_chat(&self, request: ChatRequest, on_token: TokenSink<'_>) -> Result<ChatResponse, LlmError> {
+        self.with_fallback("request", on_token, |provider, sink| {
+            let request = request.clone();
+            Box::pin(async move {
+                let mut response = provider.chat_completion_stream(request, sink).await?;
+                response.provider = Some(provider.name().to_string());
+                Ok(response)
+            })
+        })
+        .await
+    }
 
-        let generation_duration = generation_start.elapsed();
-        let completion_tokens = n_cur - initial_tokens;
+    pub async fn text_completion(&self, request: CompletionRequest) -> Result<CompletionResponse, LlmError> {
+        self.text_completion_stream(request, &|_| {}).await
+    }
 
-        info!("✅ Token generation complete: {} tokens in {:?} ({:.2} tokens/sec)",
-            completion_tokens,
-            generation_duration,
-            completion_tokens as f64 / generation_duration.as_secs_f64()
+    /// Continue a raw prompt, passing each generated piece of text to `on_token`.
+    pub async fn text_completion_stream(&self, request: CompletionRequest, on_token: TokenSink<'_>) -> Result<CompletionResponse, LlmError> {
+        info!("🚀 Text completion request received");
+        debug!("📋 Request details: model={}, prompt_length={} chars, suffix={}, temperature={:?}, top_p={:?}, max_tokens={:?}",
+            request.model,
+            request.prompt.len(),
+            request.suffix.is_some(),
+            request.temperature,
+            request.top_p,
+            request.max_tokens
         );
 
-        // Build the response
-        info!("📦 Building chat response...");
-        let total_duration = start_time.elapsed();
-        let prompt_tokens = prompt_tokens_len as u32;
-        let completion_tokens = (n_cur - initial_tokens) as u32;
-        let total_tokens = n_cur as u32;
-
-        // Log response statistics
-        info!("📊 Response statistics:");
-        info!("   • Prompt tokens: {}", prompt_tokens);
-        info!("   • Completion tokens: {}", completion_tokens);
-        info!("   • Total tokens: {}", total_tokens);
-        info!("   • Response length: {} characters", response_content.len());
-        info!("   • Total processing time: {:?}", total_duration);
-
-        // Log the complete response content
-        info!("💬 Generated response content:");
-        debug!("📝 Full response: '{}'", response_content);
-
-        let chat_response = ChatResponse {
-            id: "chat-completion".to_string(),
-            object: "chat.completion".to_string(),
-            created: chrono::Utc::now().timestamp() as u64,
-            model: self.config.model_name.clone(),
-            choices: vec![ChatChoice {
-                index: 0,
-                message: ChatMessage {
-                    role: "assistant".to_string(),
-                    content: response_content.clone(),
-                },
-                finish_reason: Some("stop".to_string()),
-            }],
-            usage: Some(ChatUsage {
-                prompt_tokens,
-                completion_tokens,
-                total_tokens,
-            }),
-        };
+        if !self.is_running() {
+            error!("❌ No provider running");
+            return Err(LlmError::NotRunning);
+        }
+        request.sampling.over(&self.config.sampling).validate()?;
+
+        self.with_fallback("text completion", on_token, |provider, sink| {
+            let request = request.clone();
+            Box::pin(async move {
+                let mut response = provider.text_completion_stream(request, sink).await?;
+                response.provider = Some(provider.name().to_string());
+                Ok(response)
+            })
+        })
+        .await
+    }
+
+    /// Model that `embeddings` runs on.
+    pub fn embedding_model_name(&self) -> &str {
+        self.config.embeddings.model.as_deref().unwrap_or(&self.config.model_name)
+    }
+
+    pub async fn embeddings(&self, mut request: EmbeddingsRequest) -> Result<EmbeddingsResponse, LlmError> {
+        info!("🧮 Embeddings request received for model {}", request.model);
 
-        info!("🎉 Chat completion successful! Returning response to frontend");
-        debug!("📋 Complete response structure: {:?}", chat_response);
+        if !self.is_running() {
+            error!("❌ No provider running");
+            return Err(LlmError::NotRunning);
+        }
+
+        // Providers pool; normalization is applied here so it behaves the same
+        // whichever provider answers.
+        request.pooling.get_or_insert(self.config.embeddings.pooling);
+        let normalize = request.normalize.unwrap_or(self.config.embeddings.normalize);
+        debug!("🔧 Embedding options: pooling={:?}, normalize={}", request.pooling, normalize);
+
+        let mut response = self
+            .with_fallback("embeddings request", &|_| {}, |provider, _| provider.embeddings(request.clone()))
+            .await?;
+        if normalize {
+            for embedding in &mut response.data {
+                normalize_embedding(&mut embedding.embedding);
+            }
+        }
+        Ok(response)
+    }
 
-        Ok(chat_response)
+    /// Tokenizer of the first running provider that has one.
+    fn tokenizer(&self) -> Result<&dyn Tokenizer, LlmError> {
+        if !self.is_running() {
+            return Err(LlmError::NotRunning);
+        }
+        self.providers
+            .iter()
+            .find_map(|provider| provider.tokenizer())
+            .ok_or_else(|| LlmError::ModelError("No running provider exposes a tokenizer".to_string()))
+    }
+
+    pub async fn tokenize(&self, text: &str, add_bos: bool, parse_special: bool) -> Result<Vec<i32>, LlmError> {
+        let tokens = self.tokenizer()?.tokenize(text, add_bos, parse_special).await?;
+        debug!("🔤 Tokenized {} chars into {} tokens", text.len(), tokens.len());
+        Ok(tokens)
+    }
+
+    pub async fn detokenize(&self, tokens: &[i32], special: bool) -> Result<String, LlmError> {
+        self.tokenizer()?.detokenize(tokens, special).await
+    }
+
+    /// LoRA adapters of the first running provider that has them.
+    fn lora_adapters(&self) -> Result<&dyn LoraAdapters, LlmError> {
+        if !self.is_running() {
+            return Err(LlmError::NotRunning);
+        }
+        self.providers
+            .iter()
+            .find_map(|provider| provider.lora_adapters())
+            .ok_or_else(|| LlmError::ModelError("No running provider supports LoRA adapters".to_string()))
+    }
+
+    pub async fn list_lora_adapters(&self) -> Result<Vec<LoraAdapterConfig>, LlmError> {
+        self.lora_adapters()?.list().await
+    }
+
+    pub async fn attach_lora_adapter(&self, mut adapter: LoraAdapterConfig) -> Result<(), LlmError> {
+        adapter.path = Self::lora_adapter_path(&adapter.path)?;
+        info!("🧬 Attaching LoRA adapter {} at {}", adapter.path.display(), adapter.scale);
+        self.lora_adapters()?.attach(adapter).await
+    }
+
+    pub async fn detach_lora_adapter(&self, path: &Path) -> Result<(), LlmError> {
+        // The file may have gone since it was attached.
+        let path = Self::lora_adapter_path(path).unwrap_or_else(|_| path.to_path_buf());
+        info!("🧬 Detaching LoRA adapter {}", path.display());
+        self.lora_adapters()?.detach(&path).await
+    }
+
+    pub async fn count_tokens(&self, request: &ChatRequest) -> Result<TokenCount, LlmError> {
+        let request = ChatRequest { messages: request.prompt_messages(), ..request.clone() };
+        let prompt_tokens = self.tokenizer()?.count_chat_tokens(&request).await?;
+        debug!("🔤 Chat request is {} / {} tokens", prompt_tokens, self.config.ctx_size);
+        Ok(TokenCount {
+            prompt_tokens,
+            context_size: self.config.ctx_size,
+        })
     }
 
     pub async fn list_models(&self) -> Result<ModelsResponse, LlmError> {
@@ -628,4 +1464,10 @@ impl LlmService {
     }
 }
 
-
+/// Scale `embedding` to unit length; the zero vector is left as is.
+fn normalize_embedding(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+}
@@ -2,5 +2,10 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some(emchat_lib::WORKER_SUBCOMMAND) {
+        emchat_lib::run_inference_worker();
+        return;
+    }
+
     emchat_lib::run()
 }
@@ -0,0 +1,646 @@
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{oneshot, Mutex};
+
+use crate::llm::{FunctionCall, FunctionDefinition, LlmError, Tool};
+use crate::tools::ToolExecutor;
+
+/// MCP revision this client speaks.
+pub const PROTOCOL_VERSION: &str = "2025-03-26";
+
+/// Upper bound on a single request, tool calls included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a tool call waits for the user before it is denied.
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Separates the server name from the tool name in the tools offered to the model.
+const TOOL_SEPARATOR: &str = "__";
+/// Tool offered for each server with resources, to read one by URI.
+const READ_RESOURCE: &str = "read_resource";
+
+/// Method not found, for requests the server sends us.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// An MCP server from the app settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpServerConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub transport: McpTransportConfig,
+    /// Tools that may run without asking the user first.
+    #[serde(default)]
+    pub auto_approve: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum McpTransportConfig {
+    /// A subprocess speaking newline-delimited JSON-RPC on stdin and stdout.
+    Stdio {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: HashMap<String, String>,
+    },
+    /// A Streamable HTTP endpoint, such as `http://127.0.0.1:3001/mcp`.
+    Http {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpTool {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default = "McpTool::default_schema")]
+    pub input_schema: Value,
+}
+
+impl McpTool {
+    fn default_schema() -> Value {
+        json!({ "type": "object" })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpResource {
+    pub uri: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+}
+
+/// One part of a resource as returned by `resources/read`: text, or base64 `blob`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceContents {
+    pub uri: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+}
+
+/// A connected server and what it offers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpServerInfo {
+    pub name: String,
+    /// Name and version the server reported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_info: Option<Value>,
+    pub tools: Vec<McpTool>,
+    pub resources: Vec<McpResource>,
+}
+
+/// A tool call waiting for the user's go-ahead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolApprovalRequest {
+    pub id: String,
+    pub server: String,
+    pub tool: String,
+    pub arguments: Value,
+}
+
+/// Asks the user whether an MCP tool may run.
+#[async_trait]
+pub trait ToolApprover: Send + Sync {
+    async fn approve(&self, request: &ToolApprovalRequest) -> bool;
+}
+
+/// Approval prompts waiting for an answer from the frontend.
+#[derive(Default)]
+pub struct PendingApprovals {
+    waiting: std::sync::Mutex<HashMap<String, oneshot::Sender<bool>>>,
+}
+
+impl PendingApprovals {
+    /// Start waiting for the answer to `id`, before asking the user.
+    pub fn register(&self, id: &str) -> oneshot::Receiver<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(id.to_string(), sender);
+        receiver
+    }
+
+    /// The user's answer, or a denial once `APPROVAL_TIMEOUT` passes.
+    pub async fn wait(&self, id: &str, receiver: oneshot::Receiver<bool>) -> bool {
+        let approved = tokio::time::timeout(APPROVAL_TIMEOUT, receiver).await;
+        self.waiting.lock().unwrap().remove(id);
+        match approved {
+            Ok(Ok(approved)) => approved,
+            Ok(Err(_)) => false,
+            Err(_) => {
+                warn!("⏰ Tool call {} was not approved within {:?}", id, APPROVAL_TIMEOUT);
+                false
+            }
+        }
+    }
+
+    pub fn resolve(&self, id: &str, approved: bool) -> Result<(), LlmError> {
+        let sender = self
+            .waiting
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| LlmError::ConfigError(format!("No tool call {} is waiting for approval", id)))?;
+        let _ = sender.send(approved);
+        Ok(())
+    }
+}
+
+struct StdioProcess {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+enum Transport {
+    Stdio(Box<Mutex<StdioProcess>>),
+    Http {
+        client: reqwest::Client,
+        url: String,
+        headers: HashMap<String, String>,
+        /// `Mcp-Session-Id` assigned by the server on initialization.
+        session: std::sync::Mutex<Option<String>>,
+    },
+}
+
+impl Transport {
+    fn start(config: &McpTransportConfig) -> Result<Self, LlmError> {
+        match config {
+            McpTransportConfig::Stdio { command, args, env } => {
+                info!("🚀 Spawning MCP server: {} {}", command, args.join(" "));
+                let mut child = Command::new(command)
+                    .args(args)
+                    .envs(env)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::inherit())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .map_err(|e| LlmError::McpError(format!("Failed to start '{}': {}", command, e)))?;
+                let stdin = child.stdin.take().ok_or_else(|| LlmError::McpError("Server stdin unavailable".to_string()))?;
+                let stdout = child.stdout.take().ok_or_else(|| LlmError::McpError("Server stdout unavailable".to_string()))?;
+                Ok(Transport::Stdio(Box::new(Mutex::new(StdioProcess {
+                    child,
+                    stdin,
+                    stdout: BufReader::new(stdout),
+                }))))
+            }
+            McpTransportConfig::Http { url, headers } => Ok(Transport::Http {
+                client: reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?,
+                url: url.clone(),
+                headers: headers.clone(),
+                session: std::sync::Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Send `message` and, for a request, wait for the response with its id.
+    async fn send(&self, message: &Value) -> Result<Option<Value>, LlmError> {
+        let id = message.get("id").cloned();
+        match self {
+            Transport::Stdio(process) => {
+                let mut process = process.lock().await;
+                let mut line = serde_json::to_string(message)?;
+                line.push('\n');
+                process.stdin.write_all(line.as_bytes()).await?;
+                process.stdin.flush().await?;
+                match id {
+                    Some(id) => process.read_response(&id).await.map(Some),
+                    None => Ok(None),
+                }
+            }
+            Transport::Http { client, url, headers, session } => {
+                let mut builder = client
+                    .post(url)
+                    .header(reqwest::header::ACCEPT, "application/json, text/event-stream")
+                    .json(message);
+                for (name, value) in headers {
+                    builder = builder.header(name, value);
+                }
+                if let Some(session) = session.lock().unwrap().as_ref() {
+                    builder = builder.header("Mcp-Session-Id", session);
+                }
+
+                let response = builder.send().await?;
+                let status = response.status();
+                if !status.is_success() {
+                    let body = response.text().await.unwrap_or_default();
+                    return Err(LlmError::McpError(format!("{} returned {}: {}", url, status, body)));
+                }
+                if let Some(assigned) = response.headers().get("Mcp-Session-Id").and_then(|v| v.to_str().ok()) {
+                    *session.lock().unwrap() = Some(assigned.to_string());
+                }
+                let Some(id) = id else {
+                    return Ok(None);
+                };
+
+                let is_stream = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| v.starts_with("text/event-stream"));
+                let body = response.text().await?;
+                if !is_stream {
+                    return Ok(Some(serde_json::from_str(&body)?));
+                }
+                // The response is one of the events; others are notifications.
+                body.lines()
+                    .filter_map(|line| line.strip_prefix("data:"))
+                    .filter_map(|data| serde_json::from_str::<Value>(data.trim()).ok())
+                    .find(|event| is_response(event, &id))
+                    .map(Some)
+                    .ok_or_else(|| LlmError::McpError(format!("{} sent no response to request {}", url, id)))
+            }
+        }
+    }
+
+    async fn close(&self) {
+        match self {
+            Transport::Stdio(process) => {
+                if let Err(e) = process.lock().await.child.kill().await {
+                    warn!("⚠️ Failed to stop MCP server: {}", e);
+                }
+            }
+            Transport::Http { client, url, session, .. } => {
+                let session = session.lock().unwrap().take();
+                if let Some(session) = session {
+                    // Servers may not support ending sessions; that is fine.
+                    let _ = client.delete(url).header("Mcp-Session-Id", session).send().await;
+                }
+            }
+        }
+    }
+}
+
+impl StdioProcess {
+    /// Read messages until the response to `id`, answering the server's own
+    /// requests along the way.
+    async fn read_response(&mut self, id: &Value) -> Result<Value, LlmError> {
+        loop {
+            let mut line = String::new();
+            if self.stdout.read_line(&mut line).await? == 0 {
+                let status = self.child.wait().await?;
+                return Err(LlmError::McpError(format!("Server exited with {}", status)));
+            }
+            let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                warn!("⚠️ Ignoring non-JSON output from MCP server: {}", line.trim());
+                continue;
+            };
+            if is_response(&message, id) {
+                return Ok(message);
+            }
+            match (message.get("method").and_then(Value::as_str), message.get("id")) {
+                (Some(method), Some(request_id)) => {
+                    let reply = match method {
+                        "ping" => json!({ "jsonrpc": "2.0", "id": request_id, "result": {} }),
+                        _ => json!({
+                            "jsonrpc": "2.0",
+                            "id": request_id,
+                            "error": { "code": METHOD_NOT_FOUND, "message": format!("Method not found: {}", method) },
+                        }),
+                    };
+                    let mut reply = serde_json::to_string(&reply)?;
+                    reply.push('\n');
+                    self.stdin.write_all(reply.as_bytes()).await?;
+                    self.stdin.flush().await?;
+                }
+                (Some(method), None) => debug!("📨 MCP notification: {}", method),
+                _ => warn!("⚠️ Ignoring unexpected MCP message: {}", line.trim()),
+            }
+        }
+    }
+}
+
+fn is_response(message: &Value, id: &Value) -> bool {
+    message.get("id") == Some(id) && (message.get("result").is_some() || message.get("error").is_some())
+}
+
+/// A connection to one MCP server.
+pub struct McpClient {
+    config: McpServerConfig,
+    transport: Transport,
+    next_id: AtomicU64,
+    info: McpServerInfo,
+}
+
+impl McpClient {
+    /// Start the server, negotiate the protocol and fetch its tools and resources.
+    pub async fn connect(config: McpServerConfig) -> Result<Self, LlmError> {
+        info!("🔌 Connecting to MCP server '{}'", config.name);
+        let transport = Transport::start(&config.transport)?;
+        let mut client = Self {
+            info: McpServerInfo {
+                name: config.name.clone(),
+                server_info: None,
+                tools: Vec::new(),
+                resources: Vec::new(),
+            },
+            config,
+            transport,
+            next_id: AtomicU64::new(1),
+        };
+
+        let initialized = client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": "emchat", "version": env!("CARGO_PKG_VERSION") },
+                }),
+            )
+            .await;
+        let initialized = match initialized {
+            Ok(initialized) => initialized,
+            Err(e) => {
+                client.close().await;
+                return Err(e);
+            }
+        };
+        client.notify("notifications/initialized").await?;
+        client.info.server_info = initialized.get("serverInfo").cloned();
+
+        let capabilities = initialized.get("capabilities").cloned().unwrap_or_default();
+        if capabilities.get("tools").is_some() {
+            client.info.tools = client.list("tools/list", "tools").await?;
+        }
+        if capabilities.get("resources").is_some() {
+            client.info.resources = client.list("resources/list", "resources").await?;
+        }
+        info!(
+            "✅ MCP server '{}' ready with {} tools and {} resources",
+            client.config.name,
+            client.info.tools.len(),
+            client.info.resources.len()
+        );
+        Ok(client)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub fn info(&self) -> &McpServerInfo {
+        &self.info
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value, LlmError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        debug!("📤 MCP '{}' request {}: {}", self.config.name, id, method);
+
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, self.transport.send(&message))
+            .await
+            .map_err(|_| LlmError::McpError(format!("'{}' did not answer {} within {:?}", self.config.name, method, REQUEST_TIMEOUT)))??
+            .ok_or_else(|| LlmError::McpError(format!("'{}' sent no response to {}", self.config.name, method)))?;
+
+        if let Some(error) = response.get("error") {
+            let message = error.get("message").and_then(Value::as_str).unwrap_or("unknown error");
+            error!("❌ MCP '{}' {} failed: {}", self.config.name, method, message);
+            return Err(LlmError::McpError(format!("{} failed: {}", method, message)));
+        }
+        Ok(response.get("result").cloned().unwrap_or_default())
+    }
+
+    async fn notify(&self, method: &str) -> Result<(), LlmError> {
+        self.transport.send(&json!({ "jsonrpc": "2.0", "method": method })).await?;
+        Ok(())
+    }
+
+    /// Fetch every page of a list method.
+    async fn list<T: serde::de::DeserializeOwned>(&self, method: &str, key: &str) -> Result<Vec<T>, LlmError> {
+        let mut items = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let mut page = self.request(method, params).await?;
+            items.extend(serde_json::from_value::<Vec<T>>(page.get_mut(key).map(Value::take).unwrap_or(json!([])))?);
+            cursor = page.get("nextCursor").and_then(Value::as_str).map(str::to_string);
+            if cursor.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+
+    /// Run a tool, returning its text output. A tool that reports an error
+    /// gives `McpError` with that output.
+    pub async fn call_tool(&self, name: &str, arguments: Value) -> Result<String, LlmError> {
+        let result = self.request("tools/call", json!({ "name": name, "arguments": arguments })).await?;
+        let text = result
+            .get("content")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .map(|part| match part.get("type").and_then(Value::as_str) {
+                Some("text") => part.get("text").and_then(Value::as_str).unwrap_or_default().to_string(),
+                Some("resource") => part
+                    .pointer("/resource/text")
+                    .or_else(|| part.pointer("/resource/uri"))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                Some(kind) => format!("[{} content]", kind),
+                None => part.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if result.get("isError").and_then(Value::as_bool).unwrap_or(false) {
+            return Err(LlmError::McpError(text));
+        }
+        Ok(text)
+    }
+
+    pub async fn read_resource(&self, uri: &str) -> Result<Vec<ResourceContents>, LlmError> {
+        let mut result = self.request("resources/read", json!({ "uri": uri })).await?;
+        Ok(serde_json::from_value(result.get_mut("contents").map(Value::take).unwrap_or(json!([])))?)
+    }
+
+    pub async fn close(&self) {
+        info!("🔌 Disconnecting MCP server '{}'", self.config.name);
+        self.transport.close().await;
+    }
+}
+
+/// The MCP servers the app is connected to.
+#[derive(Default)]
+pub struct McpManager {
+    clients: Vec<Arc<McpClient>>,
+}
+
+impl McpManager {
+    /// Connect to `config`, replacing any server of the same name.
+    pub async fn connect(&mut self, config: McpServerConfig) -> Result<McpServerInfo, LlmError> {
+        if config.name.is_empty() || config.name.contains(TOOL_SEPARATOR) {
+            return Err(LlmError::ConfigError(format!(
+                "MCP server name '{}' must be non-empty and not contain '{}'",
+                config.name, TOOL_SEPARATOR
+            )));
+        }
+        self.disconnect(&config.name).await;
+        let client = McpClient::connect(config).await?;
+        let info = client.info().clone();
+        self.clients.push(Arc::new(client));
+        Ok(info)
+    }
+
+    /// Returns whether a server of that name was connected.
+    pub async fn disconnect(&mut self, name: &str) -> bool {
+        match self.clients.iter().position(|client| client.name() == name) {
+            Some(index) => {
+                self.clients.remove(index).close().await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn shutdown(&mut self) {
+        for client in self.clients.drain(..) {
+            client.close().await;
+        }
+    }
+
+    pub fn servers(&self) -> Vec<McpServerInfo> {
+        self.clients.iter().map(|client| client.info().clone()).collect()
+    }
+
+    /// A handle to the server, so that requests can be made without the manager locked.
+    pub fn client(&self, name: &str) -> Result<Arc<McpClient>, LlmError> {
+        self.clients
+            .iter()
+            .find(|client| client.name() == name)
+            .cloned()
+            .ok_or_else(|| LlmError::ConfigError(format!("No MCP server named '{}' is connected", name)))
+    }
+}
+
+/// The tools and resources of every connected server, for `tools::chat`.
+/// Each call is put to `approver` unless the server config auto-approves it.
+pub struct McpTools<'a> {
+    manager: &'a Mutex<McpManager>,
+    approver: &'a dyn ToolApprover,
+    tools: Vec<Tool>,
+}
+
+impl<'a> McpTools<'a> {
+    /// Offers the tools of the servers connected now. The manager is only
+    /// locked again to look up the server for each call, not while the user
+    /// approves it or the server answers.
+    pub async fn new(manager: &'a Mutex<McpManager>, approver: &'a dyn ToolApprover) -> Self {
+        let tools = Self::list(&*manager.lock().await);
+        Self { manager, approver, tools }
+    }
+
+    fn list(manager: &McpManager) -> Vec<Tool> {
+        let tool = |server: &str, name: &str, description: Option<String>, parameters: Value| Tool {
+            kind: "function".to_string(),
+            function: FunctionDefinition {
+                name: format!("{}{}{}", server, TOOL_SEPARATOR, name),
+                description,
+                parameters: Some(parameters),
+            },
+        };
+
+        let mut tools = Vec::new();
+        for client in &manager.clients {
+            let info = client.info();
+            for t in &info.tools {
+                tools.push(tool(&info.name, &t.name, t.description.clone(), t.input_schema.clone()));
+            }
+            if !info.resources.is_empty() && !info.tools.iter().any(|t| t.name == READ_RESOURCE) {
+                let uris: Vec<&str> = info.resources.iter().map(|r| r.uri.as_str()).collect();
+                let listing: Vec<String> = info
+                    .resources
+                    .iter()
+                    .map(|r| format!("{} ({})", r.uri, r.description.as_deref().unwrap_or(&r.name)))
+                    .collect();
+                tools.push(tool(
+                    &info.name,
+                    READ_RESOURCE,
+                    Some(format!("Read a resource from {}: {}", info.name, listing.join(", "))),
+                    json!({
+                        "type": "object",
+                        "properties": { "uri": { "enum": uris } },
+                        "required": ["uri"],
+                    }),
+                ));
+            }
+        }
+        tools
+    }
+
+    async fn run(&self, function: &FunctionCall) -> Result<String, LlmError> {
+        let (server, tool) = function
+            .name
+            .split_once(TOOL_SEPARATOR)
+            .ok_or_else(|| LlmError::ConfigError(format!("'{}' is not an MCP tool", function.name)))?;
+        let arguments: Value = match function.arguments.trim() {
+            "" => json!({}),
+            arguments => serde_json::from_str(arguments)?,
+        };
+
+        let auto_approved = self.manager.lock().await.client(server)?.config.auto_approve.iter().any(|name| name == tool);
+        if !auto_approved {
+            let request = ToolApprovalRequest {
+                id: uuid::Uuid::new_v4().to_string(),
+                server: server.to_string(),
+                tool: tool.to_string(),
+                arguments: arguments.clone(),
+            };
+            info!("🙋 Asking to run {} on MCP server '{}'", tool, server);
+            if !self.approver.approve(&request).await {
+                return Err(LlmError::McpError("The user declined this tool call".to_string()));
+            }
+        }
+
+        let client = self.manager.lock().await.client(server)?;
+        if tool == READ_RESOURCE && !client.info.tools.iter().any(|t| t.name == READ_RESOURCE) {
+            let uri = arguments.get("uri").and_then(Value::as_str).unwrap_or_default();
+            let contents = client.read_resource(uri).await?;
+            return Ok(serde_json::to_string(&contents)?);
+        }
+        client.call_tool(tool, arguments).await
+    }
+}
+
+#[async_trait]
+impl ToolExecutor for McpTools<'_> {
+    fn tools(&self) -> Vec<Tool> {
+        self.tools.clone()
+    }
+
+    async fn call(&self, function: &FunctionCall) -> String {
+        info!("🔧 Calling MCP tool {}({})", function.name, function.arguments);
+        match self.run(function).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("⚠️ MCP tool {} failed: {}", function.name, e);
+                json!({ "error": e.to_string() }).to_string()
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::bluetooth_tools;
+use crate::llm::{ChatMessage, ChatRequest, FunctionCall, LlmError};
+use crate::mcp::{McpTool, PROTOCOL_VERSION};
+use crate::{BluetoothState, LlmState};
+
+// The app's own MCP server, so agents on this machine can drive the Bluetooth
+// scanner and the local model. It speaks Streamable HTTP statelessly: every
+// request is answered with a single JSON body and no session is kept.
+
+/// Tool that sends a prompt to the local model.
+const CHAT_TOOL: &str = "chat";
+
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Clone)]
+struct McpServerState {
+    service: LlmState,
+    bluetooth: BluetoothState,
+}
+
+/// The `/mcp` endpoint, merged into the API server's router.
+pub(crate) fn routes(service: LlmState, bluetooth: BluetoothState) -> Router<LlmState> {
+    Router::new()
+        .route("/mcp", post(handle))
+        .with_state(McpServerState { service, bluetooth })
+}
+
+#[derive(Debug, Deserialize)]
+struct CallParams {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatArguments {
+    prompt: String,
+    system: Option<String>,
+    max_tokens: Option<i32>,
+    temperature: Option<f64>,
+}
+
+/// Tools offered to MCP clients: the built-in Bluetooth tools and `chat`.
+pub fn tools() -> Vec<McpTool> {
+    let mut tools: Vec<McpTool> = bluetooth_tools::definitions()
+        .into_iter()
+        .map(|tool| McpTool {
+            name: tool.function.name,
+            description: tool.function.description,
+            input_schema: tool.function.parameters.unwrap_or_else(|| json!({ "type": "object" })),
+        })
+        .collect();
+    tools.push(McpTool {
+        name: CHAT_TOOL.to_string(),
+        description: Some("Send a prompt to the model loaded in EmChat and return its reply.".to_string()),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "prompt": { "type": "string" },
+                "system": { "type": "string", "description": "Optional system prompt." },
+                "max_tokens": { "type": "integer" },
+                "temperature": { "type": "number" }
+            },
+            "required": ["prompt"]
+        }),
+    });
+    tools
+}
+
+async fn handle(State(state): State<McpServerState>, headers: HeaderMap, Json(message): Json<Value>) -> Response {
+    // Browsers always send an Origin; refuse pages that are not local so a
+    // rebound DNS name cannot reach the hardware.
+    if let Some(origin) = headers.get(header::ORIGIN).and_then(|origin| origin.to_str().ok()) {
+        if !is_local_origin(origin) {
+            warn!("🚫 Rejected MCP request from origin {}", origin);
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+
+    let Some(method) = message.get("method").and_then(Value::as_str) else {
+        if message.get("result").is_some() || message.get("error").is_some() {
+            return StatusCode::ACCEPTED.into_response();
+        }
+        return Json(error(Value::Null, INVALID_REQUEST, "Expected a JSON-RPC message")).into_response();
+    };
+    let Some(id) = message.get("id").cloned() else {
+        debug!("🔔 MCP notification {}", method);
+        return StatusCode::ACCEPTED.into_response();
+    };
+
+    info!("🔌 MCP request {}", method);
+    let params = message.get("params").cloned().unwrap_or(Value::Null);
+    let reply = match method {
+        "initialize" => result(
+            id,
+            json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "tools": {} },
+                "serverInfo": { "name": "emchat", "version": env!("CARGO_PKG_VERSION") }
+            }),
+        ),
+        "ping" => result(id, json!({})),
+        "tools/list" => result(id, json!({ "tools": tools() })),
+        "tools/call" => match serde_json::from_value::<CallParams>(params) {
+            Ok(params) if params.name == CHAT_TOOL || tools().iter().any(|tool| tool.name == params.name) => {
+                result(id, call_result(call(&state, params).await))
+            }
+            Ok(params) => error(id, INVALID_PARAMS, &format!("Unknown tool '{}'", params.name)),
+            Err(e) => error(id, INVALID_PARAMS, &e.to_string()),
+        },
+        method => error(id, METHOD_NOT_FOUND, &format!("Method not found: {}", method)),
+    };
+    Json(reply).into_response()
+}
+
+async fn call(state: &McpServerState, params: CallParams) -> Result<String, String> {
+    let arguments = if params.arguments.is_null() { json!({}) } else { params.arguments };
+    if params.name != CHAT_TOOL {
+        let function = FunctionCall {
+            name: params.name,
+            arguments: arguments.to_string(),
+        };
+        return bluetooth_tools::run(&state.bluetooth, &function).await.map(|result| result.to_string());
+    }
+
+    let arguments: ChatArguments = serde_json::from_value(arguments).map_err(|e| e.to_string())?;
+    chat(&state.service, arguments).await.map_err(|e| e.to_string())
+}
+
+async fn chat(service: &LlmState, arguments: ChatArguments) -> Result<String, LlmError> {
+    let message = |role: &str, content: String| ChatMessage {
+        role: role.to_string(),
+        content,
+        ..Default::default()
+    };
+    let mut messages: Vec<ChatMessage> = arguments.system.map(|system| message("system", system)).into_iter().collect();
+    messages.push(message("user", arguments.prompt));
+
+    let request = ChatRequest {
+        model: "local".to_string(),
+        messages,
+        temperature: arguments.temperature,
+        max_tokens: arguments.max_tokens,
+        ..Default::default()
+    };
+    let service = service.read().await;
+    let response = service.chat_completion(request).await?;
+    Ok(response.choices.into_iter().next().map(|choice| choice.message.content).unwrap_or_default())
+}
+
+fn call_result(outcome: Result<String, String>) -> Value {
+    let (text, is_error) = match outcome {
+        Ok(text) => (text, false),
+        Err(message) => (message, true),
+    };
+    json!({ "content": [{ "type": "text", "text": text }], "isError": is_error })
+}
+
+fn result(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn is_local_origin(origin: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(origin) else {
+        return false;
+    };
+    match url.host_str() {
+        Some(host) => host == "localhost" || host.ends_with(".localhost") || host == "127.0.0.1" || host == "[::1]",
+        None => false,
+    }
+}
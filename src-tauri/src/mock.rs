@@ -0,0 +1,381 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use async_trait::async_trait;
+
+use crate::llm::{
+    CancelToken, ChatChoice, ChatMessage, ChatRequest, ChatResponse, ChatUsage, ChoiceLogprobs, CompletionChoice,
+    CompletionRequest, CompletionResponse, Embedding, EmbeddingsRequest, EmbeddingsResponse, EmbeddingsUsage, LlmError,
+    LoraAdapterConfig, TokenLogprob, TopLogprob,
+};
+use crate::provider::{ChatProvider, LoraAdapters, TokenSink, Tokenizer};
+use crate::tools;
+
+/// BOS token id of the mock tokenizer, rendered as `<s>`.
+pub const MOCK_BOS: i32 = 256;
+
+/// One scripted outcome for a `MockProvider` request.
+pub enum MockReply {
+    Text(String),
+    Error(LlmError),
+}
+
+/// Deterministic stand-in for `LlamaProvider` in tests.
+///
+/// Replies are taken from a script in order. Words count as tokens, both for
+/// prompt accounting against `ctx_size` and for generation, which emits one
+/// token per `token_delay` and checks the cancel token in between, like the
+/// llama.cpp loop does. The embedding of a text is `[words, characters]`.
+/// `tokenize` is byte-level, with `MOCK_BOS` as the BOS token, which `<s>` is
+/// parsed as when special tokens are. When a request
+/// offers tools, `<tool_call>` blocks in a reply become tool calls. Each of a
+/// request's `best_of` replies takes the next scripted one, and the first `n`
+/// are returned. Asked for log-probabilities, every token is certain. Like the
+/// local model, it generates for one request at a time. LoRA adapters are
+/// only kept in a list.
+pub struct MockProvider {
+    name: String,
+    script: Mutex<VecDeque<MockReply>>,
+    token_delay: Duration,
+    ctx_size: u32,
+    cancel_token: CancelToken,
+    requests: Mutex<Vec<ChatRequest>>,
+    slot: tokio::sync::Mutex<()>,
+    adapters: Mutex<Vec<LoraAdapterConfig>>,
+}
+
+impl MockProvider {
+    pub fn new(cancel_token: CancelToken) -> Self {
+        Self {
+            name: "mock".to_string(),
+            script: Mutex::new(VecDeque::new()),
+            token_delay: Duration::ZERO,
+            ctx_size: 4096,
+            cancel_token,
+            requests: Mutex::new(Vec::new()),
+            slot: tokio::sync::Mutex::new(()),
+            adapters: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn reply(self, text: &str) -> Self {
+        self.script.lock().unwrap().push_back(MockReply::Text(text.to_string()));
+        self
+    }
+
+    pub fn fail(self, error: LlmError) -> Self {
+        self.script.lock().unwrap().push_back(MockReply::Error(error));
+        self
+    }
+
+    pub fn with_token_delay(mut self, token_delay: Duration) -> Self {
+        self.token_delay = token_delay;
+        self
+    }
+
+    pub fn with_ctx_size(mut self, ctx_size: u32) -> Self {
+        self.ctx_size = ctx_size;
+        self
+    }
+
+    /// Requests received so far, in arrival order.
+    pub fn requests(&self) -> Vec<ChatRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn count_tokens(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    /// Play the next scripted reply one word at a time, within the context and `max_tokens`.
+    async fn generate(&self, prompt_tokens: usize, max_tokens: Option<i32>, cancel: &CancelToken, on_token: TokenSink<'_>) -> Result<(String, usize, &'static str), LlmError> {
+        if prompt_tokens >= self.ctx_size as usize {
+            return Err(LlmError::ContextOverflow(format!(
+                "prompt is {} tokens but the context holds {}",
+                prompt_tokens, self.ctx_size
+            )));
+        }
+
+        let scripted = self.script.lock().unwrap().pop_front();
+        let text = match scripted {
+            Some(MockReply::Text(text)) => text,
+            Some(MockReply::Error(e)) => return Err(e),
+            None => return Err(LlmError::ModelError("Mock script exhausted".to_string())),
+        };
+
+        let budget = (self.ctx_size as usize - prompt_tokens).min(max_tokens.map_or(usize::MAX, |n| n.max(0) as usize));
+        let mut content = String::new();
+        let mut completion_tokens = 0;
+        let mut finish_reason = "stop";
+        for token in text.split_inclusive(' ') {
+            if completion_tokens == budget {
+                finish_reason = "length";
+                break;
+            }
+            if self.cancel_token.is_cancelled() || cancel.is_cancelled() {
+                return Err(LlmError::Cancelled);
+            }
+            if !self.token_delay.is_zero() {
+                tokio::time::sleep(self.token_delay).await;
+            }
+            content.push_str(token);
+            on_token(token);
+            completion_tokens += 1;
+        }
+        Ok((content, completion_tokens, finish_reason))
+    }
+}
+
+#[async_trait]
+impl ChatProvider for MockProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn chat_completion(&self, request: ChatRequest) -> Result<ChatResponse, LlmError> {
+        self.chat_completion_stream(request, &|_| {}).await
+    }
+
+    async fn chat_completion_stream(&self, request: ChatRequest, on_token: TokenSink<'_>) -> Result<ChatResponse, LlmError> {
+        self.requests.lock().unwrap().push(request.clone());
+        let _slot = self.slot.lock().await;
+
+        let prompt_tokens: usize = request.messages.iter().map(|m| Self::count_tokens(&m.content)).sum();
+        let (n, best_of) = request.choice_counts()?;
+        let top_logprobs = request.logprob_count()?;
+        let offers_tools = !tools::offered(&request)?.is_empty();
+        let mut choices = Vec::new();
+        let mut completion_tokens = 0;
+        for index in 0..best_of {
+            let sink: TokenSink<'_> = if best_of == 1 { on_token } else { &|_| {} };
+            let (content, tokens, finish_reason) = self.generate(prompt_tokens, request.max_tokens, &request.cancel, sink).await?;
+            completion_tokens += tokens;
+            let logprobs = top_logprobs.map(|top| ChoiceLogprobs {
+                content: content
+                    .split_inclusive(' ')
+                    .map(|token| TokenLogprob {
+                        token: token.to_string(),
+                        logprob: 0.0,
+                        bytes: token.as_bytes().to_vec(),
+                        top_logprobs: (top > 0)
+                            .then(|| TopLogprob { token: token.to_string(), logprob: 0.0, bytes: token.as_bytes().to_vec() })
+                            .into_iter()
+                            .collect(),
+                    })
+                    .collect(),
+            });
+            let (content, tool_calls, finish_reason) = if !offers_tools {
+                (content, None, finish_reason)
+            } else {
+                let (content, calls) = tools::parse_calls(&content);
+                match calls.is_empty() {
+                    true => (content, None, finish_reason),
+                    false => (content, Some(calls), "tool_calls"),
+                }
+            };
+            choices.push(ChatChoice {
+                index: index as u32,
+                message: ChatMessage {
+                    role: "assistant".to_string(),
+                    content,
+                    tool_calls,
+                    ..Default::default()
+                },
+                logprobs,
+                finish_reason: Some(finish_reason.to_string()),
+            });
+        }
+        choices.truncate(n);
+
+        Ok(ChatResponse {
+            id: "chat-completion".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: request.model,
+            choices,
+            usage: Some(ChatUsage {
+                prompt_tokens: prompt_tokens as u32,
+                completion_tokens: completion_tokens as u32,
+                total_tokens: (prompt_tokens + completion_tokens) as u32,
+                completion_tokens_details: None,
+            }),
+            provider: None,
+        })
+    }
+
+    async fn text_completion(&self, request: CompletionRequest) -> Result<CompletionResponse, LlmError> {
+        self.text_completion_stream(request, &|_| {}).await
+    }
+
+    /// The suffix counts towards the prompt but does not otherwise affect the reply.
+    async fn text_completion_stream(&self, request: CompletionRequest, on_token: TokenSink<'_>) -> Result<CompletionResponse, LlmError> {
+        let _slot = self.slot.lock().await;
+        let prompt_tokens = Self::count_tokens(&request.prompt) + request.suffix.as_deref().map_or(0, Self::count_tokens);
+        let (text, completion_tokens, finish_reason) = self.generate(prompt_tokens, request.max_tokens, &request.cancel, on_token).await?;
+
+        Ok(CompletionResponse {
+            id: "text-completion".to_string(),
+            object: "text_completion".to_string(),
+            created: 0,
+            model: request.model,
+            choices: vec![CompletionChoice {
+                index: 0,
+                text,
+                finish_reason: Some(finish_reason.to_string()),
+            }],
+            usage: Some(ChatUsage {
+                prompt_tokens: prompt_tokens as u32,
+                completion_tokens: completion_tokens as u32,
+                total_tokens: (prompt_tokens + completion_tokens) as u32,
+                completion_tokens_details: None,
+            }),
+            provider: None,
+        })
+    }
+
+    async fn embeddings(&self, request: EmbeddingsRequest) -> Result<EmbeddingsResponse, LlmError> {
+        let texts = request.input.into_texts();
+        let prompt_tokens = texts.iter().map(|t| Self::count_tokens(t)).sum::<usize>() as u32;
+        let data = (0_u32..)
+            .zip(texts)
+            .map(|(index, text)| Embedding {
+                object: "embedding".to_string(),
+                index,
+                embedding: vec![Self::count_tokens(&text) as f32, text.chars().count() as f32],
+                tokens: Self::count_tokens(&text) as u32,
+            })
+            .collect();
+
+        Ok(EmbeddingsResponse {
+            object: "list".to_string(),
+            data,
+            model: request.model,
+            usage: EmbeddingsUsage {
+                prompt_tokens,
+                total_tokens: prompt_tokens,
+            },
+        })
+    }
+
+    fn is_busy(&self) -> bool {
+        self.slot.try_lock().is_err()
+    }
+
+    fn tokenizer(&self) -> Option<&dyn Tokenizer> {
+        Some(self)
+    }
+
+    fn lora_adapters(&self) -> Option<&dyn LoraAdapters> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl Tokenizer for MockProvider {
+    async fn tokenize(&self, text: &str, add_bos: bool, parse_special: bool) -> Result<Vec<i32>, LlmError> {
+        let mut tokens: Vec<i32> = add_bos.then_some(MOCK_BOS).into_iter().collect();
+        let mut rest = text.as_bytes();
+        while let Some((&byte, after)) = rest.split_first() {
+            match rest.strip_prefix(b"<s>").filter(|_| parse_special) {
+                Some(after) => {
+                    tokens.push(MOCK_BOS);
+                    rest = after;
+                }
+                None => {
+                    tokens.push(i32::from(byte));
+                    rest = after;
+                }
+            }
+        }
+        Ok(tokens)
+    }
+
+    async fn detokenize(&self, tokens: &[i32], special: bool) -> Result<String, LlmError> {
+        let mut bytes = Vec::new();
+        for &token in tokens {
+            match token {
+                0..=255 => bytes.push(token as u8),
+                MOCK_BOS if special => bytes.extend_from_slice(b"<s>"),
+                MOCK_BOS => {}
+                _ => return Err(LlmError::ConfigError(format!("Token id {} is outside the vocabulary (0..257)", token))),
+            }
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    async fn count_chat_tokens(&self, request: &ChatRequest) -> Result<u32, LlmError> {
+        Ok(request.messages.iter().map(|m| Self::count_tokens(&m.content)).sum::<usize>() as u32)
+    }
+}
+
+#[async_trait]
+impl LoraAdapters for MockProvider {
+    async fn list(&self) -> Result<Vec<LoraAdapterConfig>, LlmError> {
+        Ok(self.adapters.lock().unwrap().clone())
+    }
+
+    async fn attach(&self, adapter: LoraAdapterConfig) -> Result<(), LlmError> {
+        let mut adapters = self.adapters.lock().unwrap();
+        match adapters.iter_mut().find(|attached| attached.path == adapter.path) {
+            Some(attached) => attached.scale = adapter.scale,
+            None => adapters.push(adapter),
+        }
+        Ok(())
+    }
+
+    async fn detach(&self, path: &Path) -> Result<(), LlmError> {
+        let mut adapters = self.adapters.lock().unwrap();
+        let count = adapters.len();
+        adapters.retain(|attached| attached.path != path);
+        if adapters.len() == count {
+            return Err(LlmError::ConfigError(format!("LoRA adapter {} is not attached", path.display())));
+        }
+        Ok(())
+    }
+}
+
+/// Lets a test keep a handle on a mock after handing it to `LlmService`.
+#[async_trait]
+impl ChatProvider for Arc<MockProvider> {
+    fn name(&self) -> &str {
+        self.as_ref().name()
+    }
+
+    async fn chat_completion(&self, request: ChatRequest) -> Result<ChatResponse, LlmError> {
+        self.as_ref().chat_completion(request).await
+    }
+
+    async fn chat_completion_stream(&self, request: ChatRequest, on_token: TokenSink<'_>) -> Result<ChatResponse, LlmError> {
+        self.as_ref().chat_completion_stream(request, on_token).await
+    }
+
+    async fn text_completion(&self, request: CompletionRequest) -> Result<CompletionResponse, LlmError> {
+        self.as_ref().text_completion(request).await
+    }
+
+    async fn text_completion_stream(&self, request: CompletionRequest, on_token: TokenSink<'_>) -> Result<CompletionResponse, LlmError> {
+        self.as_ref().text_completion_stream(request, on_token).await
+    }
+
+    async fn embeddings(&self, request: EmbeddingsRequest) -> Result<EmbeddingsResponse, LlmError> {
+        self.as_ref().embeddings(request).await
+    }
+
+    fn is_busy(&self) -> bool {
+        self.as_ref().is_busy()
+    }
+
+    fn tokenizer(&self) -> Option<&dyn Tokenizer> {
+        self.as_ref().tokenizer()
+    }
+
+    fn lora_adapters(&self) -> Option<&dyn LoraAdapters> {
+        self.as_ref().lora_adapters()
+    }
+}
@@ -0,0 +1,333 @@
+use std::convert::Infallible;
+use std::time::{Instant, SystemTime};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+use crate::llm::{ChatMessage, ChatRequest, ChatResponse, LlmError, ResponseFormat, SamplingOptions};
+use crate::server::{self, StreamEvent};
+use crate::LlmState;
+
+/// Ollama's `/api/*` endpoints on top of `LlmService`, for clients that speak
+/// the Ollama protocol rather than OpenAI's.
+pub(crate) fn routes() -> Router<LlmState> {
+    Router::new()
+        .route("/api/chat", post(chat))
+        .route("/api/generate", post(generate))
+        .route("/api/tags", get(tags))
+        .route("/api/show", post(show))
+}
+
+/// `LlmError` rendered as an Ollama error body, `{"error": "..."}`.
+struct OllamaError(StatusCode, String);
+
+impl From<LlmError> for OllamaError {
+    fn from(error: LlmError) -> Self {
+        OllamaError(server::status_code(&error), error.to_string())
+    }
+}
+
+impl IntoResponse for OllamaError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+/// The subset of Ollama's model options that maps onto `ChatRequest`.
+#[derive(Debug, Default, Deserialize)]
+struct OllamaOptions {
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    num_predict: Option<i32>,
+    /// Ollama shares llama.cpp's names for the penalties and Mirostat.
+    #[serde(flatten)]
+    sampling: SamplingOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: Option<bool>,
+    format: Option<serde_json::Value>,
+    #[serde(default)]
+    options: OllamaOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaGenerateRequest {
+    model: String,
+    prompt: String,
+    system: Option<String>,
+    stream: Option<bool>,
+    format: Option<serde_json::Value>,
+    #[serde(default)]
+    options: OllamaOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaShowRequest {
+    #[serde(alias = "name")]
+    model: String,
+}
+
+/// One line of a `/api/chat` or `/api/generate` reply. Chat replies carry
+/// `message`, generate replies carry `response`; the last line has `done` set
+/// and the generation statistics.
+#[derive(Debug, Serialize)]
+struct OllamaChunk {
+    model: String,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<String>,
+    done: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    stats: Option<OllamaStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaStats {
+    done_reason: String,
+    /// Nanoseconds, as Ollama reports durations.
+    total_duration: u64,
+    prompt_eval_count: u32,
+    eval_count: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaModelDetails {
+    format: String,
+    family: String,
+    parameter_size: String,
+    quantization_level: String,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaModel {
+    name: String,
+    model: String,
+    modified_at: String,
+    size: u64,
+    digest: String,
+    details: OllamaModelDetails,
+}
+
+#[derive(Clone, Copy)]
+enum ReplyKind {
+    Chat,
+    Generate,
+}
+
+impl ReplyKind {
+    fn chunk(self, model: &str, content: String, stats: Option<OllamaStats>) -> OllamaChunk {
+        let (message, response) = match self {
+            ReplyKind::Chat => (
+                Some(ChatMessage {
+                    role: "assistant".to_string(),
+                    content,
+                    ..Default::default()
+                }),
+                None,
+            ),
+            ReplyKind::Generate => (None, Some(content)),
+        };
+        OllamaChunk {
+            model: model.to_string(),
+            created_at: timestamp(SystemTime::now()),
+            message,
+            response,
+            done: stats.is_some(),
+            stats,
+        }
+    }
+}
+
+/// Ollama's `format`: `"json"` for any JSON, or a JSON schema.
+fn response_format(format: Option<serde_json::Value>) -> Option<ResponseFormat> {
+    match format? {
+        serde_json::Value::String(format) if format == "json" => Some(ResponseFormat::JsonObject),
+        schema @ serde_json::Value::Object(_) => Some(ResponseFormat::JsonSchema {
+            json_schema: None,
+            schema: Some(schema),
+        }),
+        _ => None,
+    }
+}
+
+fn timestamp(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+fn stats(response: &ChatResponse, start: Instant) -> OllamaStats {
+    let usage = response.usage.as_ref();
+    OllamaStats {
+        done_reason: response
+            .choices
+            .first()
+            .and_then(|c| c.finish_reason.clone())
+            .unwrap_or_else(|| "stop".to_string()),
+        total_duration: start.elapsed().as_nanos() as u64,
+        prompt_eval_count: usage.map_or(0, |u| u.prompt_tokens),
+        eval_count: usage.map_or(0, |u| u.completion_tokens),
+    }
+}
+
+/// Guess the quantization from a model name like `Llama-3.2-1B-Instruct-Q5_K_M`.
+fn quantization_level(model_name: &str) -> String {
+    model_name
+        .rsplit(['-', '.'])
+        .find(|part| {
+            let upper = part.to_ascii_uppercase();
+            (upper.starts_with('Q') || upper.starts_with("IQ") || upper.starts_with('F') || upper.starts_with("BF"))
+                && upper.chars().any(|c| c.is_ascii_digit())
+        })
+        .map(|part| part.to_ascii_uppercase())
+        .unwrap_or_default()
+}
+
+fn model_details(model_name: &str) -> OllamaModelDetails {
+    OllamaModelDetails {
+        format: "gguf".to_string(),
+        family: String::new(),
+        parameter_size: String::new(),
+        quantization_level: quantization_level(model_name),
+    }
+}
+
+async fn chat(State(service): State<LlmState>, Json(request): Json<OllamaChatRequest>) -> Response {
+    info!("🦙 Ollama chat request for model {}", request.model);
+    let chat_request = ChatRequest {
+        model: request.model,
+        messages: request.messages,
+        temperature: request.options.temperature,
+        top_p: request.options.top_p,
+        max_tokens: request.options.num_predict,
+        response_format: response_format(request.format),
+        sampling: request.options.sampling,
+        ..Default::default()
+    };
+    reply(service, chat_request, ReplyKind::Chat, request.stream.unwrap_or(true)).await
+}
+
+async fn generate(State(service): State<LlmState>, Json(request): Json<OllamaGenerateRequest>) -> Response {
+    info!("🦙 Ollama generate request for model {}", request.model);
+    let mut messages = Vec::new();
+    if let Some(system) = request.system {
+        messages.push(ChatMessage {
+            role: "system".to_string(),
+            content: system,
+            ..Default::default()
+        });
+    }
+    messages.push(ChatMessage {
+        role: "user".to_string(),
+        content: request.prompt,
+        ..Default::default()
+    });
+
+    let chat_request = ChatRequest {
+        model: request.model,
+        messages,
+        temperature: request.options.temperature,
+        top_p: request.options.top_p,
+        max_tokens: request.options.num_predict,
+        response_format: response_format(request.format),
+        sampling: request.options.sampling,
+        ..Default::default()
+    };
+    reply(service, chat_request, ReplyKind::Generate, request.stream.unwrap_or(true)).await
+}
+
+/// Answer a chat or generate request, either as a single JSON object or, as
+/// Ollama does by default, as newline-delimited JSON while tokens are produced.
+async fn reply(service: LlmState, request: ChatRequest, kind: ReplyKind, stream: bool) -> Response {
+    let start = Instant::now();
+    let model = request.model.clone();
+
+    if !stream {
+        let service = service.read().await;
+        return match service.chat_completion(request).await {
+            Ok(response) => {
+                let content = response.choices.first().map(|c| c.message.content.clone()).unwrap_or_default();
+                Json(kind.chunk(&model, content, Some(stats(&response, start)))).into_response()
+            }
+            Err(e) => OllamaError::from(e).into_response(),
+        };
+    }
+
+    let lines = server::spawn_chat_stream(service, request).filter_map(move |event| {
+        let value = match event {
+            StreamEvent::Token(token) => serde_json::to_value(kind.chunk(&model, token, None)),
+            // Ollama clients only get the reply.
+            StreamEvent::Reasoning(_) => return None,
+            StreamEvent::Done(Ok(response)) => {
+                serde_json::to_value(kind.chunk(&model, String::new(), Some(stats(&response, start))))
+            }
+            StreamEvent::Done(Err(e)) => {
+                error!("❌ Ollama stream failed: {}", e);
+                Ok(serde_json::json!({ "error": e.to_string() }))
+            }
+        };
+        let mut line = value
+            .unwrap_or_else(|e| serde_json::json!({ "error": e.to_string() }))
+            .to_string();
+        line.push('\n');
+        Some(Ok::<_, Infallible>(line))
+    });
+
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(lines)).into_response()
+}
+
+async fn tags(State(service): State<LlmState>) -> Result<Json<serde_json::Value>, OllamaError> {
+    let service = service.read().await;
+    let models: Vec<OllamaModel> = service
+        .list_models()
+        .await?
+        .data
+        .into_iter()
+        .map(|model| {
+            let metadata = service.model_file_metadata(&model.id).map(|(_, metadata)| metadata);
+            OllamaModel {
+                name: model.id.clone(),
+                model: model.id.clone(),
+                modified_at: metadata
+                    .as_ref()
+                    .and_then(|m| m.modified().ok())
+                    .map(timestamp)
+                    .unwrap_or_default(),
+                size: metadata.as_ref().map_or(0, |m| m.len()),
+                digest: String::new(),
+                details: model_details(&model.id),
+            }
+        })
+        .collect();
+
+    info!("🦙 Ollama tags: {} model(s)", models.len());
+    Ok(Json(serde_json::json!({ "models": models })))
+}
+
+async fn show(State(service): State<LlmState>, Json(request): Json<OllamaShowRequest>) -> Result<Json<serde_json::Value>, OllamaError> {
+    let service = service.read().await;
+    let (path, metadata) = service.model_file_metadata(&request.model).ok_or_else(|| {
+        OllamaError(StatusCode::NOT_FOUND, format!("model '{}' not found", request.model))
+    })?;
+
+    Ok(Json(serde_json::json!({
+        // The file name only, so the server's directory layout is not disclosed.
+        "modelfile": format!("FROM {}", path.file_name().unwrap_or_default().to_string_lossy()),
+        "parameters": "",
+        "template": "",
+        "details": model_details(&request.model),
+        "modified_at": metadata.modified().ok().map(timestamp).unwrap_or_default(),
+    })))
+}
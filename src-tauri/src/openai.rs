@@ -0,0 +1,105 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use log::{debug, error, info};
+
+use crate::llm::{
+    CancelToken, ChatRequest, ChatResponse, CompletionRequest, CompletionResponse, EmbeddingsRequest, EmbeddingsResponse,
+    LlmError, ResponseFormat,
+};
+use crate::provider::ChatProvider;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Generous upper bound for a whole non-streaming completion on a slow server.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Client for servers exposing the OpenAI `/v1/chat/completions` and `/v1/embeddings` endpoints,
+/// such as Ollama, llama-server or vLLM.
+pub struct OpenAiProvider {
+    name: String,
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: Option<String>,
+    cancel_token: CancelToken,
+}
+
+impl OpenAiProvider {
+    pub fn new(base_url: String, api_key: Option<String>, model: Option<String>, cancel_token: CancelToken) -> Result<Self, LlmError> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            name: format!("openai ({})", base_url),
+            client,
+            base_url,
+            api_key,
+            model,
+            cancel_token,
+        })
+    }
+
+    async fn send<B: Serialize, R: DeserializeOwned + std::fmt::Debug>(&self, path: &str, body: &B) -> Result<R, LlmError> {
+        let url = format!("{}{}", self.base_url, path);
+        info!("🌐 Sending request to {}", url);
+
+        let mut builder = self.client.post(&url).json(body);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+
+        let response = builder.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            error!("❌ {} returned {}: {}", url, status, body);
+            return Err(LlmError::HttpError(format!("{} returned {}: {}", url, status, body)));
+        }
+
+        let reply: R = response.json().await?;
+        debug!("📋 Remote response: {:?}", reply);
+        Ok(reply)
+    }
+
+    /// Send a request, giving up as soon as the cancel token fires.
+    async fn send_cancellable<B: Serialize, R: DeserializeOwned + std::fmt::Debug>(&self, path: &str, body: &B) -> Result<R, LlmError> {
+        tokio::select! {
+            result = self.send(path, body) => result,
+            _ = self.cancel_token.cancelled() => Err(LlmError::Cancelled),
+        }
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OpenAiProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn chat_completion(&self, mut request: ChatRequest) -> Result<ChatResponse, LlmError> {
+        if let Some(model) = &self.model {
+            request.model = model.clone();
+        }
+        request.stream = Some(false);
+        request.response_format = request.response_format.map(ResponseFormat::into_openai);
+        self.send_cancellable("/v1/chat/completions", &request).await
+    }
+
+    async fn text_completion(&self, mut request: CompletionRequest) -> Result<CompletionResponse, LlmError> {
+        if let Some(model) = &self.model {
+            request.model = model.clone();
+        }
+        request.stream = Some(false);
+        self.send_cancellable("/v1/completions", &request).await
+    }
+
+    async fn embeddings(&self, mut request: EmbeddingsRequest) -> Result<EmbeddingsResponse, LlmError> {
+        if let Some(model) = &self.model {
+            request.model = model.clone();
+        }
+        self.send_cancellable("/v1/embeddings", &request).await
+    }
+}
@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+
+use crate::llm::{
+    ChatRequest, ChatResponse, CompletionRequest, CompletionResponse, EmbeddingsRequest, EmbeddingsResponse, LlmError,
+    LoraAdapterConfig,
+};
+
+/// Receives generated text as it is produced, one piece per token.
+pub type TokenSink<'a> = &'a (dyn Fn(&str) + Send + Sync);
+
+/// A backend that can answer chat completion requests.
+///
+/// `LlmService` holds the providers started from `LlmConfig::provider` and
+/// `LlmConfig::fallback_providers`, and tries them in that order.
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    /// Short identifier used in logs.
+    fn name(&self) -> &str;
+
+    async fn chat_completion(&self, request: ChatRequest) -> Result<ChatResponse, LlmError>;
+
+    /// Like `chat_completion`, but also hands each piece of the reply to
+    /// `on_token` as it is generated. Providers that cannot stream deliver the
+    /// whole reply as a single piece.
+    async fn chat_completion_stream(&self, request: ChatRequest, on_token: TokenSink<'_>) -> Result<ChatResponse, LlmError> {
+        let response = self.chat_completion(request).await?;
+        if let Some(choice) = response.choices.first() {
+            on_token(&choice.message.content);
+        }
+        Ok(response)
+    }
+
+    /// Continue a raw prompt without applying a chat template.
+    async fn text_completion(&self, _request: CompletionRequest) -> Result<CompletionResponse, LlmError> {
+        Err(LlmError::ModelError(format!("The {} provider does not support text completion", self.name())))
+    }
+
+    /// Like `text_completion`, but also hands each generated piece to `on_token`.
+    async fn text_completion_stream(&self, request: CompletionRequest, on_token: TokenSink<'_>) -> Result<CompletionResponse, LlmError> {
+        let response = self.text_completion(request).await?;
+        if let Some(choice) = response.choices.first() {
+            on_token(&choice.text);
+        }
+        Ok(response)
+    }
+
+    /// Whether a request would first have to wait for another to finish.
+    fn is_busy(&self) -> bool {
+        false
+    }
+
+    async fn embeddings(&self, _request: EmbeddingsRequest) -> Result<EmbeddingsResponse, LlmError> {
+        Err(LlmError::ModelError(format!("The {} provider does not support embeddings", self.name())))
+    }
+
+    /// Access to the model's vocabulary, for providers that have one locally.
+    fn tokenizer(&self) -> Option<&dyn Tokenizer> {
+        None
+    }
+
+    /// LoRA adapters on the model, for providers that load one locally.
+    fn lora_adapters(&self) -> Option<&dyn LoraAdapters> {
+        None
+    }
+}
+
+/// Conversion between text and the token ids of a loaded model.
+#[async_trait]
+pub trait Tokenizer: Send + Sync {
+    /// With `parse_special` set, special-token text such as `<|im_end|>` becomes
+    /// the special token; otherwise it is tokenized as plain text.
+    async fn tokenize(&self, text: &str, add_bos: bool, parse_special: bool) -> Result<Vec<i32>, LlmError>;
+
+    /// With `special` set, special tokens are rendered as their text; otherwise they are dropped.
+    async fn detokenize(&self, tokens: &[i32], special: bool) -> Result<String, LlmError>;
+
+    /// Prompt tokens for `request` once its messages are put through the chat template.
+    async fn count_chat_tokens(&self, request: &ChatRequest) -> Result<u32, LlmError>;
+}
+
+/// LoRA adapters swapped on a loaded model; generations started afterwards use
+/// the new set.
+#[async_trait]
+pub trait LoraAdapters: Send + Sync {
+    async fn list(&self) -> Result<Vec<LoraAdapterConfig>, LlmError>;
+
+    /// Apply `adapter`, or change its scale if it is already attached.
+    async fn attach(&self, adapter: LoraAdapterConfig) -> Result<(), LlmError>;
+
+    async fn detach(&self, path: &Path) -> Result<(), LlmError>;
+}
@@ -0,0 +1,144 @@
+use crate::llm::ChatMessage;
+
+// Thinking models such as DeepSeek-R1 and QwQ open their reply with their
+// reasoning inside <think> tags before giving the answer.
+
+pub const THINK_OPEN: &str = "<think>";
+pub const THINK_CLOSE: &str = "</think>";
+
+/// Split generated `text`, which continues `prefill`, into the reasoning it
+/// opens with, if any, and the reply. Reasoning cut off before its closing
+/// tag leaves an empty reply.
+pub fn split(prefill: &str, text: &str) -> (Option<String>, String) {
+    let mut filter = ReasoningFilter::after(prefill);
+    let (mut reasoning, mut content) = filter.push(text);
+    let (rest_reasoning, rest_content) = filter.finish();
+    reasoning.push_str(&rest_reasoning);
+    content.push_str(&rest_content);
+    (Some(reasoning).filter(|reasoning| !reasoning.is_empty()), content)
+}
+
+/// `messages` as they go back to the model, with the reasoning of assistant
+/// turns either dropped or put back inline, where the chat template expects
+/// it. A trailing partial reply keeps its reasoning, left open if the reply
+/// has not started yet.
+pub fn history(messages: &[ChatMessage], strip: bool) -> Vec<ChatMessage> {
+    let last = messages.len().saturating_sub(1);
+    messages
+        .iter()
+        .enumerate()
+        .map(|(i, message)| {
+            let mut message = message.clone();
+            let reasoning = message.reasoning_content.take();
+            if message.role != "assistant" {
+                return message;
+            }
+            let (inline, content) = split("", &message.content);
+            let prefill = i == last;
+            message.content = match reasoning.or(inline) {
+                Some(reasoning) if prefill && content.is_empty() => format!("{}\n{}", THINK_OPEN, reasoning),
+                Some(reasoning) if prefill || !strip => {
+                    format!("{}\n{}\n{}\n\n{}", THINK_OPEN, reasoning, THINK_CLOSE, content)
+                }
+                _ => content,
+            };
+            message
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, PartialEq)]
+enum State {
+    /// Nothing but whitespace seen yet; the reply may still open with <think>.
+    #[default]
+    Start,
+    /// Inside <think>, skipping the whitespace before the reasoning.
+    Opened,
+    Thinking,
+    /// Past the reasoning, skipping the whitespace before the answer.
+    Closed,
+    Answering,
+}
+
+/// Sorts streamed text into reasoning and reply, holding back pieces that
+/// could be the start of a tag until it is clear which they are.
+#[derive(Debug, Default)]
+pub struct ReasoningFilter {
+    held: String,
+    state: State,
+}
+
+impl ReasoningFilter {
+    /// A filter for text that continues `prefill`, which is not passed on.
+    pub fn after(prefill: &str) -> Self {
+        let mut filter = Self::default();
+        filter.push(prefill);
+        filter
+    }
+
+    /// The reasoning and reply text in `piece` that can be passed on now.
+    pub fn push(&mut self, piece: &str) -> (String, String) {
+        let mut reasoning = String::new();
+        let mut content = String::new();
+        self.held.push_str(piece);
+        loop {
+            match self.state {
+                State::Start => {
+                    let text = self.held.trim_start();
+                    if let Some(rest) = text.strip_prefix(THINK_OPEN) {
+                        self.held = rest.to_string();
+                        self.state = State::Opened;
+                    } else if !THINK_OPEN.starts_with(text) {
+                        self.state = State::Answering;
+                    } else {
+                        break;
+                    }
+                }
+                State::Thinking => match self.held.find(THINK_CLOSE) {
+                    Some(end) => {
+                        reasoning.push_str(self.held[..end].trim_end());
+                        self.held.drain(..end + THINK_CLOSE.len());
+                        self.state = State::Closed;
+                    }
+                    None => {
+                        // Keep back what could be the start of the closing tag,
+                        // and the whitespace that may come before it.
+                        let partial = (1..THINK_CLOSE.len())
+                            .rev()
+                            .find(|&n| self.held.ends_with(&THINK_CLOSE[..n]))
+                            .unwrap_or(0);
+                        let ready = self.held[..self.held.len() - partial].trim_end().len();
+                        reasoning.extend(self.held.drain(..ready));
+                        break;
+                    }
+                },
+                State::Opened | State::Closed => {
+                    self.held = self.held.trim_start().to_string();
+                    if self.held.is_empty() {
+                        break;
+                    }
+                    self.state = if self.state == State::Opened { State::Thinking } else { State::Answering };
+                }
+                State::Answering => {
+                    content.push_str(&self.held);
+                    self.held.clear();
+                    break;
+                }
+            }
+        }
+        (reasoning, content)
+    }
+
+    /// Whether the text pushed so far leaves the filter inside the reasoning.
+    pub fn is_thinking(&self) -> bool {
+        matches!(self.state, State::Opened | State::Thinking)
+    }
+
+    /// Whatever is still held back once generation has ended.
+    pub fn finish(self) -> (String, String) {
+        match self.state {
+            State::Opened | State::Thinking => (self.held.trim_end().to_string(), String::new()),
+            _ => (String::new(), self.held),
+        }
+    }
+}
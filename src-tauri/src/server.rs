@@ -0,0 +1,450 @@
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware,
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::auth::{require_api_key, ApiKeys, AuthState};
+use crate::llm::{
+    ChatRequest, ChatResponse, ChatUsage, ChoiceLogprobs, CompletionChoice, CompletionRequest, CompletionResponse,
+    EmbeddingsRequest, EmbeddingsResponse, LlmError, ModelsResponse, ToolCall,
+};
+use crate::ollama;
+use crate::mcp_server;
+use crate::{BluetoothState, LlmState};
+
+pub const DEFAULT_API_PORT: u16 = 8080;
+
+/// How long `ApiServer::stop` waits for open connections before dropping them.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiServerConfig {
+    /// Port to listen on; 0 picks a free one.
+    pub port: u16,
+    /// Listen on all interfaces rather than loopback only. Requests must then
+    /// always carry an API key.
+    pub allow_lan: bool,
+    /// Origins browsers may call the API from; `*` allows any. Empty disables CORS.
+    pub cors_origins: Vec<String>,
+}
+
+impl Default for ApiServerConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_API_PORT,
+            allow_lan: false,
+            cors_origins: Vec::new(),
+        }
+    }
+}
+
+/// Embedded HTTP server exposing the OpenAI and Ollama APIs on top of the app's `LlmService`,
+/// so other local tools can reuse the model the app already has loaded. It also serves
+/// the model and the Bluetooth scanner to MCP clients at `/mcp`.
+pub struct ApiServer {
+    addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl ApiServer {
+    pub async fn start(service: LlmState, bluetooth: BluetoothState, keys: ApiKeys, config: &ApiServerConfig) -> Result<Self, LlmError> {
+        let cors = cors_layer(&config.cors_origins)?;
+        let auth = AuthState {
+            keys,
+            allow_lan: config.allow_lan,
+        };
+        let mut app = router(service, bluetooth, auth);
+        if let Some(cors) = cors {
+            app = app.layer(cors);
+        }
+
+        let ip = if config.allow_lan { Ipv4Addr::UNSPECIFIED } else { Ipv4Addr::LOCALHOST };
+        let listener = tokio::net::TcpListener::bind((ip, config.port)).await?;
+        let addr = listener.local_addr()?;
+        let (shutdown, shutdown_rx) = oneshot::channel();
+
+        let task = tokio::spawn(async move {
+            let result = axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    let _ = shutdown_rx.await;
+                })
+                .await;
+            if let Err(e) = result {
+                error!("❌ API server failed: {}", e);
+            }
+        });
+
+        if config.allow_lan {
+            warn!("🌐 API server exposed on the local network at {}; API keys are required", addr);
+        } else {
+            info!("🌐 OpenAI-compatible API listening on http://{}", addr);
+        }
+        Ok(Self { addr, shutdown, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// URL for clients on this machine.
+    pub fn base_url(&self) -> String {
+        if self.addr.ip().is_unspecified() {
+            format!("http://{}:{}", Ipv4Addr::LOCALHOST, self.addr.port())
+        } else {
+            format!("http://{}", self.addr)
+        }
+    }
+
+    pub async fn stop(self) {
+        info!("🛑 Stopping API server on {}", self.addr);
+        let _ = self.shutdown.send(());
+
+        let mut task = self.task;
+        if tokio::time::timeout(STOP_TIMEOUT, &mut task).await.is_err() {
+            warn!("⚠️ API server connections still open after {:?}, closing them", STOP_TIMEOUT);
+            task.abort();
+        }
+    }
+}
+
+fn router(service: LlmState, bluetooth: BluetoothState, auth: AuthState) -> Router {
+    Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/completions", post(completions))
+        .route("/v1/models", get(list_models))
+        .route("/v1/embeddings", post(embeddings))
+        .merge(ollama::routes())
+        .merge(mcp_server::routes(service.clone(), bluetooth))
+        .route_layer(middleware::from_fn_with_state(auth, require_api_key))
+        .with_state(service)
+}
+
+fn cors_layer(origins: &[String]) -> Result<Option<CorsLayer>, LlmError> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .map_err(|_| LlmError::ConfigError(format!("Invalid CORS origin: {}", origin)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE]),
+    ))
+}
+
+/// `LlmError` rendered as an OpenAI-style error body.
+struct ApiError(LlmError);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (status_code(&self.0), Json(error_body(&self.0))).into_response()
+    }
+}
+
+pub(crate) fn status_code(error: &LlmError) -> StatusCode {
+    match error {
+        LlmError::NotInitialized | LlmError::NotRunning | LlmError::Cancelled => StatusCode::SERVICE_UNAVAILABLE,
+        LlmError::ContextOverflow(_) | LlmError::ConfigError(_) | LlmError::SerializationError(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl From<LlmError> for ApiError {
+    fn from(error: LlmError) -> Self {
+        ApiError(error)
+    }
+}
+
+fn error_body(error: &LlmError) -> serde_json::Value {
+    let error_type = match error {
+        LlmError::ContextOverflow(_) | LlmError::ConfigError(_) | LlmError::SerializationError(_) => "invalid_request_error",
+        _ => "server_error",
+    };
+    serde_json::json!({
+        "error": {
+            "message": error.to_string(),
+            "type": error_type,
+        }
+    })
+}
+
+async fn chat_completions(State(service): State<LlmState>, Json(request): Json<ChatRequest>) -> Result<Response, ApiError> {
+    info!("🌐 API chat completion request (stream: {})", request.stream.unwrap_or(false));
+    if request.stream.unwrap_or(false) {
+        if request.choice_counts()?.1 > 1 {
+            return Err(LlmError::ConfigError("Streaming returns a single reply; leave out n and best_of".to_string()).into());
+        }
+        return Ok(Sse::new(stream_chat(service, request)).into_response());
+    }
+
+    let service = service.read().await;
+    let response = service.chat_completion(request).await?;
+    Ok(Json(response).into_response())
+}
+
+async fn completions(State(service): State<LlmState>, Json(request): Json<CompletionRequest>) -> Result<Response, ApiError> {
+    info!("🌐 API text completion request (stream: {})", request.stream.unwrap_or(false));
+    if request.stream.unwrap_or(false) {
+        return Ok(Sse::new(stream_completion(service, request)).into_response());
+    }
+
+    let service = service.read().await;
+    let response = service.text_completion(request).await?;
+    Ok(Json(response).into_response())
+}
+
+async fn list_models(State(service): State<LlmState>) -> Result<Json<ModelsResponse>, ApiError> {
+    let service = service.read().await;
+    Ok(Json(service.list_models().await?))
+}
+
+async fn embeddings(State(service): State<LlmState>, Json(request): Json<EmbeddingsRequest>) -> Result<Json<EmbeddingsResponse>, ApiError> {
+    let service = service.read().await;
+    Ok(Json(service.embeddings(request).await?))
+}
+
+pub(crate) enum StreamEvent<R = ChatResponse> {
+    Token(String),
+    /// Part of a thinking model's reasoning.
+    Reasoning(String),
+    Done(Result<R, LlmError>),
+}
+
+/// Run a chat completion in the background, relaying each token and then the
+/// final result. The service is read-locked until generation finishes.
+pub(crate) fn spawn_chat_stream(service: LlmState, request: ChatRequest) -> UnboundedReceiverStream<StreamEvent> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let service = service.read().await;
+        // Once the client has gone there is no one to generate for.
+        let (token_tx, token_cancel) = (tx.clone(), request.cancel.clone());
+        let (reasoning_tx, reasoning_cancel) = (tx.clone(), request.cancel.clone());
+        let result = service
+            .chat_completion_stream(
+                request,
+                &move |token| {
+                    if token_tx.send(StreamEvent::Token(token.to_string())).is_err() {
+                        token_cancel.cancel();
+                    }
+                },
+                &move |reasoning| {
+                    if reasoning_tx.send(StreamEvent::Reasoning(reasoning.to_string())).is_err() {
+                        reasoning_cancel.cancel();
+                    }
+                },
+            )
+            .await;
+        let _ = tx.send(StreamEvent::Done(result));
+    });
+    UnboundedReceiverStream::new(rx)
+}
+
+/// Like `spawn_chat_stream`, for a raw text completion.
+fn spawn_completion_stream(service: LlmState, request: CompletionRequest) -> UnboundedReceiverStream<StreamEvent<CompletionResponse>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let service = service.read().await;
+        let (token_tx, cancel) = (tx.clone(), request.cancel.clone());
+        let result = service
+            .text_completion_stream(request, &move |token| {
+                if token_tx.send(StreamEvent::Token(token.to_string())).is_err() {
+                    cancel.cancel();
+                }
+            })
+            .await;
+        let _ = tx.send(StreamEvent::Done(result));
+    });
+    UnboundedReceiverStream::new(rx)
+}
+
+#[derive(Debug, Serialize)]
+struct ChatChunk {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    choices: Vec<ChunkChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<ChatUsage>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChunkChoice {
+    index: u32,
+    delta: ChunkDelta,
+    /// Sent with the last chunk, for the whole reply.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<ChoiceLogprobs>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ChunkDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ChunkToolCall>>,
+}
+
+/// Streamed tool calls carry their position in the message.
+#[derive(Debug, Serialize)]
+struct ChunkToolCall {
+    index: usize,
+    #[serde(flatten)]
+    call: ToolCall,
+}
+
+/// Relay a completion as OpenAI `chat.completion.chunk` events, ending with `[DONE]`.
+fn stream_chat(service: LlmState, request: ChatRequest) -> impl Stream<Item = Result<Event, Infallible>> {
+    let id = format!("chatcmpl-{}", uuid::Uuid::new_v4());
+    let created = chrono::Utc::now().timestamp() as u64;
+    let model = request.model.clone();
+    let stream = spawn_chat_stream(service, request);
+
+    let chunk = move |delta: ChunkDelta, finish_reason: Option<String>, usage: Option<ChatUsage>| ChatChunk {
+        id: id.clone(),
+        object: "chat.completion.chunk",
+        created,
+        model: model.clone(),
+        choices: vec![ChunkChoice {
+            index: 0,
+            delta,
+            logprobs: None,
+            finish_reason,
+        }],
+        usage,
+    };
+    let role = chunk(
+        ChunkDelta {
+            role: Some("assistant".to_string()),
+            ..ChunkDelta::default()
+        },
+        None,
+        None,
+    );
+
+    let events = stream.map(move |event| match event {
+        StreamEvent::Token(token) => serde_json::to_value(chunk(
+            ChunkDelta {
+                content: Some(token),
+                ..ChunkDelta::default()
+            },
+            None,
+            None,
+        )),
+        StreamEvent::Reasoning(reasoning) => serde_json::to_value(chunk(
+            ChunkDelta {
+                reasoning_content: Some(reasoning),
+                ..ChunkDelta::default()
+            },
+            None,
+            None,
+        )),
+        StreamEvent::Done(Ok(response)) => {
+            debug!("✅ API stream finished");
+            let choice = response.choices.into_iter().next();
+            let finish_reason = choice.as_ref().and_then(|c| c.finish_reason.clone());
+            let logprobs = choice.as_ref().and_then(|c| c.logprobs.clone());
+            // Tool calls are only known once generation is done, so they all come in the last chunk.
+            let tool_calls = choice.and_then(|c| c.message.tool_calls).map(|calls| {
+                calls.into_iter().enumerate().map(|(index, call)| ChunkToolCall { index, call }).collect()
+            });
+            let delta = ChunkDelta {
+                tool_calls,
+                ..ChunkDelta::default()
+            };
+            let mut chunk = chunk(delta, finish_reason, response.usage);
+            chunk.choices[0].logprobs = logprobs;
+            serde_json::to_value(chunk)
+        }
+        StreamEvent::Done(Err(e)) => {
+            error!("❌ API stream failed: {}", e);
+            Ok(error_body(&e))
+        }
+    });
+
+    tokio_stream::once(serde_json::to_value(role))
+        .chain(events)
+        .map(|data| {
+            let data = data.unwrap_or_else(|e| error_body(&e.into()));
+            Ok(Event::default().data(data.to_string()))
+        })
+        .chain(tokio_stream::once(Ok(Event::default().data("[DONE]"))))
+}
+
+/// Relay a text completion as OpenAI `text_completion` events, ending with `[DONE]`.
+fn stream_completion(service: LlmState, request: CompletionRequest) -> impl Stream<Item = Result<Event, Infallible>> {
+    let id = format!("cmpl-{}", uuid::Uuid::new_v4());
+    let created = chrono::Utc::now().timestamp() as u64;
+    let model = request.model.clone();
+    let stream = spawn_completion_stream(service, request);
+
+    let chunk = move |text: String, finish_reason: Option<String>, usage: Option<ChatUsage>| CompletionResponse {
+        id: id.clone(),
+        object: "text_completion".to_string(),
+        created,
+        model: model.clone(),
+        choices: vec![CompletionChoice {
+            index: 0,
+            text,
+            finish_reason,
+        }],
+        usage,
+        provider: None,
+    };
+
+    stream
+        .map(move |event| match event {
+            // Raw completions are never split into reasoning and reply.
+            StreamEvent::Token(text) | StreamEvent::Reasoning(text) => serde_json::to_value(chunk(text, None, None)),
+            StreamEvent::Done(Ok(response)) => {
+                debug!("✅ API completion stream finished");
+                let finish_reason = response.choices.first().and_then(|c| c.finish_reason.clone());
+                serde_json::to_value(chunk(String::new(), finish_reason, response.usage))
+            }
+            StreamEvent::Done(Err(e)) => {
+                error!("❌ API completion stream failed: {}", e);
+                Ok(error_body(&e))
+            }
+        })
+        .map(|data| {
+            let data = data.unwrap_or_else(|e| error_body(&e.into()));
+            Ok(Event::default().data(data.to_string()))
+        })
+        .chain(tokio_stream::once(Ok(Event::default().data("[DONE]"))))
+}
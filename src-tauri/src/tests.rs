@@ -1,16 +1,73 @@
 #[cfg(test)]
 mod tests {
-    use crate::llm::{LlmConfig, LlmService, ChatRequest, ChatMessage};
+    use crate::auth::ApiKeyStore;
+    use crate::llm::{
+        LlmConfig, LlmError, LlmService, ChatRequest, ChatMessage, EmbeddingConfig, EmbeddingInput, EmbeddingPooling,
+        EmbeddingsRequest, EmbeddingsResponse, ProviderConfig, SamplingConfig, SamplingOptions, ChatUsage,
+        CompletionTokensDetails, LoraAdapterConfig,
+    };
+    use crate::mock::{MockProvider, MOCK_BOS};
+    use crate::provider::ChatProvider;
+    use crate::server::{ApiServer, ApiServerConfig};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Accept a single HTTP request on `listener`, answer it with `body` as
+    /// JSON, and return the raw request text.
+    async fn serve_once(listener: TcpListener, body: String) -> String {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(header_end) = text.find("\r\n\r\n") {
+                let content_length = text[..header_end]
+                    .lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                    .unwrap_or(0);
+                if request.len() >= header_end + 4 + content_length {
+                    break;
+                }
+            }
+            if n == 0 {
+                break;
+            }
+        }
+
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&request).to_string()
+    }
+
+    fn openai_config(port: u16) -> LlmConfig {
+        LlmConfig {
+            model_name: "test_model".to_string(),
+            provider: ProviderConfig::OpenAi {
+                host: "http://127.0.0.1".to_string(),
+                port,
+                api_key: Some("secret".to_string()),
+                model: None,
+            },
+            ..LlmConfig::default()
+        }
+    }
 
     #[test]
     fn test_llm_config_default() {
         let config = LlmConfig::default();
-        assert_eq!(config.model_name, "deepseek-r1-distill-qwen-1.5b");
-        assert_eq!(config.host, "http://127.0.0.1");
-        assert_eq!(config.port, 11434);
+        assert_eq!(config.model_name, "Llama-3.2-1B-Instruct-Q5_K_M");
+        assert_eq!(config.provider, ProviderConfig::Local);
         assert_eq!(config.temperature, 0.8);
         assert_eq!(config.top_p, 0.9);
-        assert_eq!(config.max_tokens, -1);
+        assert_eq!(config.max_tokens, 512);
         assert_eq!(config.ctx_size, 4096);
     }
 
@@ -18,12 +75,11 @@ mod tests {
     fn test_llm_service_creation() {
         let config = LlmConfig {
             model_name: "test_model".to_string(),
-            host: "http://127.0.0.1".to_string(),
-            port: 8081,
             temperature: 0.7,
             top_p: 0.8,
             max_tokens: 1000,
             ctx_size: 2048,
+            ..openai_config(8081)
         };
 
         let service = LlmService::new(config.clone());
@@ -43,12 +99,14 @@ mod tests {
                 ChatMessage {
                     role: "user".to_string(),
                     content: "Hello, world!".to_string(),
+                    ..Default::default()
                 },
             ],
             temperature: Some(0.8),
             top_p: Some(0.9),
             max_tokens: Some(100),
             stream: Some(false),
+            ..Default::default()
         };
 
         // Test that the request can be serialized to JSON
@@ -63,16 +121,19 @@ mod tests {
         let user_msg = ChatMessage {
             role: "user".to_string(),
             content: "Test message".to_string(),
+            ..Default::default()
         };
 
         let assistant_msg = ChatMessage {
             role: "assistant".to_string(),
             content: "Test response".to_string(),
+            ..Default::default()
         };
 
         let system_msg = ChatMessage {
             role: "system".to_string(),
             content: "System prompt".to_string(),
+            ..Default::default()
         };
 
         assert_eq!(user_msg.role, "user");
@@ -88,4 +149,1926 @@ mod tests {
         assert!(!service.is_running());
         assert!(!service.get_status().is_running);
     }
+
+    #[test]
+    fn test_llm_config_isolate_inference_defaults_off() {
+        // Configs saved by older frontends don't carry the field.
+        let json = r#"{
+            "model_name": "test_model",
+            "model_path": null,
+            "temperature": 0.7,
+            "top_p": 0.8,
+            "max_tokens": 256,
+            "ctx_size": 2048,
+            "n_threads": null,
+            "n_gpu_layers": 0
+        }"#;
+
+        let config: LlmConfig = serde_json::from_str(json).unwrap();
+        assert!(!config.isolate_inference);
+        assert!(!LlmConfig::default().isolate_inference);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_crashed_worker_is_respawned() {
+        use crate::worker::InferenceWorker;
+
+        // Stands in for the worker: it dies on its first request and answers
+        // once the marker file says it has crashed before.
+        let marker = std::env::temp_dir().join(format!("emchat-worker-{}", uuid::Uuid::new_v4()));
+        let script = r#"
+            while read line; do
+                case "$line" in
+                    *'"type":"start"'*) echo '{"Ok":"ready"}' ;;
+                    *'"parse_special":false'*) echo '{"Ok":[60]}' ;;
+                    *) if [ -e "$0" ]; then echo '{"Ok":[1,2,3]}'; else touch "$0"; kill -9 $$; fi ;;
+                esac
+            done
+        "#;
+        let cancel = crate::llm::CancelToken::default();
+        let mut worker = InferenceWorker::new(LlmConfig::default()).with_command("sh", &["-c", script, marker.to_str().unwrap()]);
+        worker.start(&cancel).await.unwrap();
+
+        let crashed = worker.tokenize("hi", true, true, &cancel).await;
+        assert!(matches!(&crashed, Err(LlmError::WorkerCrashed(reason)) if reason.contains("exited")), "{:?}", crashed);
+        assert_eq!(worker.tokenize("hi", true, true, &cancel).await.unwrap(), vec![1, 2, 3]);
+        // Whether to parse special tokens is passed on to the worker.
+        assert_eq!(worker.tokenize("<", false, false, &cancel).await.unwrap(), vec![60]);
+        std::fs::remove_file(marker).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_worker_streams_tokens_and_reasoning() {
+        use crate::worker::InferenceWorker;
+        use crate::llm::CompletionRequest;
+
+        // Stands in for the worker: it streams its reasoning and reply piece
+        // by piece before writing the whole response.
+        let script = r#"
+            while read -r line; do
+                case "$line" in
+                    *'"type":"start"'*) echo '{"Ok":"ready"}' ;;
+                    *'"type":"chat"'*)
+                        echo '{"Reasoning":"Counting."}'
+                        echo '{"Token":"Three"}'
+                        echo '{"Token":"."}'
+                        echo '{"Ok":{"id":"c","object":"chat.completion","created":0,"model":"m","choices":[{"index":0,"message":{"role":"assistant","content":"Three.","reasoning_content":"Counting."},"finish_reason":"stop"}],"usage":null}}' ;;
+                    *'"type":"completion"'*)
+                        echo '{"Token":"fn "}'
+                        echo '{"Token":"main"}'
+                        echo '{"Ok":{"id":"t","object":"text_completion","created":0,"model":"m","choices":[{"index":0,"text":"fn main","finish_reason":"stop"}],"usage":null}}' ;;
+                esac
+            done
+        "#;
+        let cancel = crate::llm::CancelToken::default();
+        let mut worker = InferenceWorker::new(LlmConfig::default()).with_command("sh", &["-c", script]);
+        worker.start(&cancel).await.unwrap();
+
+        // The reasoning comes back inside its tags, as the model wrote it.
+        let streamed = std::sync::Mutex::new(String::new());
+        let request = ChatRequest {
+            messages: vec![ChatMessage { role: "user".to_string(), content: "How many?".to_string(), ..Default::default() }],
+            ..Default::default()
+        };
+        let response = worker.chat_completion_stream(request, &cancel, &|token| streamed.lock().unwrap().push_str(token)).await.unwrap();
+        assert_eq!(streamed.lock().unwrap().as_str(), "<think>Counting.</think>Three.");
+        assert_eq!(response.choices[0].message.content, "Three.");
+
+        // Reasoning already opened by a partial reply is not opened again.
+        let streamed = std::sync::Mutex::new(String::new());
+        let request = ChatRequest {
+            messages: vec![
+                ChatMessage { role: "user".to_string(), content: "How many?".to_string(), ..Default::default() },
+                ChatMessage { role: "assistant".to_string(), content: "<think>\nLet me see.".to_string(), ..Default::default() },
+            ],
+            ..Default::default()
+        };
+        worker.chat_completion_stream(request, &cancel, &|token| streamed.lock().unwrap().push_str(token)).await.unwrap();
+        assert_eq!(streamed.lock().unwrap().as_str(), "Counting.</think>Three.");
+
+        let streamed = std::sync::Mutex::new(Vec::new());
+        let request: CompletionRequest = serde_json::from_value(serde_json::json!({ "model": "m", "prompt": "" })).unwrap();
+        let response = worker
+            .text_completion_stream(request, &cancel, &|token| streamed.lock().unwrap().push(token.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(*streamed.lock().unwrap(), vec!["fn ", "main"]);
+        assert_eq!(response.choices[0].text, "fn main");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_request_cancel_stops_the_worker() {
+        use crate::worker::InferenceWorker;
+
+        // Stands in for the worker: it never answers a chat request.
+        let script = r#"
+            while read -r line; do
+                case "$line" in
+                    *'"type":"start"'*) echo '{"Ok":"ready"}' ;;
+                    *'"type":"chat"'*) read -r never ;;
+                    *) echo '{"Ok":[1,2,3]}' ;;
+                esac
+            done
+        "#;
+        let cancel = crate::llm::CancelToken::default();
+        let mut worker = InferenceWorker::new(LlmConfig::default()).with_command("sh", &["-c", script]);
+        worker.start(&cancel).await.unwrap();
+
+        let request = ChatRequest::default();
+        let request_cancel = request.cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            request_cancel.cancel();
+        });
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), worker.chat_completion(request, &cancel)).await;
+        assert!(matches!(result, Ok(Err(LlmError::Cancelled))), "{:?}", result);
+        // The next request gets a fresh worker.
+        assert_eq!(worker.tokenize("hi", true, true, &cancel).await.unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_speculative_decoding_config_and_usage() {
+        assert_eq!(LlmConfig::default().speculative.draft_model, None);
+        let config: LlmConfig = serde_json::from_value(serde_json::json!({
+            "model_name": "qwen2.5-3b-instruct-q4_k_m",
+            "model_path": null,
+            "temperature": 0.7,
+            "top_p": 0.8,
+            "max_tokens": 256,
+            "ctx_size": 2048,
+            "n_threads": null,
+            "n_gpu_layers": 0,
+            "speculative": { "draft_model": "qwen2.5-0.5b-instruct-q8_0" }
+        }))
+        .unwrap();
+        assert_eq!(config.speculative.draft_model.as_deref(), Some("qwen2.5-0.5b-instruct-q8_0"));
+        assert_eq!(config.speculative.draft_tokens, 8);
+
+        let usage = ChatUsage {
+            prompt_tokens: 10,
+            completion_tokens: 20,
+            total_tokens: 30,
+            completion_tokens_details: None,
+        };
+        assert!(serde_json::to_value(&usage).unwrap().get("completion_tokens_details").is_none());
+        let details = CompletionTokensDetails { accepted_prediction_tokens: 15, rejected_prediction_tokens: 5 };
+        let usage = serde_json::to_value(ChatUsage { completion_tokens_details: Some(details), ..usage }).unwrap();
+        assert_eq!(usage["completion_tokens_details"]["accepted_prediction_tokens"], 15);
+    }
+
+    #[test]
+    fn test_provider_config_serialization() {
+        let config: ProviderConfig = serde_json::from_str(
+            r#"{"type": "openai", "host": "http://localhost", "port": 11434}"#,
+        ).unwrap();
+
+        assert_eq!(config, ProviderConfig::OpenAi {
+            host: "http://localhost".to_string(),
+            port: 11434,
+            api_key: None,
+            model: None,
+        });
+        assert_eq!(config.base_url(), "http://localhost:11434");
+        assert_eq!(ProviderConfig::Local.base_url(), "local");
+    }
+
+    #[tokio::test]
+    async fn test_openai_provider_chat_completion() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let reply = serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "test_model",
+            "system_fingerprint": "ignored",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Hi from the server" },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 5, "completion_tokens": 4, "total_tokens": 9 }
+        });
+        let server = tokio::spawn(serve_once(listener, reply.to_string()));
+
+        let mut service = LlmService::new(openai_config(port));
+        service.start().await.unwrap();
+        assert!(service.is_running());
+
+        let response = service.chat_completion(ChatRequest {
+            model: "test_model".to_string(),
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content: "Hello".to_string(),
+                ..Default::default()
+            }],
+            max_tokens: Some(16),
+            ..Default::default()
+        }).await.unwrap();
+
+        assert_eq!(response.choices[0].message.content, "Hi from the server");
+        assert_eq!(response.usage.unwrap().total_tokens, 9);
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /v1/chat/completions"));
+        assert!(request.to_ascii_lowercase().contains("authorization: bearer secret"));
+        assert!(request.contains("\"stream\":false"));
+        assert!(!request.contains("temperature"));
+    }
+
+    #[tokio::test]
+    async fn test_openai_provider_reports_http_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let mut service = LlmService::new(openai_config(port));
+        service.start().await.unwrap();
+
+        let result = service.chat_completion(ChatRequest {
+            model: "test_model".to_string(),
+            messages: vec![],
+            ..Default::default()
+        }).await;
+
+        assert!(matches!(result, Err(LlmError::HttpError(_))));
+    }
+
+    fn chat_reply(content: &str) -> String {
+        serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1700000000,
+            "model": "test_model",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": "stop"
+            }],
+            "usage": null
+        }).to_string()
+    }
+
+    fn hello_request() -> ChatRequest {
+        ChatRequest {
+            model: "test_model".to_string(),
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content: "Hello".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fallback_provider_answers_when_primary_fails() {
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_port = dead.local_addr().unwrap().port();
+        drop(dead);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_once(listener, chat_reply("from fallback")));
+
+        let fallback = openai_config(port).provider;
+        let mut service = LlmService::new(LlmConfig {
+            fallback_providers: vec![fallback.clone()],
+            ..openai_config(dead_port)
+        });
+        service.start().await.unwrap();
+
+        let response = service.chat_completion(hello_request()).await.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(response.choices[0].message.content, "from fallback");
+        assert_eq!(response.provider, Some(format!("openai ({})", fallback.base_url())));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_used_when_local_model_cannot_load() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_once(listener, chat_reply("remote answer")));
+
+        let mut service = LlmService::new(LlmConfig {
+            model_name: "model-that-does-not-exist".to_string(),
+            provider: ProviderConfig::Local,
+            fallback_providers: vec![openai_config(port).provider],
+            ..LlmConfig::default()
+        });
+        let msg = service.start().await.unwrap();
+        assert!(!msg.contains("local"));
+
+        let response = service.chat_completion(hello_request()).await.unwrap();
+        server.await.unwrap();
+        assert_eq!(response.choices[0].message.content, "remote answer");
+
+        // Starting again retries the local model instead of leaving it out for good.
+        let retried = service.start().await.unwrap();
+        assert!(!retried.contains("already initialized"), "{}", retried);
+        assert!(service.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_start_fails_when_no_provider_starts() {
+        let mut service = LlmService::new(LlmConfig {
+            model_name: "model-that-does-not-exist".to_string(),
+            ..LlmConfig::default()
+        });
+
+        assert!(service.start().await.is_err());
+        assert!(!service.is_running());
+    }
+
+    /// A running service whose only providers are the given mocks, all sharing
+    /// the service's cancel token.
+    fn mock_service(build: impl FnOnce(&crate::llm::CancelToken) -> Vec<MockProvider>) -> (LlmService, Vec<Arc<MockProvider>>) {
+        let service = LlmService::new(LlmConfig::default());
+        let mocks: Vec<Arc<MockProvider>> = build(&service.cancel_token()).into_iter().map(Arc::new).collect();
+        let providers = mocks.iter().map(|m| Box::new(m.clone()) as Box<dyn ChatProvider>).collect();
+        (service.with_providers(providers), mocks)
+    }
+
+    #[tokio::test]
+    async fn test_mock_provider_scripted_replies() {
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone()).reply("first answer").reply("second answer"),
+        ]);
+
+        let first = service.chat_completion(hello_request()).await.unwrap();
+        let second = service.chat_completion(hello_request()).await.unwrap();
+
+        assert_eq!(first.choices[0].message.content, "first answer");
+        assert_eq!(first.choices[0].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(first.usage.as_ref().unwrap().completion_tokens, 2);
+        assert_eq!(first.provider.as_deref(), Some("mock"));
+        assert_eq!(second.choices[0].message.content, "second answer");
+        assert_eq!(mocks[0].requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_tokens_stops_with_length() {
+        let (service, _) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone()).reply("one two three four five"),
+        ]);
+
+        let response = service.chat_completion(ChatRequest {
+            max_tokens: Some(2),
+            ..hello_request()
+        }).await.unwrap();
+
+        assert_eq!(response.choices[0].message.content, "one two ");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("length"));
+    }
+
+    #[tokio::test]
+    async fn test_context_overflow_is_reported() {
+        let (service, _) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone()).with_ctx_size(3).reply("unused"),
+        ]);
+
+        let result = service.chat_completion(ChatRequest {
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content: "this prompt is far too long".to_string(),
+                ..Default::default()
+            }],
+            ..hello_request()
+        }).await;
+
+        assert!(matches!(result, Err(LlmError::ContextOverflow(_))));
+    }
+
+    #[tokio::test]
+    async fn test_forced_error_falls_back_to_next_provider() {
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone()).with_name("primary").fail(LlmError::ModelError("boom".to_string())),
+            MockProvider::new(cancel.clone()).with_name("backup").reply("backup answer"),
+        ]);
+
+        let response = service.chat_completion(hello_request()).await.unwrap();
+
+        assert_eq!(response.choices[0].message.content, "backup answer");
+        assert_eq!(response.provider.as_deref(), Some("backup"));
+        assert_eq!(mocks[0].requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_falls_back_to_next_provider() {
+        let (service, _) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone()).with_name("primary").fail(LlmError::ModelError("boom".to_string())),
+            MockProvider::new(cancel.clone()).with_name("backup").reply("upon a time"),
+        ]);
+
+        let request = serde_json::from_value(serde_json::json!({ "model": "local", "prompt": "Once" })).unwrap();
+        let response = service.text_completion(request).await.unwrap();
+
+        assert_eq!(response.choices[0].text, "upon a time");
+        assert_eq!(response.provider.as_deref(), Some("backup"));
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_stops_generation_and_fallback() {
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone())
+                .with_token_delay(Duration::from_millis(20))
+                .reply(&"word ".repeat(100)),
+            MockProvider::new(cancel.clone()).reply("should not be used"),
+        ]);
+        let cancel_token = service.cancel_token();
+
+        let handle = tokio::spawn(async move { service.chat_completion(hello_request()).await });
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        cancel_token.cancel();
+
+        let result = tokio::time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap();
+        assert!(matches!(result, Err(LlmError::Cancelled)));
+        assert!(mocks[1].requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_cancels_a_running_generation_in_time() {
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone())
+                .with_token_delay(Duration::from_millis(50))
+                .reply(&"word ".repeat(1000)),
+        ]);
+        let cancel_token = service.cancel_token();
+        let service = Arc::new(tokio::sync::RwLock::new(service));
+        let generation = tokio::spawn({
+            let service = service.clone();
+            async move { service.read().await.chat_completion(hello_request()).await }
+        });
+        while !mocks[0].is_busy() {
+            tokio::task::yield_now().await;
+        }
+
+        // The reply would take 50 s; exit waits for the service's write lock.
+        let started = std::time::Instant::now();
+        let shutdown = async {
+            crate::shutdown_services(&cancel_token, &bluetooth_state(), &Default::default(), &service, &Default::default()).await
+        };
+        tokio::time::timeout(crate::SHUTDOWN_TIMEOUT, shutdown).await.expect("shutdown took too long");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(matches!(generation.await.unwrap(), Err(LlmError::Cancelled)));
+        assert!(!service.read().await.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_stream_stops_its_generation() {
+        use tokio_stream::StreamExt;
+
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone())
+                .with_token_delay(Duration::from_millis(20))
+                .reply(&"word ".repeat(100))
+                .reply("still here"),
+        ]);
+        let service = Arc::new(tokio::sync::RwLock::new(service));
+
+        let mut stream = crate::server::spawn_chat_stream(service.clone(), hello_request());
+        assert!(stream.next().await.is_some());
+        assert!(mocks[0].is_busy());
+        drop(stream);
+
+        // The next token finds no reader, long before the reply would end.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!mocks[0].is_busy());
+        // Only that request was stopped.
+        let response = service.read().await.chat_completion(hello_request()).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "still here");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_queue_on_busy_provider() {
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone())
+                .with_token_delay(Duration::from_millis(5))
+                .reply("one two three")
+                .reply("four five six"),
+        ]);
+        // Same shape as the managed state commands use.
+        let service = Arc::new(tokio::sync::RwLock::new(service));
+
+        let tasks: Vec<_> = (0..2).map(|i| {
+            let service = service.clone();
+            tokio::spawn(async move {
+                let request = ChatRequest { model: format!("request-{}", i), ..hello_request() };
+                service.read().await.chat_completion(request).await
+            })
+        }).collect();
+
+        let mut contents = Vec::new();
+        for task in tasks {
+            contents.push(task.await.unwrap().unwrap().choices[0].message.content.clone());
+        }
+        contents.sort();
+
+        assert_eq!(contents, vec!["four five six", "one two three"]);
+        assert_eq!(mocks[0].requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_busy_provider_is_passed_over_for_the_next() {
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone())
+                .with_name("local")
+                .with_token_delay(Duration::from_millis(20))
+                .reply("one two three")
+                .reply("four five six"),
+            MockProvider::new(cancel.clone()).with_name("remote").reply("from remote"),
+        ]);
+        let service = Arc::new(tokio::sync::RwLock::new(service));
+
+        let first = tokio::spawn({
+            let service = service.clone();
+            async move { service.read().await.chat_completion(hello_request()).await }
+        });
+        while mocks[0].requests().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let second = service.read().await.chat_completion(hello_request()).await.unwrap();
+
+        assert_eq!(second.provider.as_deref(), Some("remote"));
+        assert_eq!(first.await.unwrap().unwrap().provider.as_deref(), Some("local"));
+        assert_eq!(mocks[1].requests().len(), 1);
+    }
+
+    async fn start_api_server(service: LlmService) -> ApiServer {
+        let service = Arc::new(tokio::sync::RwLock::new(service));
+        let config = ApiServerConfig { port: 0, ..ApiServerConfig::default() };
+        ApiServer::start(service, bluetooth_state(), ApiKeyStore::default().shared(), &config).await.unwrap()
+    }
+
+    fn bluetooth_state() -> crate::BluetoothState {
+        Arc::new(tokio::sync::Mutex::new(crate::bluetooth::BluetoothScanner::new()))
+    }
+
+    #[tokio::test]
+    async fn test_api_server_chat_completion() {
+        let (service, _mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone()).reply("Hello there")]);
+        let server = start_api_server(service).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/v1/chat/completions", server.base_url()))
+            .json(&hello_request())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello there");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+
+        let response = reqwest::get(format!("{}/v1/models", server.base_url())).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["object"], "list");
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_api_server_streams_chat_completion() {
+        let (service, _mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone()).reply("one two three")]);
+        let server = start_api_server(service).await;
+
+        let request = ChatRequest { stream: Some(true), ..hello_request() };
+        let response = reqwest::Client::new()
+            .post(format!("{}/v1/chat/completions", server.base_url()))
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+        let body = response.text().await.unwrap();
+        let events: Vec<&str> = body.lines().filter_map(|line| line.strip_prefix("data: ")).collect();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+
+        let chunks: Vec<serde_json::Value> = events[..events.len() - 1]
+            .iter()
+            .map(|event| serde_json::from_str(event).unwrap())
+            .collect();
+        assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+        let content: Vec<&str> = chunks.iter().filter_map(|c| c["choices"][0]["delta"]["content"].as_str()).collect();
+        assert_eq!(content, vec!["one ", "two ", "three"]);
+        let last = chunks.last().unwrap();
+        assert_eq!(last["object"], "chat.completion.chunk");
+        assert_eq!(last["choices"][0]["finish_reason"], "stop");
+        assert_eq!(last["usage"]["completion_tokens"], 3);
+
+        server.stop().await;
+    }
+
+    #[test]
+    fn test_reasoning_filter_splits_streamed_think_tags() {
+        use crate::reasoning::ReasoningFilter;
+
+        let pieces = ["\n<th", "ink>\n", "Two plus", " two.\n</", "think>", "\n\n", "It is 4", "."];
+        let mut filter = ReasoningFilter::default();
+        let (mut reasoning, mut content) = (String::new(), String::new());
+        for piece in pieces {
+            let (r, c) = filter.push(piece);
+            reasoning.push_str(&r);
+            content.push_str(&c);
+        }
+        let (r, c) = filter.finish();
+        reasoning.push_str(&r);
+        content.push_str(&c);
+        assert_eq!((reasoning.as_str(), content.as_str()), ("Two plus two.", "It is 4."));
+
+        let mut filter = ReasoningFilter::default();
+        assert_eq!(filter.push("<b>bold</b>"), (String::new(), "<b>bold</b>".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_reasoning_content_is_split_from_reply() {
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone())
+                .reply("<think> The capital of France. </think> Paris")
+                .reply("<think> Still Paris. </think> Paris again"),
+        ]);
+        let server = start_api_server(service).await;
+        let url = format!("{}/v1/chat/completions", server.base_url());
+        let client = reqwest::Client::new();
+
+        let body: serde_json::Value = client.post(&url).json(&hello_request()).send().await.unwrap().json().await.unwrap();
+        let message = &body["choices"][0]["message"];
+        assert_eq!(message["content"], "Paris");
+        assert_eq!(message["reasoning_content"], "The capital of France.");
+
+        let mut request = ChatRequest { stream: Some(true), strip_reasoning: Some(true), ..hello_request() };
+        request.messages.push(serde_json::from_value(message.clone()).unwrap());
+        request.messages.push(ChatMessage { content: "Sure?".to_string(), ..request.messages[0].clone() });
+        let body = client.post(&url).json(&request).send().await.unwrap().text().await.unwrap();
+        let deltas: Vec<serde_json::Value> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|event| serde_json::from_str::<serde_json::Value>(event).ok())
+            .map(|chunk| chunk["choices"][0]["delta"].clone())
+            .collect();
+        let text = |field: &str| deltas.iter().filter_map(|d| d[field].as_str()).collect::<String>();
+        assert_eq!(text("reasoning_content"), "Still Paris.");
+        assert_eq!(text("content"), "Paris again");
+
+        // The earlier reasoning was not sent back to the model.
+        let history = &mocks[0].requests()[1].messages[1];
+        assert_eq!((history.content.as_str(), history.reasoning_content.as_ref()), ("Paris", None));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_trailing_assistant_message_is_continued() {
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone()).reply("the capital. </think> Paris").reply(", of course."),
+        ]);
+        let assistant = |content: &str, reasoning: Option<&str>| ChatMessage {
+            role: "assistant".to_string(),
+            content: content.to_string(),
+            reasoning_content: reasoning.map(str::to_string),
+            ..Default::default()
+        };
+
+        // Reasoning cut off by a `length` stop is continued where it ended.
+        let mut request = hello_request();
+        request.messages.push(assistant("", Some("France has")));
+        let response = service.chat_completion(request).await.unwrap();
+        assert_eq!(mocks[0].requests()[0].messages[1].content, "<think>\nFrance has");
+        let message = &response.choices[0].message;
+        assert_eq!((message.content.as_str(), message.reasoning_content.as_deref()), ("Paris", Some("the capital.")));
+
+        // Opening words steer the reply; only the rest comes back.
+        let mut request = ChatRequest { strip_reasoning: Some(true), ..hello_request() };
+        request.messages.push(assistant("Paris", Some("the capital.")));
+        let response = service.chat_completion(request).await.unwrap();
+        assert_eq!(mocks[0].requests()[1].messages[1].content, "<think>\nthe capital.\n</think>\n\nParis");
+        assert_eq!(response.choices[0].message.content, ", of course.");
+        assert_eq!(response.choices[0].message.reasoning_content, None);
+    }
+
+    #[tokio::test]
+    async fn test_n_choices_and_best_of() {
+        let (service, _mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone()).reply("one").reply("two").reply("three").reply("four").reply("five"),
+        ]);
+
+        let response = service.chat_completion(ChatRequest { n: Some(3), ..hello_request() }).await.unwrap();
+        let replies: Vec<(u32, &str)> = response.choices.iter().map(|c| (c.index, c.message.content.as_str())).collect();
+        assert_eq!(replies, vec![(0, "one"), (1, "two"), (2, "three")]);
+        assert_eq!(response.usage.unwrap().completion_tokens, 3);
+
+        let response = service.chat_completion(ChatRequest { best_of: Some(2), ..hello_request() }).await.unwrap();
+        assert_eq!(response.choices.len(), 1);
+
+        for (n, best_of) in [(Some(0), None), (Some(2), Some(1)), (Some(17), None)] {
+            let result = service.chat_completion(ChatRequest { n, best_of, ..hello_request() }).await;
+            assert!(matches!(result, Err(LlmError::ConfigError(_))), "n={:?} best_of={:?}", n, best_of);
+        }
+
+        let server = start_api_server(service).await;
+        let response = reqwest::Client::new()
+            .post(format!("{}/v1/chat/completions", server.base_url()))
+            .json(&ChatRequest { stream: Some(true), n: Some(2), ..hello_request() })
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_logprobs_are_returned_per_token() {
+        let (service, _mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone()).reply("Hello there").reply("Hello again").reply("unused"),
+        ]);
+
+        let response = service.chat_completion(hello_request()).await.unwrap();
+        assert!(response.choices[0].logprobs.is_none());
+
+        let request = ChatRequest { logprobs: Some(true), top_logprobs: Some(1), ..hello_request() };
+        let response = service.chat_completion(request).await.unwrap();
+        let logprobs = &response.choices[0].logprobs.as_ref().unwrap().content;
+        let tokens: Vec<&str> = logprobs.iter().map(|l| l.token.as_str()).collect();
+        assert_eq!(tokens, vec!["Hello ", "again"]);
+        assert_eq!(logprobs[1].bytes, b"again");
+        assert_eq!(logprobs[1].top_logprobs.len(), 1);
+
+        for (logprobs, top_logprobs) in [(None, Some(2)), (Some(true), Some(21))] {
+            let result = service.chat_completion(ChatRequest { logprobs, top_logprobs, ..hello_request() }).await;
+            assert!(matches!(result, Err(LlmError::ConfigError(_))), "top_logprobs={:?}", top_logprobs);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sampling_options_override_config() {
+        let request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "model": "test-model",
+            "messages": [],
+            "repeat_penalty": 1.1,
+            "mirostat": 2,
+            "logit_bias": { "15043": -100.0, "Hello": 5.0 },
+        }))
+        .unwrap();
+        let config = SamplingConfig { repeat_last_n: 128, dry_multiplier: 0.8, ..SamplingConfig::default() };
+        let sampling = request.sampling.over(&config);
+        assert_eq!((sampling.repeat_penalty, sampling.repeat_last_n, sampling.mirostat), (1.1, 128, 2));
+        assert_eq!((sampling.dry_multiplier, sampling.logit_bias["Hello"]), (0.8, 5.0));
+        assert_eq!(serde_json::to_value(&request).unwrap()["mirostat"], 2);
+        assert!(sampling.validate().is_ok());
+
+        let (service, _mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone()).reply("unused")]);
+        let sampling = SamplingOptions { mirostat: Some(3), ..SamplingOptions::default() };
+        let result = service.chat_completion(ChatRequest { sampling, ..hello_request() }).await;
+        assert!(matches!(result, Err(LlmError::ConfigError(_))));
+
+        let sampling = SamplingOptions { dry_sequence_breakers: Some(vec!["\n".to_string(), "a\0b".to_string()]), ..SamplingOptions::default() };
+        let result = service.chat_completion(ChatRequest { sampling, ..hello_request() }).await;
+        assert!(matches!(result, Err(LlmError::ConfigError(message)) if message.contains("NUL")));
+
+        // Text completions take the same options.
+        let request: crate::llm::CompletionRequest = serde_json::from_value(serde_json::json!({
+            "model": "test-model",
+            "prompt": "Once",
+            "mirostat": 3,
+        }))
+        .unwrap();
+        assert_eq!(request.sampling.mirostat, Some(3));
+        assert!(matches!(service.text_completion(request).await, Err(LlmError::ConfigError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_api_server_text_completion() {
+        let (service, _mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone()).reply("upon a time").reply("there was a"),
+        ]);
+        let server = start_api_server(service).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post(format!("{}/v1/completions", server.base_url()))
+            .json(&serde_json::json!({ "model": "test-model", "prompt": "Once", "max_tokens": 2 }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["object"], "text_completion");
+        assert_eq!(body["choices"][0]["text"], "upon a ");
+        assert_eq!(body["choices"][0]["finish_reason"], "length");
+        assert_eq!(body["usage"]["prompt_tokens"], 1);
+
+        let response = client
+            .post(format!("{}/v1/completions", server.base_url()))
+            .json(&serde_json::json!({ "model": "test-model", "prompt": "Once upon a time", "stream": true }))
+            .send()
+            .await
+            .unwrap();
+        let body = response.text().await.unwrap();
+        let events: Vec<&str> = body.lines().filter_map(|line| line.strip_prefix("data: ")).collect();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+        let chunks: Vec<serde_json::Value> = events[..events.len() - 1]
+            .iter()
+            .map(|event| serde_json::from_str(event).unwrap())
+            .collect();
+        let text: String = chunks.iter().filter_map(|c| c["choices"][0]["text"].as_str()).collect();
+        assert_eq!(text, "there was a");
+        assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "stop");
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_api_server_embeddings() {
+        let (service, _mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone())]);
+        let server = start_api_server(service).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/v1/embeddings", server.base_url()))
+            .json(&serde_json::json!({ "model": "test-model", "input": ["hello world", "hi"], "normalize": false }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body: EmbeddingsResponse = response.json().await.unwrap();
+        assert_eq!(body.data.len(), 2);
+        assert_eq!(body.data[0].embedding, vec![2.0, 11.0]);
+        assert_eq!(body.data[1].index, 1);
+        assert_eq!(body.data[1].tokens, 1);
+        assert_eq!(body.usage.prompt_tokens, 3);
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_api_server_reports_service_not_running() {
+        let server = start_api_server(LlmService::new(LlmConfig::default())).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/v1/chat/completions", server.base_url()))
+            .json(&hello_request())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["message"], "Service not running");
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_ollama_chat_streams_ndjson() {
+        let (service, _mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone()).reply("one two").reply("whole reply"),
+        ]);
+        let server = start_api_server(service).await;
+        let client = reqwest::Client::new();
+        let body = serde_json::json!({
+            "model": "test-model",
+            "messages": [{ "role": "user", "content": "Hello" }],
+            "options": { "num_predict": 16 },
+        });
+
+        // Ollama streams unless told otherwise.
+        let response = client.post(format!("{}/api/chat", server.base_url())).json(&body).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+        let text = response.text().await.unwrap();
+        let lines: Vec<serde_json::Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let content: Vec<&str> = lines.iter().filter_map(|l| l["message"]["content"].as_str()).collect();
+        assert_eq!(content.concat(), "one two");
+        assert!(lines[..lines.len() - 1].iter().all(|l| l["done"] == false));
+        let last = lines.last().unwrap();
+        assert_eq!(last["done"], true);
+        assert_eq!(last["done_reason"], "stop");
+        assert_eq!(last["eval_count"], 2);
+
+        let mut body = body;
+        body["stream"] = false.into();
+        let response = client.post(format!("{}/api/chat", server.base_url())).json(&body).send().await.unwrap();
+        let reply: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(reply["message"]["role"], "assistant");
+        assert_eq!(reply["message"]["content"], "whole reply");
+        assert_eq!(reply["done"], true);
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_ollama_generate_maps_prompt_and_system() {
+        let (service, mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone()).reply("Paris")]);
+        let server = start_api_server(service).await;
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/api/generate", server.base_url()))
+            .json(&serde_json::json!({
+                "model": "test-model",
+                "prompt": "Capital of France?",
+                "system": "Answer in one word.",
+                "stream": false,
+                "options": { "repeat_penalty": 1.2, "mirostat": 2 },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let reply: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(reply["response"], "Paris");
+        assert!(reply.get("message").is_none());
+
+        let request = &mocks[0].requests()[0];
+        assert_eq!(request.messages[0].role, "system");
+        assert_eq!(request.messages[0].content, "Answer in one word.");
+        assert_eq!(request.messages[1].role, "user");
+        assert_eq!(request.messages[1].content, "Capital of France?");
+        assert_eq!((request.sampling.repeat_penalty, request.sampling.mirostat), (Some(1.2), Some(2)));
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_ollama_tags_and_show() {
+        let server = start_api_server(LlmService::new(LlmConfig::default())).await;
+        let client = reqwest::Client::new();
+
+        let response = client.get(format!("{}/api/tags", server.base_url())).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let tags: serde_json::Value = response.json().await.unwrap();
+        assert!(tags["models"].is_array());
+
+        let response = client
+            .post(format!("{}/api/show", server.base_url()))
+            .json(&serde_json::json!({ "model": "no-such-model" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"], "model 'no-such-model' not found");
+
+        // Files outside the models directory are not models.
+        let response = client
+            .post(format!("{}/api/show", server.base_url()))
+            .json(&serde_json::json!({ "model": "../Cargo.toml" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_api_server_requires_key_once_one_exists() {
+        let (service, _mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone())]);
+        let keys = ApiKeyStore::default().shared();
+        let config = ApiServerConfig { port: 0, ..ApiServerConfig::default() };
+        let server = ApiServer::start(Arc::new(tokio::sync::RwLock::new(service)), bluetooth_state(), keys.clone(), &config).await.unwrap();
+        let client = reqwest::Client::new();
+        let models = format!("{}/v1/models", server.base_url());
+
+        // Loopback with no keys configured stays open.
+        assert_eq!(client.get(&models).send().await.unwrap().status(), 200);
+
+        let new_key = keys.lock().unwrap().create("laptop").unwrap();
+        assert!(new_key.key.starts_with(&new_key.info.prefix));
+
+        let response = client.get(&models).send().await.unwrap();
+        assert_eq!(response.status(), 401);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["message"], "Invalid or missing API key");
+
+        let response = client.get(&models).bearer_auth("emchat-wrong").send().await.unwrap();
+        assert_eq!(response.status(), 401);
+
+        let response = client.get(&models).bearer_auth(&new_key.key).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(keys.lock().unwrap().list()[0].request_count, 1);
+
+        // Ollama routes answer in Ollama's error shape.
+        let response = client.get(format!("{}/api/tags", server.base_url())).send().await.unwrap();
+        assert_eq!(response.status(), 401);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"], "Invalid or missing API key");
+
+        keys.lock().unwrap().revoke(&new_key.info.id).unwrap();
+        let response = client.get(&models).bearer_auth(&new_key.key).send().await.unwrap();
+        assert_eq!(response.status(), 200, "no keys left, loopback is open again");
+
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_api_server_on_lan_always_requires_key() {
+        let config = ApiServerConfig { port: 0, allow_lan: true, cors_origins: vec!["http://localhost:5173".to_string()] };
+        let keys = ApiKeyStore::default().shared();
+        let server = ApiServer::start(Arc::new(tokio::sync::RwLock::new(LlmService::new(LlmConfig::default()))), bluetooth_state(), keys.clone(), &config)
+            .await
+            .unwrap();
+        assert!(server.addr().ip().is_unspecified());
+        assert!(server.base_url().starts_with("http://127.0.0.1:"));
+        let client = reqwest::Client::new();
+        let models = format!("{}/v1/models", server.base_url());
+
+        assert_eq!(client.get(&models).send().await.unwrap().status(), 401);
+
+        // Preflight requests are answered by the CORS layer without a key.
+        let response = client
+            .request(reqwest::Method::OPTIONS, &models)
+            .header("Origin", "http://localhost:5173")
+            .header("Access-Control-Request-Method", "GET")
+            .header("Access-Control-Request-Headers", "authorization")
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(response.headers()["access-control-allow-origin"], "http://localhost:5173");
+
+        let key = keys.lock().unwrap().create("teammate").unwrap().key;
+        let response = client.get(&models).bearer_auth(&key).header("Origin", "http://localhost:5173").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["access-control-allow-origin"], "http://localhost:5173");
+
+        server.stop().await;
+    }
+
+    #[test]
+    fn test_api_keys_persist_hashed() {
+        let path = std::env::temp_dir().join(format!("emchat-keys-{}", uuid::Uuid::new_v4())).join("api_keys.json");
+        let mut store = ApiKeyStore::load(path.clone());
+        let kept = store.create("kept").unwrap();
+        let revoked = store.create("revoked").unwrap();
+        store.revoke(&revoked.info.id).unwrap();
+        assert!(store.revoke(&revoked.info.id).is_err());
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains(&kept.key), "keys must not be stored in the clear");
+
+        let reloaded = ApiKeyStore::load(path.clone());
+        let names: Vec<String> = reloaded.list().into_iter().map(|k| k.name).collect();
+        assert_eq!(names, vec!["kept"]);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_normalized_by_default() {
+        let (service, _mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone())]);
+        let request = EmbeddingsRequest {
+            model: service.embedding_model_name().to_string(),
+            input: EmbeddingInput::Batch(vec!["three four".to_string()]),
+            pooling: None,
+            normalize: None,
+        };
+
+        // [words, chars] = [2, 10], scaled to unit length.
+        let response = service.embeddings(request.clone()).await.unwrap();
+        let embedding = &response.data[0].embedding;
+        assert!((embedding[0] - 2.0 / 104f32.sqrt()).abs() < 1e-6);
+        assert!((embedding.iter().map(|x| x * x).sum::<f32>() - 1.0).abs() < 1e-6);
+        assert_eq!(response.data[0].tokens, 2);
+        assert_eq!(response.model, LlmConfig::default().model_name);
+
+        let raw = service.embeddings(EmbeddingsRequest { normalize: Some(false), ..request }).await.unwrap();
+        assert_eq!(raw.data[0].embedding, vec![2.0, 10.0]);
+    }
+
+    #[test]
+    fn test_embedding_config_defaults() {
+        let config: LlmConfig = serde_json::from_value(serde_json::json!({
+            "model_name": "chat",
+            "temperature": 0.8,
+            "top_p": 0.9,
+            "max_tokens": 512,
+            "ctx_size": 4096,
+            "n_gpu_layers": 0,
+        }))
+        .unwrap();
+        assert_eq!(config.embeddings.model, None);
+        assert_eq!(config.embeddings.pooling, EmbeddingPooling::Mean);
+        assert!(config.embeddings.normalize);
+
+        let config: EmbeddingConfig = serde_json::from_value(serde_json::json!({ "model": "nomic-embed", "pooling": "cls" })).unwrap();
+        assert_eq!(config.model.as_deref(), Some("nomic-embed"));
+        assert_eq!(config.pooling, EmbeddingPooling::Cls);
+        assert!(config.normalize);
+
+        let service = LlmService::new(LlmConfig { embeddings: config, ..LlmConfig::default() });
+        assert_eq!(service.embedding_model_name(), "nomic-embed");
+    }
+
+    #[tokio::test]
+    async fn test_tokenize_detokenize_and_count() {
+        let (service, _mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone())]);
+
+        let tokens = service.tokenize("hi", true, true).await.unwrap();
+        assert_eq!(tokens, vec![MOCK_BOS, 104, 105]);
+        assert_eq!(service.tokenize("hi", false, true).await.unwrap(), vec![104, 105]);
+        // Special-token text is the token only when asked for.
+        assert_eq!(service.tokenize("<s>hi", false, true).await.unwrap(), vec![MOCK_BOS, 104, 105]);
+        assert_eq!(service.tokenize("<s>hi", false, false).await.unwrap(), vec![60, 115, 62, 104, 105]);
+
+        assert_eq!(service.detokenize(&tokens, false).await.unwrap(), "hi");
+        assert_eq!(service.detokenize(&tokens, true).await.unwrap(), "<s>hi");
+        assert!(matches!(service.detokenize(&[-1], false).await, Err(LlmError::ConfigError(_))));
+
+        let count = service.count_tokens(&hello_request()).await.unwrap();
+        assert_eq!(count.prompt_tokens, 1);
+        assert_eq!(count.context_size, LlmConfig::default().ctx_size);
+
+        // Earlier reasoning counts only while it is sent back to the model.
+        let mut request = hello_request();
+        request.messages.push(ChatMessage {
+            role: "assistant".to_string(),
+            content: "Paris".to_string(),
+            reasoning_content: Some("The capital of France.".to_string()),
+            ..request.messages[0].clone()
+        });
+        request.messages.push(ChatMessage { content: "Sure?".to_string(), ..request.messages[0].clone() });
+        assert_eq!(service.count_tokens(&request).await.unwrap().prompt_tokens, 9);
+        let request = ChatRequest { strip_reasoning: Some(true), ..request };
+        assert_eq!(service.count_tokens(&request).await.unwrap().prompt_tokens, 3);
+    }
+
+    #[tokio::test]
+    async fn test_tokenize_needs_a_running_local_model() {
+        let service = LlmService::new(LlmConfig::default());
+        assert!(matches!(service.tokenize("hi", true, true).await, Err(LlmError::NotRunning)));
+
+        // Remote providers have no vocabulary to expose.
+        let remote = crate::openai::OpenAiProvider::new("http://127.0.0.1:1".to_string(), None, None, service.cancel_token()).unwrap();
+        let service = service.with_providers(vec![Box::new(remote)]);
+        assert!(matches!(service.count_tokens(&hello_request()).await, Err(LlmError::ModelError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_lora_adapters_config_and_commands() {
+        let config: LlmConfig = serde_json::from_value(serde_json::json!({
+            "model_name": "Llama-3.2-1B-Instruct-Q5_K_M",
+            "model_path": null,
+            "temperature": 0.8,
+            "top_p": 0.9,
+            "max_tokens": 512,
+            "ctx_size": 4096,
+            "n_threads": null,
+            "n_gpu_layers": 0,
+            "lora_adapters": [{ "path": "pirate.gguf" }, { "path": "terse.gguf", "scale": 0.5 }]
+        }))
+        .unwrap();
+        assert_eq!(config.lora_adapters, vec![
+            LoraAdapterConfig { path: "pirate.gguf".into(), scale: 1.0 },
+            LoraAdapterConfig { path: "terse.gguf".into(), scale: 0.5 },
+        ]);
+        assert!(LlmConfig::default().lora_adapters.is_empty());
+
+        let service = LlmService::new(config);
+        assert!(matches!(service.list_lora_adapters().await, Err(LlmError::NotRunning)));
+
+        // Remote providers have no weights to adapt.
+        let remote = crate::openai::OpenAiProvider::new("http://127.0.0.1:1".to_string(), None, None, service.cancel_token()).unwrap();
+        let service = service.with_providers(vec![Box::new(remote)]);
+        assert!(matches!(service.list_lora_adapters().await, Err(LlmError::ModelError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_lora_adapters_attach_list_and_detach() {
+        let (service, _mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone())]);
+        let models_dir = std::env::current_dir().unwrap().join("models");
+        std::fs::create_dir_all(&models_dir).unwrap();
+        let name = std::path::PathBuf::from(format!("emchat-lora-{}.gguf", uuid::Uuid::new_v4()));
+        let path = models_dir.join(&name);
+        std::fs::write(&path, b"").unwrap();
+
+        // Adapters are named by file and resolved in the models directory.
+        service.attach_lora_adapter(LoraAdapterConfig { path: name.clone(), scale: 1.0 }).await.unwrap();
+        assert_eq!(service.list_lora_adapters().await.unwrap(), vec![LoraAdapterConfig { path: path.clone(), scale: 1.0 }]);
+        // Attaching again only switches the scale.
+        service.attach_lora_adapter(LoraAdapterConfig { path: name.clone(), scale: 0.25 }).await.unwrap();
+        assert_eq!(service.list_lora_adapters().await.unwrap(), vec![LoraAdapterConfig { path: path.clone(), scale: 0.25 }]);
+
+        let missing = LoraAdapterConfig { path: name.with_extension("missing"), scale: 1.0 };
+        assert!(matches!(service.attach_lora_adapter(missing).await, Err(LlmError::ModelError(_))));
+        // Nothing outside the models directory can be named, even when the file exists.
+        for outside in [path.clone(), std::path::Path::new("..").join("models").join(&name), std::path::Path::new("models").join(&name)] {
+            let adapter = LoraAdapterConfig { path: outside, scale: 1.0 };
+            assert!(matches!(service.attach_lora_adapter(adapter).await, Err(LlmError::ModelError(_))));
+        }
+        assert!(service.model_file_metadata(&name.to_string_lossy()).is_some());
+        assert!(service.model_file_metadata(&format!("../models/{}", name.display())).is_none());
+
+        service.detach_lora_adapter(&name).await.unwrap();
+        assert!(service.list_lora_adapters().await.unwrap().is_empty());
+        assert!(matches!(service.detach_lora_adapter(&name).await, Err(LlmError::ConfigError(_))));
+        std::fs::remove_file(path).unwrap();
+        // Kept if it holds anything else.
+        let _ = std::fs::remove_dir(models_dir);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_respawned_worker_gets_the_attached_lora_adapters() {
+        use crate::worker::InferenceWorker;
+
+        // Stands in for the worker: it records each start request, accepts
+        // adapter changes and dies on anything else.
+        let starts = std::env::temp_dir().join(format!("emchat-worker-{}", uuid::Uuid::new_v4()));
+        let script = r#"
+            while read -r line; do
+                case "$line" in
+                    *'"type":"start"'*) printf '%s\n' "$line" >> "$0"; echo '{"Ok":"ready"}' ;;
+                    *'"type":"attach_lora_adapter"'* | *'"type":"detach_lora_adapter"'*) echo '{"Ok":null}' ;;
+                    *) kill -9 $$ ;;
+                esac
+            done
+        "#;
+        let cancel = crate::llm::CancelToken::default();
+        let mut worker = InferenceWorker::new(LlmConfig::default()).with_command("sh", &["-c", script, starts.to_str().unwrap()]);
+        worker.start(&cancel).await.unwrap();
+
+        let terse = LoraAdapterConfig { path: "/adapters/terse.gguf".into(), scale: 1.0 };
+        let pirate = LoraAdapterConfig { path: "/adapters/pirate.gguf".into(), scale: 1.0 };
+        worker.attach_lora_adapter(terse.clone(), &cancel).await.unwrap();
+        worker.attach_lora_adapter(pirate.clone(), &cancel).await.unwrap();
+        worker.attach_lora_adapter(LoraAdapterConfig { scale: 0.5, ..terse.clone() }, &cancel).await.unwrap();
+        worker.detach_lora_adapter(&pirate.path, &cancel).await.unwrap();
+
+        assert!(matches!(worker.tokenize("hi", true, true, &cancel).await, Err(LlmError::WorkerCrashed(_))));
+        assert!(worker.tokenize("hi", true, true, &cancel).await.is_err());
+
+        let log = std::fs::read_to_string(&starts).unwrap();
+        std::fs::remove_file(starts).unwrap();
+        let starts: Vec<serde_json::Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(starts.len(), 2);
+        assert_eq!(starts[0]["config"]["lora_adapters"], serde_json::json!([]));
+        let respawned: Vec<LoraAdapterConfig> = serde_json::from_value(starts[1]["config"]["lora_adapters"].clone()).unwrap();
+        assert_eq!(respawned, vec![LoraAdapterConfig { scale: 0.5, ..terse }]);
+    }
+
+    #[test]
+    fn test_reply_budget_keeps_replies_in_the_context() {
+        use crate::llama::reply_budget;
+
+        assert_eq!(reply_budget(4096, 10, 1, 512), 512);
+        assert_eq!(reply_budget(4096, 4000, 1, 512), 96);
+        assert_eq!(reply_budget(4096, 4095, 1, 512), 1);
+        assert_eq!(reply_budget(4096, 4096, 1, 512), 0);
+        assert_eq!(reply_budget(4096, 10, 1, -1), 0);
+        // The prompt is decoded once; the rest of the context is split between the replies.
+        assert_eq!(reply_budget(100, 10, 4, 512), 22);
+        for (ctx_size, prompt, replies) in [(4096_u32, 4000_usize, 1_usize), (100, 10, 4), (2048, 7, 16), (64, 60, 16)] {
+            let budget = reply_budget(ctx_size, prompt, replies, i32::MAX);
+            assert!(prompt + replies * budget as usize <= ctx_size as usize);
+            assert!(prompt + replies * (budget as usize + 1) > ctx_size as usize);
+        }
+    }
+
+    #[test]
+    fn test_fim_prompt_is_prefix_suffix_middle() {
+        use crate::llama::FimTokens;
+        use llama_cpp_2::token::LlamaToken;
+
+        let fim = FimTokens {
+            prefix: LlamaToken(1001),
+            suffix: LlamaToken(1002),
+            middle: LlamaToken(1003),
+        };
+        let tokens = fim.prompt(&[LlamaToken(1)], &[LlamaToken(10), LlamaToken(11)], &[LlamaToken(20)]);
+
+        let ids: Vec<i32> = tokens.into_iter().map(|t| t.0).collect();
+        assert_eq!(ids, vec![1, 1001, 10, 11, 1002, 20, 1003]);
+    }
+
+    #[test]
+    fn test_grammar_validation() {
+        use crate::grammar::validate;
+
+        let command = r#"
+            # Fixed command syntax for automation prompts
+            root   ::= verb " " target ("," target)* "\n"?
+            verb   ::= "scan" | "connect" | "disconnect"
+            target ::= [A-F0-9]{2} (":" [A-F0-9]{2}){5}
+        "#;
+        assert!(validate(command).is_ok());
+
+        for bad in [
+            "root ::= \"unterminated",
+            "root ::= missing",
+            "start ::= \"a\"",
+            "root ::= root \"a\" | \"b\"",
+            "root ::= (\"a\"?)*",
+            "root ::= \"a\" {x}",
+        ] {
+            assert!(matches!(validate(bad), Err(LlmError::ConfigError(_))), "accepted {:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_grammar_is_rejected_before_generation() {
+        let (service, mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone()).reply("scan")]);
+
+        let result = service.chat_completion(ChatRequest {
+            grammar: Some("root ::= verb".to_string()),
+            ..hello_request()
+        }).await;
+        assert!(matches!(result, Err(LlmError::ConfigError(message)) if message.starts_with("Invalid grammar")));
+        assert!(mocks[0].requests().is_empty());
+
+        let grammar = "root ::= \"scan\" | \"stop\"".to_string();
+        service.chat_completion(ChatRequest { grammar: Some(grammar.clone()), ..hello_request() }).await.unwrap();
+        assert_eq!(mocks[0].requests()[0].grammar, Some(grammar));
+    }
+
+    fn device_summary_schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "maxLength": 64 },
+                "address": { "type": "string" },
+                "rssi": { "type": ["integer", "null"], "maximum": 0 },
+                "kind": { "enum": ["phone", "headset", "sensor", "unknown"] },
+                "services": { "type": "array", "items": { "$ref": "#/$defs/service" }, "maxItems": 8 }
+            },
+            "required": ["name", "address", "kind"],
+            "additionalProperties": false,
+            "$defs": {
+                "service": { "type": "object", "properties": { "uuid": { "type": "string" } }, "required": ["uuid"] }
+            }
+        })
+    }
+
+    #[test]
+    fn test_json_schema_grammar_and_validation() {
+        use crate::json_schema::{to_grammar, validate};
+
+        let schema = device_summary_schema();
+        let grammar = to_grammar(&schema).unwrap();
+        crate::grammar::validate(&grammar).unwrap();
+        assert!(grammar.contains(r#""\"kind\"""#));
+
+        let device = serde_json::json!({
+            "name": "Pixel Buds", "address": "AA:BB:CC:DD:EE:FF", "rssi": -60, "kind": "headset",
+            "services": [{ "uuid": "180f" }]
+        });
+        assert!(validate(&device, &schema).is_ok());
+
+        let mut wrong = device.clone();
+        wrong["kind"] = "toaster".into();
+        assert!(validate(&wrong, &schema).unwrap_err().starts_with("$.kind"));
+        let mut wrong = device.clone();
+        wrong["services"][0] = serde_json::json!({});
+        assert!(validate(&wrong, &schema).unwrap_err().contains("'uuid'"));
+        let mut wrong = device;
+        wrong["rssi"] = 5.into();
+        assert!(validate(&wrong, &schema).is_err());
+
+        let unsupported = serde_json::json!({ "type": "string", "pattern": "^[A-F0-9:]+$" });
+        assert!(matches!(to_grammar(&unsupported), Err(LlmError::ConfigError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_response_format_checks_reply_against_schema() {
+        use crate::llm::ResponseFormat;
+
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone())
+                .reply(r#"{"name": "Tag", "address": "AA:BB:CC:DD:EE:FF", "kind": "sensor"}"#)
+                .reply(r#"{"name": "Tag"}"#),
+        ]);
+        let request = ChatRequest {
+            response_format: Some(ResponseFormat::JsonSchema {
+                json_schema: None,
+                schema: Some(device_summary_schema()),
+            }),
+            ..hello_request()
+        };
+
+        let response = service.chat_completion(request.clone()).await.unwrap();
+        let device: serde_json::Value = serde_json::from_str(&response.choices[0].message.content).unwrap();
+        assert_eq!(device["kind"], "sensor");
+        assert!(mocks[0].requests()[0].effective_grammar().unwrap().is_some());
+
+        let result = service.chat_completion(request.clone()).await;
+        assert!(matches!(result, Err(LlmError::SchemaMismatch(message)) if message.contains("'address'")));
+
+        let both = ChatRequest { grammar: Some("root ::= \"{}\"".to_string()), ..request };
+        assert!(matches!(service.chat_completion(both).await, Err(LlmError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_tool_calls_are_parsed_from_generated_text() {
+        use crate::tools::{parse_calls, CallFilter};
+
+        let text = "Let me check.\n<tool_call>\n{\"name\": \"get_battery\", \"arguments\": {\"address\": \"AA:BB\"}}\n</tool_call>\n<tool_call>\nnot json\n</tool_call>";
+        let (content, calls) = parse_calls(text);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_battery");
+        assert_eq!(calls[0].function.arguments, r#"{"address":"AA:BB"}"#);
+        assert!(calls[0].id.starts_with("call_"));
+        assert!(content.starts_with("Let me check."));
+        assert!(content.contains("not json"));
+
+        // The tag may be split across tokens; nothing from it on is streamed.
+        let mut filter = CallFilter::default();
+        let streamed: String = ["Let me", " check.\n<tool", "_call>\n{\"name\"", "}"]
+            .iter()
+            .map(|piece| filter.push(piece))
+            .collect();
+        assert_eq!(streamed + &filter.finish(), "Let me check.\n");
+        let mut filter = CallFilter::default();
+        assert_eq!(filter.push("a <tool"), "a ");
+        assert_eq!(filter.finish(), "<tool");
+
+        // OpenAI clients send null content with calls, Ollama sends arguments as an object.
+        let message: ChatMessage = serde_json::from_value(serde_json::json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{ "function": { "name": "scan", "arguments": { "seconds": 5 } } }],
+        }))
+        .unwrap();
+        assert_eq!(message.content, "");
+        assert_eq!(message.tool_calls.unwrap()[0].function.arguments, r#"{"seconds":5}"#);
+    }
+
+    #[test]
+    fn test_tool_choice_constrains_generation() {
+        use crate::llm::{FunctionDefinition, Tool, ToolChoice, ToolChoiceFunction, ToolChoiceMode};
+        use crate::tools::{offered, system_prompt};
+
+        let tool = |name: &str| Tool {
+            kind: "function".to_string(),
+            function: FunctionDefinition {
+                name: name.to_string(),
+                description: Some(format!("Run {}", name)),
+                parameters: Some(serde_json::json!({
+                    "type": "object",
+                    "properties": { "seconds": { "type": "integer" } },
+                    "required": ["seconds"],
+                })),
+            },
+        };
+        let request = ChatRequest {
+            tools: Some(vec![tool("scan"), tool("stop_scan")]),
+            ..hello_request()
+        };
+        assert_eq!(offered(&request).unwrap().len(), 2);
+        assert!(request.effective_grammar().unwrap().is_none());
+        let prompt = system_prompt(Some("Be brief."), &offered(&request).unwrap());
+        assert!(prompt.starts_with("Be brief.\n\n# Tools"));
+        assert!(prompt.contains(r#""name":"stop_scan""#));
+
+        let none = ChatRequest { tool_choice: Some(ToolChoice::Mode(ToolChoiceMode::None)), ..request.clone() };
+        assert!(offered(&none).unwrap().is_empty());
+
+        let forced = ChatRequest {
+            tool_choice: Some(ToolChoice::Function {
+                kind: "function".to_string(),
+                function: ToolChoiceFunction { name: "scan".to_string() },
+            }),
+            ..request.clone()
+        };
+        assert_eq!(offered(&forced).unwrap()[0].function.name, "scan");
+        let grammar = forced.effective_grammar().unwrap().unwrap();
+        crate::grammar::validate(&grammar).unwrap();
+        assert!(grammar.contains(r#""\"scan\"""#) && !grammar.contains("stop_scan"));
+
+        let unknown = ChatRequest {
+            tool_choice: Some(ToolChoice::Function {
+                kind: "function".to_string(),
+                function: ToolChoiceFunction { name: "pair".to_string() },
+            }),
+            ..request.clone()
+        };
+        assert!(matches!(offered(&unknown), Err(LlmError::ConfigError(_))));
+
+        let required = ChatRequest {
+            tool_choice: Some(ToolChoice::Mode(ToolChoiceMode::Required)),
+            grammar: Some("root ::= \"ok\"".to_string()),
+            ..request
+        };
+        assert!(matches!(required.effective_grammar(), Err(LlmError::ConfigError(_))));
+    }
+
+    fn device(name: &str, rssi: Option<i16>) -> crate::bluetooth::BluetoothDevice {
+        crate::bluetooth::BluetoothDevice {
+            id: name.to_string(),
+            name: Some(name.to_string()),
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            rssi,
+            is_connectable: true,
+            services: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_bluetooth_tool_device_ordering() {
+        use crate::bluetooth_tools::{by_signal, named};
+
+        let devices = vec![device("Tag", Some(-80)), device("Buds", None), device("Watch", Some(-40)), device("Buds Case", Some(-60))];
+        let names = |devices: Vec<crate::bluetooth::BluetoothDevice>| devices.into_iter().map(|d| d.id).collect::<Vec<_>>();
+        assert_eq!(names(by_signal(devices.clone(), None)), ["Watch", "Buds Case", "Tag", "Buds"]);
+        assert_eq!(names(by_signal(devices.clone(), Some(2))), ["Watch", "Buds Case"]);
+        assert_eq!(names(named(devices, "buds")), ["Buds Case", "Buds"]);
+    }
+
+    #[tokio::test]
+    async fn test_chat_runs_bluetooth_tool_calls() {
+        use crate::bluetooth::BluetoothScanner;
+        use crate::bluetooth_tools::BluetoothTools;
+
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone())
+                .reply("<tool_call>\n{\"name\": \"list_bluetooth_devices\", \"arguments\": {\"limit\": 3}}\n</tool_call>")
+                .reply("<tool_call>\n{\"name\": \"start_bluetooth_scan\", \"arguments\": {}}\n</tool_call>")
+                .reply("I could not find any devices.")
+                .reply("<tool_call>\n{\"name\": \"lookup_owner\", \"arguments\": {}}\n</tool_call>"),
+        ]);
+        let service = tokio::sync::RwLock::new(service);
+        let scanner = tokio::sync::Mutex::new(BluetoothScanner::new());
+
+        let response = crate::tools::chat(&service, hello_request(), &[&BluetoothTools(&scanner)]).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "I could not find any devices.");
+
+        let requests = mocks[0].requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].tools.as_ref().unwrap().iter().any(|t| t.function.name == "find_bluetooth_device"));
+        let results: Vec<&ChatMessage> = requests[2].messages.iter().filter(|m| m.role == "tool").collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].content, r#"{"devices":[],"scanning":false}"#);
+        // Without an adapter the scan fails, and the model is told so.
+        assert!(results[1].content.contains("not initialized"));
+        assert_eq!(results[1].tool_call_id, requests[2].messages[3].tool_calls.as_ref().map(|c| c[0].id.clone()));
+
+        // Calls to the caller's own tools are handed back.
+        let response = crate::tools::chat(&service, hello_request(), &[&BluetoothTools(&scanner)]).await.unwrap();
+        assert_eq!(response.choices[0].message.tool_calls.as_ref().unwrap()[0].function.name, "lookup_owner");
+    }
+
+    /// Minimal MCP server for tests: echoes `text` back from its `echo` tool
+    /// and serves one resource. It pings the client before answering a call.
+    #[cfg(unix)]
+    const ECHO_MCP_SERVER: &str = r#"
+while IFS= read -r line; do
+  id=$(printf '%s\n' "$line" | sed -n 's/^{"id":\([0-9]*\),.*/\1/p')
+  case "$line" in
+    *'"method":"initialize"'*) result='{"protocolVersion":"2025-03-26","capabilities":{"tools":{},"resources":{}},"serverInfo":{"name":"echo","version":"1.0"}}' ;;
+    *'"method":"tools/list"'*) result='{"tools":[{"name":"echo","description":"Echo text back","inputSchema":{"type":"object","properties":{"text":{"type":"string"}},"required":["text"]}}]}' ;;
+    *'"method":"resources/list"'*) result='{"resources":[{"uri":"file:///motd.txt","name":"motd"}]}' ;;
+    *'"method":"resources/read"'*) result='{"contents":[{"uri":"file:///motd.txt","mimeType":"text/plain","text":"Hello from MCP"}]}' ;;
+    *'"method":"tools/call"'*)
+      echo '{"jsonrpc":"2.0","id":"server-1","method":"ping"}'
+      text=$(printf '%s\n' "$line" | sed -n 's/.*"text":"\([^"]*\)".*/\1/p')
+      result="{\"content\":[{\"type\":\"text\",\"text\":\"$text\"}]}" ;;
+    *) continue ;;
+  esac
+  printf '{"jsonrpc":"2.0","id":%s,"result":%s}\n' "$id" "$result"
+done
+"#;
+
+    /// Approves or denies every call, recording what was asked.
+    struct RecordingApprover {
+        approve: bool,
+        asked: std::sync::Mutex<Vec<crate::mcp::ToolApprovalRequest>>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::mcp::ToolApprover for RecordingApprover {
+        async fn approve(&self, request: &crate::mcp::ToolApprovalRequest) -> bool {
+            self.asked.lock().unwrap().push(request.clone());
+            self.approve
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_mcp_stdio_tools_and_resources_in_chat() {
+        use crate::mcp::{McpManager, McpServerConfig, McpTools, McpTransportConfig};
+
+        let manager = tokio::sync::Mutex::new(McpManager::default());
+        let info = manager
+            .lock()
+            .await
+            .connect(McpServerConfig {
+                name: "echo".to_string(),
+                transport: McpTransportConfig::Stdio {
+                    command: "sh".to_string(),
+                    args: vec!["-c".to_string(), ECHO_MCP_SERVER.to_string()],
+                    env: Default::default(),
+                },
+                auto_approve: Vec::new(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(info.server_info.unwrap()["name"], "echo");
+        assert_eq!(info.tools[0].name, "echo");
+        assert_eq!(info.resources[0].uri, "file:///motd.txt");
+
+        let (service, mocks) = mock_service(|cancel| vec![
+            MockProvider::new(cancel.clone())
+                .reply("<tool_call>\n{\"name\": \"echo__echo\", \"arguments\": {\"text\": \"marco\"}}\n</tool_call>")
+                .reply("<tool_call>\n{\"name\": \"echo__read_resource\", \"arguments\": {\"uri\": \"file:///motd.txt\"}}\n</tool_call>")
+                .reply("The server says polo.")
+                .reply("<tool_call>\n{\"name\": \"echo__echo\", \"arguments\": {\"text\": \"again\"}}\n</tool_call>")
+                .reply("Fine, I will not."),
+        ]);
+        let service = tokio::sync::RwLock::new(service);
+
+        let approver = RecordingApprover { approve: true, asked: Default::default() };
+        let tools = McpTools::new(&manager, &approver).await;
+        let response = crate::tools::chat(&service, hello_request(), &[&tools]).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "The server says polo.");
+        let asked = approver.asked.lock().unwrap().clone();
+        assert_eq!(asked.len(), 2);
+        assert_eq!((asked[0].server.as_str(), asked[0].tool.as_str()), ("echo", "echo"));
+        assert_eq!(asked[0].arguments, serde_json::json!({ "text": "marco" }));
+
+        let results: Vec<String> = mocks[0].requests()[2]
+            .messages
+            .iter()
+            .filter(|m| m.role == "tool")
+            .map(|m| m.content.clone())
+            .collect();
+        assert_eq!(results[0], "marco");
+        assert!(results[1].contains("Hello from MCP"));
+
+        // A declined call is reported to the model instead of running.
+        let approver = RecordingApprover { approve: false, asked: Default::default() };
+        let tools = McpTools::new(&manager, &approver).await;
+        let response = crate::tools::chat(&service, hello_request(), &[&tools]).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "Fine, I will not.");
+        let last = mocks[0].requests().pop().unwrap();
+        assert!(last.messages.last().unwrap().content.contains("declined"));
+
+        manager.lock().await.shutdown().await;
+        assert!(manager.lock().await.servers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mcp_http_transport_with_session_and_event_stream() {
+        use axum::http::{HeaderMap, StatusCode};
+        use axum::response::IntoResponse;
+        use crate::mcp::{McpClient, McpServerConfig, McpTransportConfig};
+
+        async fn handle(headers: HeaderMap, axum::Json(message): axum::Json<serde_json::Value>) -> axum::response::Response {
+            let id = message["id"].clone();
+            let reply = |result: serde_json::Value| serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result });
+            match message["method"].as_str().unwrap_or_default() {
+                "initialize" => (
+                    [("Mcp-Session-Id", "session-1")],
+                    axum::Json(reply(serde_json::json!({ "protocolVersion": "2025-03-26", "capabilities": { "tools": {} } }))),
+                )
+                    .into_response(),
+                _ if headers.get("Mcp-Session-Id").is_none_or(|v| v != "session-1") => StatusCode::BAD_REQUEST.into_response(),
+                "notifications/initialized" => StatusCode::ACCEPTED.into_response(),
+                "tools/list" => axum::Json(reply(serde_json::json!({ "tools": [{ "name": "uptime" }] }))).into_response(),
+                "tools/call" => {
+                    let events = format!(
+                        "event: message\ndata: {}\n\nevent: message\ndata: {}\n\n",
+                        serde_json::json!({ "jsonrpc": "2.0", "method": "notifications/progress", "params": {} }),
+                        reply(serde_json::json!({ "content": [{ "type": "text", "text": "up 3 days" }], "isError": false })),
+                    );
+                    ([("content-type", "text/event-stream")], events).into_response()
+                }
+                _ => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/mcp", listener.local_addr().unwrap());
+        let app = axum::Router::new().route("/mcp", axum::routing::post(handle));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = McpClient::connect(McpServerConfig {
+            name: "remote".to_string(),
+            transport: McpTransportConfig::Http { url, headers: Default::default() },
+            auto_approve: Vec::new(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(client.info().tools[0].name, "uptime");
+        assert!(client.info().resources.is_empty());
+        assert_eq!(client.call_tool("uptime", serde_json::json!({})).await.unwrap(), "up 3 days");
+    }
+
+    #[tokio::test]
+    async fn test_mcp_manager_is_not_locked_while_a_tool_runs() {
+        use crate::llm::FunctionCall;
+        use crate::mcp::{McpManager, McpServerConfig, McpTools, McpTransportConfig};
+        use crate::tools::ToolExecutor;
+        use tokio::sync::Notify;
+
+        // The tool only answers once `release` is notified.
+        async fn handle(
+            axum::extract::State(release): axum::extract::State<Arc<Notify>>,
+            axum::Json(message): axum::Json<serde_json::Value>,
+        ) -> axum::response::Response {
+            use axum::response::IntoResponse;
+            let reply = |result: serde_json::Value| axum::Json(serde_json::json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }));
+            match message["method"].as_str().unwrap_or_default() {
+                "initialize" => reply(serde_json::json!({ "protocolVersion": "2025-03-26", "capabilities": { "tools": {} } })).into_response(),
+                "tools/list" => reply(serde_json::json!({ "tools": [{ "name": "wait" }] })).into_response(),
+                "tools/call" => {
+                    release.notified().await;
+                    reply(serde_json::json!({ "content": [{ "type": "text", "text": "done" }] })).into_response()
+                }
+                _ => axum::http::StatusCode::ACCEPTED.into_response(),
+            }
+        }
+
+        let release = Arc::new(Notify::new());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/mcp", listener.local_addr().unwrap());
+        let app = axum::Router::new().route("/mcp", axum::routing::post(handle)).with_state(release.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let manager = tokio::sync::Mutex::new(McpManager::default());
+        manager
+            .lock()
+            .await
+            .connect(McpServerConfig {
+                name: "slow".to_string(),
+                transport: McpTransportConfig::Http { url, headers: Default::default() },
+                auto_approve: vec!["wait".to_string()],
+            })
+            .await
+            .unwrap();
+
+        let approver = RecordingApprover { approve: false, asked: Default::default() };
+        let tools = McpTools::new(&manager, &approver).await;
+        let call = FunctionCall { name: "slow__wait".to_string(), arguments: String::new() };
+        // The manager can be used while the call waits on the server.
+        let (result, servers) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(tools.call(&call), async {
+                let servers = manager.lock().await.servers();
+                release.notify_one();
+                servers
+            })
+        })
+        .await
+        .expect("the manager stayed locked during the call");
+        assert_eq!(result, "done");
+        assert_eq!(servers[0].name, "slow");
+        assert!(approver.asked.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_api_server_serves_mcp_tools() {
+        use crate::mcp::{McpClient, McpServerConfig, McpTransportConfig};
+
+        let (service, _mocks) = mock_service(|cancel| vec![MockProvider::new(cancel.clone()).reply("Pong")]);
+        let server = start_api_server(service).await;
+
+        let client = McpClient::connect(McpServerConfig {
+            name: "emchat".to_string(),
+            transport: McpTransportConfig::Http { url: format!("{}/mcp", server.base_url()), headers: Default::default() },
+            auto_approve: Vec::new(),
+        })
+        .await
+        .unwrap();
+        let names: Vec<&str> = client.info().tools.iter().map(|tool| tool.name.as_str()).collect();
+        assert!(names.contains(&"chat"));
+        assert!(names.contains(&"read_bluetooth_characteristic"));
+
+        let reply = client.call_tool("chat", serde_json::json!({ "prompt": "Ping" })).await.unwrap();
+        assert_eq!(reply, "Pong");
+        // Without an adapter the scan fails, and the client is told so.
+        match client.call_tool("start_bluetooth_scan", serde_json::json!({})).await {
+            Err(LlmError::McpError(message)) => assert!(message.contains("not initialized")),
+            other => panic!("expected a tool error, got {:?}", other),
+        }
+
+        let rebound = reqwest::Client::new()
+            .post(format!("{}/mcp", server.base_url()))
+            .header("Origin", "http://attacker.example")
+            .json(&serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "ping" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(rebound.status(), 403);
+        server.stop().await;
+    }
+
+    // Tests against llama.cpp itself need a small GGUF chat model, so they are
+    // ignored by default. Run them with
+    // `EMCHAT_TEST_MODEL=/path/to/model.gguf cargo test -- --ignored`.
+
+    /// llama.cpp's backend can only be initialized once at a time, so tests
+    /// that load the model take turns.
+    static LOCAL_MODEL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    async fn local_service(config: LlmConfig) -> LlmService {
+        let path = std::env::var_os("EMCHAT_TEST_MODEL").expect("EMCHAT_TEST_MODEL should be the GGUF model to test with");
+        let mut service = LlmService::new(LlmConfig { model_path: Some(path.into()), ..config });
+        service.start().await.unwrap();
+        service
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    #[ignore = "needs a GGUF model in EMCHAT_TEST_MODEL"]
+    async fn test_local_generation_does_not_stall_the_runtime() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let _model = LOCAL_MODEL.lock().await;
+        let service = Arc::new(local_service(LlmConfig::default()).await);
+        // Shares the only worker thread with the generation below.
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let generation = tokio::spawn({
+            let (service, ticks, seen) = (service.clone(), ticks.clone(), seen.clone());
+            async move {
+                let request = ChatRequest { max_tokens: Some(32), temperature: Some(0.0), ..hello_request() };
+                let on_token = move |_: &str| seen.lock().unwrap().push(ticks.load(Ordering::SeqCst));
+                service.chat_completion_stream(request, &on_token, &|_| {}).await
+            }
+        });
+        generation.await.unwrap().unwrap();
+        ticker.abort();
+
+        let seen = seen.lock().unwrap();
+        assert!(seen.len() > 1, "{:?}", seen);
+        assert!(seen.last() > seen.first(), "the ticker never ran while generating: {:?}", seen);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a GGUF model in EMCHAT_TEST_MODEL"]
+    async fn test_local_model_generates_n_sequences() {
+        let _model = LOCAL_MODEL.lock().await;
+        let service = local_service(LlmConfig::default()).await;
+        let request = |n| ChatRequest { n: Some(n), max_tokens: Some(12), temperature: Some(0.0), ..hello_request() };
+
+        // Greedy replies decoded side by side match the one decoded alone,
+        // so each sequence sees the shared prompt and only its own tokens.
+        let single = service.chat_completion(request(1)).await.unwrap();
+        let pair = service.chat_completion(request(2)).await.unwrap();
+        assert_eq!(pair.choices.len(), 2);
+        for (index, choice) in pair.choices.iter().enumerate() {
+            assert_eq!(choice.index, index as u32);
+            assert_eq!(choice.message.content, single.choices[0].message.content);
+            assert_eq!(choice.finish_reason, single.choices[0].finish_reason);
+        }
+        let usage = (single.usage.unwrap(), pair.usage.unwrap());
+        assert_eq!(usage.1.prompt_tokens, usage.0.prompt_tokens);
+        assert_eq!(usage.1.completion_tokens, 2 * usage.0.completion_tokens);
+
+        // Sampled replies with best_of are scored and the likeliest kept.
+        let response = service
+            .chat_completion(ChatRequest { best_of: Some(3), temperature: Some(0.9), ..request(2) })
+            .await
+            .unwrap();
+        assert_eq!(response.choices.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs a GGUF model in EMCHAT_TEST_MODEL"]
+    async fn test_local_speculative_decoding_keeps_the_reply() {
+        let _model = LOCAL_MODEL.lock().await;
+        let request = |n| ChatRequest { n: Some(n), max_tokens: Some(24), temperature: Some(0.0), ..hello_request() };
+        let plain = local_service(LlmConfig::default()).await;
+        let expected = plain.chat_completion(request(1)).await.unwrap();
+        drop(plain);
+
+        // The model drafts for itself, from the models directory.
+        let models_dir = std::env::current_dir().unwrap().join("models");
+        std::fs::create_dir_all(&models_dir).unwrap();
+        let draft_name = format!("emchat-draft-{}", uuid::Uuid::new_v4());
+        let draft_path = models_dir.join(format!("{}.gguf", draft_name));
+        std::os::unix::fs::symlink(std::env::var_os("EMCHAT_TEST_MODEL").unwrap(), &draft_path).unwrap();
+        let mut config = LlmConfig::default();
+        config.speculative.draft_model = Some(draft_name);
+        let service = local_service(config).await;
+
+        // Greedy decoding verifies every drafted token, so the reply is the same.
+        let response = service.chat_completion(request(1)).await.unwrap();
+        assert_eq!(response.choices[0].message.content, expected.choices[0].message.content);
+        let usage = response.usage.unwrap();
+        assert_eq!(usage.completion_tokens, expected.usage.unwrap().completion_tokens);
+        let details = usage.completion_tokens_details.expect("drafted tokens are reported");
+        assert!(details.accepted_prediction_tokens > 0, "{:?}", details);
+
+        // Several replies at once are decoded without a draft.
+        let response = service.chat_completion(request(2)).await.unwrap();
+        assert!(response.usage.unwrap().completion_tokens_details.is_none());
+
+        drop(service);
+        std::fs::remove_file(draft_path).unwrap();
+        // Kept if it holds anything else.
+        let _ = std::fs::remove_dir(models_dir);
+    }
 }
@@ -0,0 +1,256 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+
+use crate::grammar::GRAMMAR_ROOT;
+use crate::json_schema;
+use crate::llm::{
+    ChatMessage, ChatRequest, ChatResponse, FunctionCall, LlmError, LlmService, Tool, ToolCall, ToolChoice,
+    ToolChoiceMode,
+};
+
+// Local models are prompted with the Hermes tool format that ChatML models
+// such as Qwen 2.5 and Hermes 3 are trained on: the tool signatures go in the
+// system turn, calls come back as JSON inside <tool_call> tags and results are
+// passed in a user turn inside <tool_response> tags.
+
+pub const CALL_OPEN: &str = "<tool_call>";
+pub const CALL_CLOSE: &str = "</tool_call>";
+
+/// Give up on a conversation that keeps calling tools after this many replies.
+pub const MAX_TOOL_ROUNDS: usize = 8;
+
+/// A set of tools the app runs itself during `chat`.
+#[async_trait]
+pub trait ToolExecutor: Send + Sync {
+    fn tools(&self) -> Vec<Tool>;
+
+    /// Run `function`, one of `tools()`, returning the result for the model.
+    /// Failures are described in the result rather than ending the chat.
+    async fn call(&self, function: &FunctionCall) -> String;
+}
+
+/// Chat with the tools of `executors` available, running the calls the model
+/// makes until it answers. Calls to tools the caller supplied are returned as is.
+/// The service is only locked for each reply, not while tools run.
+pub async fn chat(service: &RwLock<LlmService>, mut request: ChatRequest, executors: &[&dyn ToolExecutor]) -> Result<ChatResponse, LlmError> {
+    let mut tools = request.tools.take().unwrap_or_default();
+    let mut runners: Vec<(String, &dyn ToolExecutor)> = Vec::new();
+    for &executor in executors {
+        for tool in executor.tools() {
+            if !tools.iter().any(|t| t.function.name == tool.function.name) {
+                runners.push((tool.function.name.clone(), executor));
+                tools.push(tool);
+            }
+        }
+    }
+    request.tools = Some(tools);
+    let runner = |name: &str| runners.iter().find(|(n, _)| n == name).map(|&(_, executor)| executor);
+
+    for round in 1..=MAX_TOOL_ROUNDS {
+        let response = service.read().await.chat_completion(request.clone()).await?;
+        let Some(message) = response.choices.first().map(|choice| choice.message.clone()) else {
+            return Ok(response);
+        };
+        let calls = message.tool_calls.iter().flatten();
+        let Some(calls) = calls.map(|call| Some((call.clone(), runner(&call.function.name)?))).collect::<Option<Vec<_>>>() else {
+            return Ok(response);
+        };
+        if calls.is_empty() {
+            return Ok(response);
+        }
+
+        info!("🧰 Round {}: running {} tool calls", round, calls.len());
+        request.messages.push(message);
+        for (call, executor) in calls {
+            request.messages.push(ChatMessage {
+                role: "tool".to_string(),
+                content: executor.call(&call.function).await,
+                tool_call_id: Some(call.id),
+                ..Default::default()
+            });
+        }
+        // Any call the caller forced has been made; let the model answer now.
+        request.tool_choice = None;
+    }
+
+    Err(LlmError::ModelError(format!("Model was still calling tools after {} rounds", MAX_TOOL_ROUNDS)))
+}
+
+/// Tools the model may call for `request`: none when `tool_choice` is `none`,
+/// only the named one when it picks a function.
+pub fn offered(request: &ChatRequest) -> Result<Vec<&Tool>, LlmError> {
+    let tools = request.tools.as_deref().unwrap_or_default();
+    match &request.tool_choice {
+        Some(ToolChoice::Mode(ToolChoiceMode::None)) => Ok(Vec::new()),
+        Some(ToolChoice::Function { function, .. }) => tools
+            .iter()
+            .find(|tool| tool.function.name == function.name)
+            .map(|tool| vec![tool])
+            .ok_or_else(|| LlmError::ConfigError(format!("tool_choice names unknown tool '{}'", function.name))),
+        Some(ToolChoice::Mode(ToolChoiceMode::Required)) if tools.is_empty() => Err(LlmError::ConfigError(
+            "tool_choice 'required' needs at least one tool".to_string(),
+        )),
+        _ => Ok(tools.iter().collect()),
+    }
+}
+
+/// Grammar that only admits tool calls, when `tool_choice` requires one.
+pub fn required_grammar(request: &ChatRequest) -> Result<Option<String>, LlmError> {
+    let required = matches!(
+        request.tool_choice,
+        Some(ToolChoice::Mode(ToolChoiceMode::Required)) | Some(ToolChoice::Function { .. })
+    );
+    if !required {
+        return Ok(None);
+    }
+
+    let calls: Vec<Value> = offered(request)?
+        .iter()
+        .map(|tool| {
+            json!({
+                "type": "object",
+                "properties": {
+                    "name": { "const": tool.function.name },
+                    "arguments": tool.function.parameters.clone().unwrap_or_else(|| json!({ "type": "object" })),
+                },
+                "required": ["name", "arguments"],
+            })
+        })
+        .collect();
+    let rules = json_schema::to_grammar_rules(&json!({ "anyOf": calls }), "call")?;
+    Ok(Some(format!(
+        "{} ::= tool-call (\"\\n\" tool-call)*\ntool-call ::= \"{}\\n\" call \"\\n{}\"\n{}",
+        GRAMMAR_ROOT, CALL_OPEN, CALL_CLOSE, rules
+    )))
+}
+
+/// System turn describing `tools`, after the conversation's own `system` prompt.
+pub fn system_prompt(system: Option<&str>, tools: &[&Tool]) -> String {
+    let mut prompt = String::new();
+    if let Some(system) = system {
+        prompt.push_str(system);
+        prompt.push_str("\n\n");
+    }
+    prompt.push_str(
+        "# Tools\n\nYou may call one or more functions to assist with the user query.\n\n\
+         You are provided with function signatures within <tools></tools> XML tags:\n<tools>",
+    );
+    for tool in tools {
+        prompt.push('\n');
+        prompt.push_str(&serde_json::to_string(tool).unwrap_or_default());
+    }
+    prompt.push_str(
+        "\n</tools>\n\nFor each function call, return a json object with function name and arguments \
+         within <tool_call></tool_call> XML tags:\n<tool_call>\n\
+         {\"name\": <function-name>, \"arguments\": <args-json-object>}\n</tool_call>",
+    );
+    prompt
+}
+
+/// An assistant turn's content followed by the calls it made.
+pub fn assistant_content(message: &ChatMessage) -> String {
+    let mut content = message.content.clone();
+    for call in message.tool_calls.iter().flatten() {
+        let arguments = serde_json::from_str(&call.function.arguments)
+            .unwrap_or_else(|_| Value::String(call.function.arguments.clone()));
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        content.push_str(&format!(
+            "{}\n{}\n{}",
+            CALL_OPEN,
+            json!({ "name": call.function.name, "arguments": arguments }),
+            CALL_CLOSE
+        ));
+    }
+    content
+}
+
+/// A tool result, as the model expects to see it.
+pub fn tool_response(content: &str) -> String {
+    format!("<tool_response>\n{}\n</tool_response>", content)
+}
+
+/// Split generated `text` into the reply and the tool calls in it. Calls that
+/// are not valid JSON are left in the reply.
+pub fn parse_calls(text: &str) -> (String, Vec<ToolCall>) {
+    let mut content = String::new();
+    let mut calls = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find(CALL_OPEN) {
+        content.push_str(&rest[..start]);
+        let body = &rest[start + CALL_OPEN.len()..];
+        let (json, after) = match body.find(CALL_CLOSE) {
+            Some(end) => (&body[..end], &body[end + CALL_CLOSE.len()..]),
+            None => (body, ""),
+        };
+        match parse_call(json) {
+            Some(call) => calls.push(call),
+            None => {
+                warn!("⚠️ Ignoring malformed tool call: '{}'", json.trim());
+                content.push_str(&rest[start..rest.len() - after.len()]);
+            }
+        }
+        rest = after;
+    }
+    content.push_str(rest);
+    (content.trim().to_string(), calls)
+}
+
+fn parse_call(json: &str) -> Option<ToolCall> {
+    let value: Value = serde_json::from_str(json.trim()).ok()?;
+    let name = value.get("name")?.as_str()?.to_string();
+    // Some models say "parameters", as in their tool signatures.
+    let arguments = match value.get("arguments").or_else(|| value.get("parameters")) {
+        Some(Value::String(text)) => text.clone(),
+        Some(arguments) => arguments.to_string(),
+        None => "{}".to_string(),
+    };
+    Some(ToolCall {
+        id: format!("call_{}", uuid::Uuid::new_v4().simple()),
+        kind: "function".to_string(),
+        function: FunctionCall { name, arguments },
+    })
+}
+
+/// Holds back streamed text from the first tool call on, so clients only
+/// see the reply itself. Calls are reported once generation is done.
+#[derive(Debug, Default)]
+pub struct CallFilter {
+    held: String,
+    calling: bool,
+}
+
+impl CallFilter {
+    /// The part of `piece` that can be passed on now.
+    pub fn push(&mut self, piece: &str) -> String {
+        if self.calling {
+            return String::new();
+        }
+        self.held.push_str(piece);
+        if let Some(start) = self.held.find(CALL_OPEN) {
+            self.calling = true;
+            let text = self.held[..start].to_string();
+            self.held.clear();
+            return text;
+        }
+        // Keep back what could be the start of a <tool_call> tag.
+        let partial = (1..CALL_OPEN.len())
+            .rev()
+            .find(|&n| self.held.ends_with(&CALL_OPEN[..n]))
+            .unwrap_or(0);
+        self.held.drain(..self.held.len() - partial).collect()
+    }
+
+    /// Whatever is still held back once generation has ended.
+    pub fn finish(self) -> String {
+        if self.calling {
+            String::new()
+        } else {
+            self.held
+        }
+    }
+}
@@ -0,0 +1,521 @@
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+
+use crate::llm::{
+    CancelToken, ChatRequest, ChatResponse, CompletionRequest, CompletionResponse, EmbeddingsRequest, EmbeddingsResponse,
+    LlmConfig, LlmError, LlmService, LoraAdapterConfig, ProviderConfig, TokenCount,
+};
+use crate::provider::{ChatProvider, LoraAdapters, TokenSink, Tokenizer};
+use crate::reasoning::{ReasoningFilter, THINK_CLOSE, THINK_OPEN};
+
+/// Command-line argument that makes the application binary run as an inference worker.
+pub const WORKER_SUBCOMMAND: &str = "inference-worker";
+
+/// Requests sent from the app to the worker, one JSON object per line on stdin.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WorkerRequest {
+    Start { config: LlmConfig },
+    Chat { request: ChatRequest },
+    Completion { request: CompletionRequest },
+    Embeddings { request: EmbeddingsRequest },
+    Tokenize { text: String, add_bos: bool, parse_special: bool },
+    Detokenize { tokens: Vec<i32>, special: bool },
+    CountTokens { request: ChatRequest },
+    ListLoraAdapters,
+    AttachLoraAdapter { adapter: LoraAdapterConfig },
+    DetachLoraAdapter { path: PathBuf },
+}
+
+impl WorkerRequest {
+    /// The request's own cancel token, for requests that carry one.
+    fn cancel_token(&self) -> Option<&CancelToken> {
+        match self {
+            WorkerRequest::Chat { request } | WorkerRequest::CountTokens { request } => Some(&request.cancel),
+            WorkerRequest::Completion { request } => Some(&request.cancel),
+            _ => None,
+        }
+    }
+}
+
+/// Replies written by the worker, one JSON object per line on stdout.
+type WorkerResponse = Result<serde_json::Value, LlmError>;
+
+/// Pieces of a streamed reply, written by the worker as it generates them,
+/// before the `WorkerResponse` line.
+#[derive(Debug, Serialize, Deserialize)]
+enum WorkerLine {
+    Token(String),
+    Reasoning(String),
+}
+
+struct WorkerProcess {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+/// Handle to an inference worker child process.
+///
+/// The worker is spawned lazily and, if it dies, respawned with the same
+/// configuration on the next request.
+pub struct InferenceWorker {
+    config: LlmConfig,
+    /// Program and arguments run instead of this binary's worker subcommand.
+    command: Option<(String, Vec<String>)>,
+    process: Option<WorkerProcess>,
+    restarts: u32,
+}
+
+impl InferenceWorker {
+    pub fn new(mut config: LlmConfig) -> Self {
+        // The worker itself must load the model in-process.
+        config.isolate_inference = false;
+        config.provider = ProviderConfig::Local;
+        Self {
+            config,
+            command: None,
+            process: None,
+            restarts: 0,
+        }
+    }
+
+    /// Run `program` as the worker instead of this binary.
+    #[cfg(test)]
+    pub(crate) fn with_command(mut self, program: &str, args: &[&str]) -> Self {
+        self.command = Some((program.to_string(), args.iter().map(|arg| arg.to_string()).collect()));
+        self
+    }
+
+    pub async fn start(&mut self, cancel_token: &CancelToken) -> Result<String, LlmError> {
+        if self.process.is_some() {
+            return Ok("Inference worker already running".to_string());
+        }
+
+        let (program, args) = match &self.command {
+            Some((program, args)) => (program.into(), args.clone()),
+            None => (std::env::current_exe()?, vec![WORKER_SUBCOMMAND.to_string()]),
+        };
+        info!("🚀 Spawning inference worker: {} {}", program.display(), args.join(" "));
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take().ok_or_else(|| {
+            LlmError::WorkerCrashed("Worker stdin unavailable".to_string())
+        })?;
+        let stdout = child.stdout.take().ok_or_else(|| {
+            LlmError::WorkerCrashed("Worker stdout unavailable".to_string())
+        })?;
+        self.process = Some(WorkerProcess {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+        });
+
+        let request = WorkerRequest::Start { config: self.config.clone() };
+        let result = self.call(&request, cancel_token, &|_| {}, &|_| {}).await;
+        if result.is_err() {
+            self.kill().await;
+        }
+        result
+    }
+
+    pub async fn chat_completion(&mut self, request: ChatRequest, cancel_token: &CancelToken) -> Result<ChatResponse, LlmError> {
+        self.chat_completion_stream(request, cancel_token, &|_| {}).await
+    }
+
+    /// Run a chat completion, passing each piece of the reply to `on_token`
+    /// as the worker generates it. The worker sorts out the reasoning, which
+    /// is handed on inside its tags again, as the model wrote it.
+    pub async fn chat_completion_stream(
+        &mut self,
+        request: ChatRequest,
+        cancel_token: &CancelToken,
+        on_token: TokenSink<'_>,
+    ) -> Result<ChatResponse, LlmError> {
+        // The worker's filter starts out past a partial reply, as this one does.
+        let messages = request.prompt_messages();
+        let prefill = messages.last().filter(|message| message.role == "assistant");
+        let thinking = prefill.is_some_and(|message| ReasoningFilter::after(&message.content).is_thinking());
+        let thinking = AtomicBool::new(thinking);
+        let on_content = |token: &str| {
+            if thinking.swap(false, Ordering::SeqCst) {
+                on_token(THINK_CLOSE);
+            }
+            on_token(token);
+        };
+        let on_reasoning = |token: &str| {
+            if !thinking.swap(true, Ordering::SeqCst) {
+                on_token(THINK_OPEN);
+            }
+            on_token(token);
+        };
+        self.request_stream(WorkerRequest::Chat { request }, cancel_token, &on_content, &on_reasoning).await
+    }
+
+    pub async fn text_completion(&mut self, request: CompletionRequest, cancel_token: &CancelToken) -> Result<CompletionResponse, LlmError> {
+        self.text_completion_stream(request, cancel_token, &|_| {}).await
+    }
+
+    /// Continue a raw prompt, passing each piece to `on_token` as the worker
+    /// generates it.
+    pub async fn text_completion_stream(
+        &mut self,
+        request: CompletionRequest,
+        cancel_token: &CancelToken,
+        on_token: TokenSink<'_>,
+    ) -> Result<CompletionResponse, LlmError> {
+        self.request_stream(WorkerRequest::Completion { request }, cancel_token, on_token, &|_| {}).await
+    }
+
+    pub async fn embeddings(&mut self, request: EmbeddingsRequest, cancel_token: &CancelToken) -> Result<EmbeddingsResponse, LlmError> {
+        self.request(WorkerRequest::Embeddings { request }, cancel_token).await
+    }
+
+    pub async fn tokenize(&mut self, text: &str, add_bos: bool, parse_special: bool, cancel_token: &CancelToken) -> Result<Vec<i32>, LlmError> {
+        let request = WorkerRequest::Tokenize { text: text.to_string(), add_bos, parse_special };
+        self.request(request, cancel_token).await
+    }
+
+    pub async fn detokenize(&mut self, tokens: &[i32], special: bool, cancel_token: &CancelToken) -> Result<String, LlmError> {
+        let request = WorkerRequest::Detokenize { tokens: tokens.to_vec(), special };
+        self.request(request, cancel_token).await
+    }
+
+    pub async fn count_chat_tokens(&mut self, request: &ChatRequest, cancel_token: &CancelToken) -> Result<u32, LlmError> {
+        let request = WorkerRequest::CountTokens { request: request.clone() };
+        let count: TokenCount = self.request(request, cancel_token).await?;
+        Ok(count.prompt_tokens)
+    }
+
+    pub async fn list_lora_adapters(&mut self, cancel_token: &CancelToken) -> Result<Vec<LoraAdapterConfig>, LlmError> {
+        self.request(WorkerRequest::ListLoraAdapters, cancel_token).await
+    }
+
+    pub async fn attach_lora_adapter(&mut self, adapter: LoraAdapterConfig, cancel_token: &CancelToken) -> Result<(), LlmError> {
+        let request = WorkerRequest::AttachLoraAdapter { adapter: adapter.clone() };
+        self.request::<()>(request, cancel_token).await?;
+        // A respawned worker starts with the adapters attached now.
+        match self.config.lora_adapters.iter_mut().find(|a| a.path == adapter.path) {
+            Some(attached) => attached.scale = adapter.scale,
+            None => self.config.lora_adapters.push(adapter),
+        }
+        Ok(())
+    }
+
+    pub async fn detach_lora_adapter(&mut self, path: &Path, cancel_token: &CancelToken) -> Result<(), LlmError> {
+        let request = WorkerRequest::DetachLoraAdapter { path: path.to_path_buf() };
+        self.request::<()>(request, cancel_token).await?;
+        self.config.lora_adapters.retain(|a| a.path != path);
+        Ok(())
+    }
+
+    /// Send a request, first respawning the worker if it is not running.
+    async fn request<T: DeserializeOwned>(&mut self, request: WorkerRequest, cancel_token: &CancelToken) -> Result<T, LlmError> {
+        self.request_stream(request, cancel_token, &|_| {}, &|_| {}).await
+    }
+
+    /// Like `request`, passing the reply and reasoning pieces the worker
+    /// streams back to `on_token` and `on_reasoning`.
+    async fn request_stream<T: DeserializeOwned>(
+        &mut self,
+        request: WorkerRequest,
+        cancel_token: &CancelToken,
+        on_token: TokenSink<'_>,
+        on_reasoning: TokenSink<'_>,
+    ) -> Result<T, LlmError> {
+        // Notice a worker that died between requests before writing to its pipe.
+        if let Some(process) = self.process.as_mut() {
+            if let Ok(Some(status)) = process.child.try_wait() {
+                warn!("💥 Inference worker exited while idle with {}", status);
+                self.process = None;
+            }
+        }
+
+        if self.process.is_none() {
+            self.restarts += 1;
+            warn!("🔄 Inference worker is not running, restarting (restart #{})", self.restarts);
+            self.start(cancel_token).await?;
+        }
+
+        self.call(&request, cancel_token, on_token, on_reasoning).await
+    }
+
+    async fn kill(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            info!("🛑 Stopping inference worker");
+            if let Err(e) = process.child.kill().await {
+                warn!("⚠️ Failed to kill inference worker: {}", e);
+            }
+        }
+    }
+
+    /// Send one request and wait for its reply, passing on the pieces streamed
+    /// before it. Cancelling `cancel_token` or the request's own token stops
+    /// the worker. If the worker exits before replying it is reaped and
+    /// `WorkerCrashed` is returned; the next request will respawn it.
+    async fn call<T: DeserializeOwned>(
+        &mut self,
+        request: &WorkerRequest,
+        cancel_token: &CancelToken,
+        on_token: TokenSink<'_>,
+        on_reasoning: TokenSink<'_>,
+    ) -> Result<T, LlmError> {
+        let request_cancel = request.cancel_token().cloned().unwrap_or_default();
+        let process = self.process.as_mut().ok_or_else(|| {
+            LlmError::WorkerCrashed("Inference worker is not running".to_string())
+        })?;
+
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        let sent = async {
+            process.stdin.write_all(line.as_bytes()).await?;
+            process.stdin.flush().await
+        }
+        .await;
+
+        let mut reply = String::new();
+        let read = match sent {
+            Ok(()) => loop {
+                reply.clear();
+                let read = tokio::select! {
+                    read = process.stdout.read_line(&mut reply) => Some(read),
+                    _ = cancel_token.cancelled() => None,
+                    _ = request_cancel.cancelled() => None,
+                };
+                // Pass on streamed pieces; any other line is the reply.
+                let piece = match &read {
+                    Some(Ok(n)) if *n > 0 => serde_json::from_str(&reply).ok(),
+                    _ => None,
+                };
+                match piece {
+                    Some(WorkerLine::Token(token)) => on_token(&token),
+                    Some(WorkerLine::Reasoning(token)) => on_reasoning(&token),
+                    None => break read,
+                }
+            },
+            Err(e) => Some(Err(e)),
+        };
+
+        match read {
+            None => {
+                warn!("🛑 Cancelling request by stopping the inference worker");
+                self.kill().await;
+                Err(LlmError::Cancelled)
+            }
+            Some(Ok(n)) if n > 0 => {
+                debug!("📨 Worker reply: {} bytes", n);
+                let response: WorkerResponse = serde_json::from_str(&reply)?;
+                Ok(serde_json::from_value(response?)?)
+            }
+            Some(result) => {
+                let reason = match result {
+                    Err(e) => format!("pipe error: {}", e),
+                    Ok(_) => self.reap().await,
+                };
+                error!("💥 Inference worker crashed mid-request: {}", reason);
+                self.process = None;
+                Err(LlmError::WorkerCrashed(reason))
+            }
+        }
+    }
+
+    async fn reap(&mut self) -> String {
+        match self.process.as_mut() {
+            Some(process) => match process.child.wait().await {
+                Ok(status) => format!("worker exited with {}", status),
+                Err(e) => format!("failed to wait for worker: {}", e),
+            },
+            None => "worker not running".to_string(),
+        }
+    }
+}
+
+/// Local inference through a worker process. Requests are serialised since the
+/// worker handles one at a time; dropping the provider kills the child.
+pub struct WorkerProvider {
+    worker: Mutex<InferenceWorker>,
+    cancel_token: CancelToken,
+}
+
+impl WorkerProvider {
+    pub async fn start(config: LlmConfig, cancel_token: CancelToken) -> Result<Self, LlmError> {
+        let mut worker = InferenceWorker::new(config);
+        let msg = worker.start(&cancel_token).await?;
+        info!("✅ Inference worker ready: {}", msg);
+
+        Ok(Self {
+            worker: Mutex::new(worker),
+            cancel_token,
+        })
+    }
+}
+
+#[async_trait]
+impl ChatProvider for WorkerProvider {
+    fn name(&self) -> &str {
+        "worker"
+    }
+
+    async fn chat_completion(&self, request: ChatRequest) -> Result<ChatResponse, LlmError> {
+        self.worker.lock().await.chat_completion(request, &self.cancel_token).await
+    }
+
+    async fn chat_completion_stream(&self, request: ChatRequest, on_token: TokenSink<'_>) -> Result<ChatResponse, LlmError> {
+        self.worker.lock().await.chat_completion_stream(request, &self.cancel_token, on_token).await
+    }
+
+    async fn text_completion(&self, request: CompletionRequest) -> Result<CompletionResponse, LlmError> {
+        self.worker.lock().await.text_completion(request, &self.cancel_token).await
+    }
+
+    async fn text_completion_stream(&self, request: CompletionRequest, on_token: TokenSink<'_>) -> Result<CompletionResponse, LlmError> {
+        self.worker.lock().await.text_completion_stream(request, &self.cancel_token, on_token).await
+    }
+
+    async fn embeddings(&self, request: EmbeddingsRequest) -> Result<EmbeddingsResponse, LlmError> {
+        self.worker.lock().await.embeddings(request, &self.cancel_token).await
+    }
+
+    fn is_busy(&self) -> bool {
+        self.worker.try_lock().is_err()
+    }
+
+    fn tokenizer(&self) -> Option<&dyn Tokenizer> {
+        Some(self)
+    }
+
+    fn lora_adapters(&self) -> Option<&dyn LoraAdapters> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl Tokenizer for WorkerProvider {
+    async fn tokenize(&self, text: &str, add_bos: bool, parse_special: bool) -> Result<Vec<i32>, LlmError> {
+        self.worker.lock().await.tokenize(text, add_bos, parse_special, &self.cancel_token).await
+    }
+
+    async fn detokenize(&self, tokens: &[i32], special: bool) -> Result<String, LlmError> {
+        self.worker.lock().await.detokenize(tokens, special, &self.cancel_token).await
+    }
+
+    async fn count_chat_tokens(&self, request: &ChatRequest) -> Result<u32, LlmError> {
+        self.worker.lock().await.count_chat_tokens(request, &self.cancel_token).await
+    }
+}
+
+#[async_trait]
+impl LoraAdapters for WorkerProvider {
+    async fn list(&self) -> Result<Vec<LoraAdapterConfig>, LlmError> {
+        self.worker.lock().await.list_lora_adapters(&self.cancel_token).await
+    }
+
+    async fn attach(&self, adapter: LoraAdapterConfig) -> Result<(), LlmError> {
+        self.worker.lock().await.attach_lora_adapter(adapter, &self.cancel_token).await
+    }
+
+    async fn detach(&self, path: &Path) -> Result<(), LlmError> {
+        self.worker.lock().await.detach_lora_adapter(path, &self.cancel_token).await
+    }
+}
+
+/// Entry point for the worker process: serve requests from stdin until it closes.
+pub fn run_worker() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build inference worker runtime");
+
+    runtime.block_on(async {
+        info!("🔧 Inference worker started (pid {})", std::process::id());
+        let mut service = LlmService::new(LlmConfig::default());
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        // Streamed pieces are written from inside generation, so everything
+        // goes through the blocking stdout to keep the lines in order.
+        let send_piece = |piece: WorkerLine| {
+            if let Ok(line) = serde_json::to_string(&piece) {
+                let _ = write_line(&line);
+            }
+        };
+        let on_token = |token: &str| send_piece(WorkerLine::Token(token.to_string()));
+        let on_reasoning = |token: &str| send_piece(WorkerLine::Reasoning(token.to_string()));
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            let response: WorkerResponse = match serde_json::from_str::<WorkerRequest>(&line) {
+                Ok(WorkerRequest::Start { config }) => {
+                    service = LlmService::new(config);
+                    service.start().await.and_then(|msg| Ok(serde_json::to_value(msg)?))
+                }
+                Ok(WorkerRequest::Chat { request }) => service
+                    .chat_completion_stream(request, &on_token, &on_reasoning)
+                    .await
+                    .and_then(|response| Ok(serde_json::to_value(response)?)),
+                Ok(WorkerRequest::Completion { request }) => service
+                    .text_completion_stream(request, &on_token)
+                    .await
+                    .and_then(|response| Ok(serde_json::to_value(response)?)),
+                Ok(WorkerRequest::Embeddings { request }) => service
+                    .embeddings(request)
+                    .await
+                    .and_then(|response| Ok(serde_json::to_value(response)?)),
+                Ok(WorkerRequest::Tokenize { text, add_bos, parse_special }) => service
+                    .tokenize(&text, add_bos, parse_special)
+                    .await
+                    .and_then(|tokens| Ok(serde_json::to_value(tokens)?)),
+                Ok(WorkerRequest::Detokenize { tokens, special }) => service
+                    .detokenize(&tokens, special)
+                    .await
+                    .and_then(|text| Ok(serde_json::to_value(text)?)),
+                Ok(WorkerRequest::CountTokens { request }) => service
+                    .count_tokens(&request)
+                    .await
+                    .and_then(|count| Ok(serde_json::to_value(count)?)),
+                Ok(WorkerRequest::ListLoraAdapters) => service
+                    .list_lora_adapters()
+                    .await
+                    .and_then(|adapters| Ok(serde_json::to_value(adapters)?)),
+                Ok(WorkerRequest::AttachLoraAdapter { adapter }) => service
+                    .attach_lora_adapter(adapter)
+                    .await
+                    .map(|()| serde_json::Value::Null),
+                Ok(WorkerRequest::DetachLoraAdapter { path }) => service
+                    .detach_lora_adapter(&path)
+                    .await
+                    .map(|()| serde_json::Value::Null),
+                Err(e) => Err(e.into()),
+            };
+
+            let reply = serde_json::to_string(&response).unwrap_or_else(|e| {
+                error!("❌ Failed to serialize worker reply: {}", e);
+                serde_json::json!({ "Err": { "SerializationError": e.to_string() } }).to_string()
+            });
+            if write_line(&reply).is_err() {
+                break;
+            }
+        }
+
+        info!("👋 Inference worker exiting");
+    });
+}
+
+/// Write one line to the worker's stdout and flush it to the app.
+fn write_line(line: &str) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", line)?;
+    stdout.flush()
+}
@@ -1,10 +1,11 @@
-import { LlmConfig } from '../types/llm';
+import { LlmConfig, McpServerConfig } from '../types/llm';
 
 export interface AppConfig {
   autoStartLlm: boolean;
   defaultLlmConfig: LlmConfig;
   retryAttempts: number;
   retryDelay: number; // in milliseconds
+  mcpServers: McpServerConfig[];
 }
 
 export const DEFAULT_APP_CONFIG: AppConfig = {
@@ -19,6 +20,7 @@ export const DEFAULT_APP_CONFIG: AppConfig = {
   },
   retryAttempts: 3,
   retryDelay: 2000,
+  mcpServers: [],
 };
 
 // Configuration management utilities
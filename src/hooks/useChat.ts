@@ -129,6 +129,57 @@ export const useChat = () => {
     updateConversation,
   ]);
 
+  // Continue the last assistant message, e.g. after it stopped at max_tokens
+  const continueMessage = useCallback(async () => {
+    if (!isRunning) {
+      throw new Error('LLM service is not running');
+    }
+
+    const conversation = currentConversation;
+    const last = conversation?.messages[conversation.messages.length - 1];
+    if (!conversation || last?.role !== 'assistant') {
+      throw new Error('There is no assistant message to continue');
+    }
+
+    try {
+      setIsLoading(true);
+      setError(undefined);
+
+      // A trailing assistant message is continued rather than answered
+      const response: ChatResponse = await sendChatMessage({
+        model: status.model_name,
+        messages: conversation.messages,
+        temperature: 0.8,
+        top_p: 0.9,
+        max_tokens: 512,
+      });
+
+      const continuation = response.choices?.[0]?.message;
+      if (!continuation) {
+        throw new Error('No response received from LLM service');
+      }
+
+      const reasoning = (last.reasoning_content ?? '') + (continuation.reasoning_content ?? '');
+      const merged: ChatMessage = {
+        ...last,
+        content: last.content + continuation.content,
+        tool_calls: continuation.tool_calls ?? last.tool_calls,
+        reasoning_content: reasoning || undefined,
+      };
+      updateConversation(conversation.id, {
+        messages: [...conversation.messages.slice(0, -1), merged],
+      });
+
+      return response;
+    } catch (error: any) {
+      console.error('Chat error:', error);
+      setError(error.message);
+      throw error;
+    } finally {
+      setIsLoading(false);
+    }
+  }, [isRunning, currentConversation, status.model_name, sendChatMessage, updateConversation]);
+
   // Select conversation
   const selectConversation = useCallback((conversationId: string) => {
     const conversation = conversations.find(c => c.id === conversationId);
@@ -165,6 +216,7 @@ export const useChat = () => {
     // Actions
     createConversation,
     sendMessage,
+    continueMessage,
     selectConversation,
     deleteConversation,
     clearConversations,
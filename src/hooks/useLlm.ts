@@ -5,8 +5,17 @@ import {
   LlmServiceStatus,
   ChatRequest,
   ChatResponse,
+  CompletionRequest,
+  CompletionResponse,
   ModelsResponse,
+  EmbeddingPooling,
+  EmbeddingsResponse,
+  TokenCount,
+  LoraAdapterConfig,
   LlmServiceState,
+  ApiServerConfig,
+  ApiKeyInfo,
+  NewApiKey,
   DEFAULT_LLM_CONFIG,
 } from '../types/llm';
 import { AppConfigManager } from '../config/app';
@@ -106,6 +115,98 @@ export const useLlm = () => {
     }
   }, []);
 
+  // Chat with the Bluetooth and MCP tools; MCP calls may raise approval requests
+  const sendChatWithTools = useCallback(async (request: ChatRequest): Promise<ChatResponse> => {
+    try {
+      setIsLoading(true);
+      return await invoke<ChatResponse>('chat_with_tools', { request });
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    } finally {
+      setIsLoading(false);
+    }
+  }, []);
+
+  // Complete a raw prompt without the chat template
+  const completeText = useCallback(async (request: CompletionRequest): Promise<CompletionResponse> => {
+    try {
+      return await invoke<CompletionResponse>('complete_text', { request });
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
+  // Fill in code between the prompt and suffix (needs a model with FIM tokens)
+  const infillText = useCallback(async (request: CompletionRequest & { suffix: string }): Promise<CompletionResponse> => {
+    try {
+      return await invoke<CompletionResponse>('infill_text', { request });
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
+  // Embed texts with the loaded (or dedicated embedding) model
+  const embedTexts = useCallback(async (texts: string[], pooling?: EmbeddingPooling, normalize?: boolean): Promise<EmbeddingsResponse> => {
+    try {
+      return await invoke<EmbeddingsResponse>('embed_texts', { texts, pooling, normalize });
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
+  // Tokenize text with the loaded local model; parseSpecial (default true) turns special-token text into the token
+  const tokenizeText = useCallback(async (text: string, addBos?: boolean, parseSpecial?: boolean): Promise<number[]> => {
+    try {
+      return await invoke<number[]>('tokenize_text', { text, addBos, parseSpecial });
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
+  // Turn token ids back into text
+  const detokenizeTokens = useCallback(async (tokens: number[], special?: boolean): Promise<string> => {
+    try {
+      return await invoke<string>('detokenize_tokens', { tokens, special });
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
+  // Count prompt tokens for a chat request after templating
+  const countChatTokens = useCallback(async (request: ChatRequest): Promise<TokenCount> => {
+    return await invoke<TokenCount>('count_chat_tokens', { request });
+  }, []);
+
+  // LoRA adapters applied to the local model
+  const listLoraAdapters = useCallback(async (): Promise<LoraAdapterConfig[]> => {
+    return await invoke<LoraAdapterConfig[]>('list_lora_adapters');
+  }, []);
+
+  // Attach a LoRA adapter, or change its scale, without reloading the model
+  const attachLoraAdapter = useCallback(async (path: string, scale?: number): Promise<void> => {
+    try {
+      await invoke('attach_lora_adapter', { path, scale });
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
+  const detachLoraAdapter = useCallback(async (path: string): Promise<void> => {
+    try {
+      await invoke('detach_lora_adapter', { path });
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
   // List available models
   const listModels = useCallback(async (): Promise<ModelsResponse> => {
     try {
@@ -128,6 +229,60 @@ export const useLlm = () => {
     }
   }, []);
 
+  // Start the local OpenAI-compatible API server
+  const startApiServer = useCallback(async (config?: ApiServerConfig) => {
+    try {
+      const message = await invoke<string>('start_api_server', { config });
+      await refreshStatus();
+      return message;
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
+  // Stop the local API server
+  const stopApiServer = useCallback(async () => {
+    try {
+      const message = await invoke<string>('stop_api_server');
+      await refreshStatus();
+      return message;
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
+  // Create an API key for the local API server; the key is only returned once
+  const createApiKey = useCallback(async (name: string): Promise<NewApiKey> => {
+    try {
+      return await invoke<NewApiKey>('create_api_key', { name });
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
+  // Revoke an API key by id
+  const revokeApiKey = useCallback(async (id: string) => {
+    try {
+      return await invoke<string>('revoke_api_key', { id });
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
+  // List API keys with their usage since startup
+  const listApiKeys = useCallback(async (): Promise<ApiKeyInfo[]> => {
+    try {
+      return await invoke<ApiKeyInfo[]>('list_api_keys');
+    } catch (error: any) {
+      setState(prev => ({ ...prev, error: error.message }));
+      throw error;
+    }
+  }, []);
+
   // Clear error
   const clearError = useCallback(() => {
     setState(prev => ({ ...prev, error: undefined }));
@@ -280,10 +435,25 @@ export const useLlm = () => {
     stopService,
     refreshStatus,
     sendChatMessage,
+    sendChatWithTools,
+    completeText,
+    infillText,
+    embedTexts,
+    tokenizeText,
+    detokenizeTokens,
+    countChatTokens,
+    listLoraAdapters,
+    attachLoraAdapter,
+    detachLoraAdapter,
     listModels,
     clearError,
     autoInitializeAndStart,
     checkLlmHealth,
+    startApiServer,
+    stopApiServer,
+    createApiKey,
+    revokeApiKey,
+    listApiKeys,
 
     // Computed properties
     isRunning: state.status.is_running,
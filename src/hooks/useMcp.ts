@@ -0,0 +1,68 @@
+import { useState, useCallback, useEffect } from 'react';
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
+import { McpServerConfig, McpServerInfo, ResourceContents, ToolApprovalRequest } from '../types/llm';
+import { AppConfigManager } from '../config/app';
+
+export const useMcp = () => {
+  const [servers, setServers] = useState<McpServerInfo[]>([]);
+  const [approvalRequests, setApprovalRequests] = useState<ToolApprovalRequest[]>([]);
+  const [error, setError] = useState<string | undefined>();
+
+  const refreshServers = useCallback(async () => {
+    setServers(await invoke<McpServerInfo[]>('list_mcp_servers'));
+  }, []);
+
+  const connectServer = useCallback(async (config: McpServerConfig): Promise<McpServerInfo> => {
+    try {
+      const info = await invoke<McpServerInfo>('connect_mcp_server', { config });
+      await refreshServers();
+      return info;
+    } catch (error: any) {
+      setError(`${config.name}: ${error.message ?? error}`);
+      throw error;
+    }
+  }, [refreshServers]);
+
+  const disconnectServer = useCallback(async (name: string) => {
+    await invoke<string>('disconnect_mcp_server', { name });
+    await refreshServers();
+  }, [refreshServers]);
+
+  const readResource = useCallback(async (server: string, uri: string): Promise<ResourceContents[]> => {
+    return await invoke<ResourceContents[]>('read_mcp_resource', { server, uri });
+  }, []);
+
+  // Answer a tool call the model wants to make
+  const resolveApproval = useCallback(async (id: string, approved: boolean) => {
+    setApprovalRequests(prev => prev.filter(request => request.id !== id));
+    await invoke('resolve_tool_approval', { id, approved });
+  }, []);
+
+  // Connect the servers from the app settings
+  useEffect(() => {
+    const { mcpServers } = AppConfigManager.loadConfig();
+    Promise.allSettled(mcpServers.map(config => connectServer(config))).then(refreshServers);
+  }, [connectServer, refreshServers]);
+
+  useEffect(() => {
+    const unlisten = listen<ToolApprovalRequest>('tool-approval-request', event => {
+      setApprovalRequests(prev => [...prev, event.payload]);
+    });
+    return () => {
+      unlisten.then(stop => stop());
+    };
+  }, []);
+
+  return {
+    servers,
+    approvalRequests,
+    error,
+    connectServer,
+    disconnectServer,
+    refreshServers,
+    readResource,
+    resolveApproval,
+    clearError: () => setError(undefined),
+  };
+};
@@ -7,6 +7,18 @@ export interface BluetoothDevice {
   services: string[];
 }
 
+export interface BluetoothCharacteristic {
+  uuid: string;
+  service: string;
+  properties: string[];
+}
+
+export interface CharacteristicValue {
+  uuid: string;
+  hex: string;
+  text?: string;
+}
+
 export interface ScanResult {
   success: boolean;
   message: string;
@@ -7,32 +7,176 @@ export interface LlmConfig {
   ctx_size: number;
   n_threads?: number;
   n_gpu_layers: number;
+  isolate_inference?: boolean;
+  provider?: ProviderConfig;
+  fallback_providers?: ProviderConfig[];
+  embeddings?: EmbeddingConfig;
+  sampling?: SamplingOptions;
+  speculative?: SpeculativeConfig;
+  lora_adapters?: LoraAdapterConfig[];
 }
 
+// LoRA adapter applied to the local model; path is a file name in the models directory
+export interface LoraAdapterConfig {
+  path: string;
+  scale?: number;
+}
+
+// Speculative decoding with a small draft model sharing the main model's vocabulary
+export interface SpeculativeConfig {
+  draft_model?: string;
+  draft_tokens?: number;
+}
+
+// Penalties, DRY, Mirostat and logit bias for the local model. In LlmConfig
+// they are the defaults, in a ChatRequest overrides for that request.
+export interface SamplingOptions {
+  repeat_penalty?: number;
+  repeat_last_n?: number;
+  frequency_penalty?: number;
+  presence_penalty?: number;
+  dry_multiplier?: number;
+  dry_base?: number;
+  dry_allowed_length?: number;
+  dry_penalty_last_n?: number;
+  dry_sequence_breakers?: string[];
+  mirostat?: 0 | 1 | 2;
+  mirostat_tau?: number;
+  mirostat_eta?: number;
+  // Keyed by token id or by text
+  logit_bias?: Record<string, number>;
+}
+
+export type EmbeddingPooling = 'mean' | 'cls' | 'last' | 'model';
+
+export interface EmbeddingConfig {
+  model?: string;
+  pooling?: EmbeddingPooling;
+  normalize?: boolean;
+}
+
+export interface Embedding {
+  object: string;
+  index: number;
+  embedding: number[];
+  tokens: number;
+}
+
+export interface EmbeddingsResponse {
+  object: string;
+  data: Embedding[];
+  model: string;
+  usage: { prompt_tokens: number; total_tokens: number };
+}
+
+export interface TokenCount {
+  prompt_tokens: number;
+  context_size: number;
+}
+
+export type ProviderConfig =
+  | { type: 'local' }
+  | { type: 'openai'; host: string; port: number; api_key?: string; model?: string };
+
 export interface ChatMessage {
-  role: 'system' | 'user' | 'assistant';
+  role: 'system' | 'user' | 'assistant' | 'tool';
   content: string;
+  // Calls the assistant made in this turn
+  tool_calls?: ToolCall[];
+  // For 'tool' messages, the call this is the result of
+  tool_call_id?: string;
+  // What a thinking model reasoned before replying, split out of content
+  reasoning_content?: string;
+}
+
+export interface Tool {
+  type: 'function';
+  function: {
+    name: string;
+    description?: string;
+    // JSON Schema of the arguments object
+    parameters?: Record<string, unknown>;
+  };
 }
 
-export interface ChatRequest {
+export interface ToolCall {
+  id: string;
+  type: 'function';
+  function: {
+    name: string;
+    // JSON-encoded arguments
+    arguments: string;
+  };
+}
+
+export type ToolChoice = 'none' | 'auto' | 'required' | { type: 'function'; function: { name: string } };
+
+export interface ChatRequest extends SamplingOptions {
   model: string;
   messages: ChatMessage[];
   temperature?: number;
   top_p?: number;
   max_tokens?: number;
   stream?: boolean;
+  // GBNF grammar the reply must match (local models)
+  grammar?: string;
+  // Ask for JSON, optionally matching a JSON Schema
+  response_format?: ResponseFormat;
+  tools?: Tool[];
+  tool_choice?: ToolChoice;
+  // Most tokens a thinking model may spend reasoning (local models)
+  thinking_budget?: number;
+  // Leave the reasoning of earlier assistant turns out of the prompt
+  strip_reasoning?: boolean;
+  // Replies to return, all from the same prompt
+  n?: number;
+  // Replies to generate, keeping the n most likely
+  best_of?: number;
+  // Return each generated token's log-probability
+  logprobs?: boolean;
+  // Likeliest alternatives to return with each token (at most 20)
+  top_logprobs?: number;
 }
 
+export type ResponseFormat =
+  | { type: 'text' }
+  | { type: 'json_object' }
+  | {
+      type: 'json_schema';
+      json_schema?: { name?: string; schema: Record<string, unknown>; strict?: boolean };
+      schema?: Record<string, unknown>;
+    };
+
 export interface ChatChoice {
   index: number;
   message: ChatMessage;
+  logprobs?: ChoiceLogprobs;
   finish_reason?: string;
 }
 
+export interface ChoiceLogprobs {
+  content: TokenLogprob[];
+}
+
+export interface TopLogprob {
+  token: string;
+  logprob: number;
+  bytes: number[];
+}
+
+export interface TokenLogprob extends TopLogprob {
+  top_logprobs: TopLogprob[];
+}
+
 export interface ChatUsage {
   prompt_tokens: number;
   completion_tokens: number;
   total_tokens: number;
+  // Drafted tokens accepted and rejected, with speculative decoding
+  completion_tokens_details?: {
+    accepted_prediction_tokens: number;
+    rejected_prediction_tokens: number;
+  };
 }
 
 export interface ChatResponse {
@@ -42,6 +186,34 @@ export interface ChatResponse {
   model: string;
   choices: ChatChoice[];
   usage?: ChatUsage;
+  provider?: string;
+}
+
+// Raw prompt completion, without the chat template
+export interface CompletionRequest extends SamplingOptions {
+  model: string;
+  prompt: string;
+  suffix?: string;
+  temperature?: number;
+  top_p?: number;
+  max_tokens?: number;
+  stream?: boolean;
+}
+
+export interface CompletionChoice {
+  index: number;
+  text: string;
+  finish_reason?: string;
+}
+
+export interface CompletionResponse {
+  id: string;
+  object: string;
+  created: number;
+  model: string;
+  choices: CompletionChoice[];
+  usage?: ChatUsage;
+  provider?: string;
 }
 
 export interface ModelInfo {
@@ -63,6 +235,25 @@ export interface LlmServiceStatus {
   base_url: string;
 }
 
+export interface ApiServerConfig {
+  port: number;
+  allow_lan?: boolean;
+  cors_origins?: string[];
+}
+
+export interface ApiKeyInfo {
+  id: string;
+  name: string;
+  prefix: string;
+  created_at: string;
+  last_used_at?: string;
+  request_count: number;
+}
+
+export interface NewApiKey extends ApiKeyInfo {
+  key: string;
+}
+
 export interface LlmError {
   error_type: string;
   message: string;
@@ -121,3 +312,47 @@ export const POPULAR_MODELS = [
     description: "Balanced performance and quality",
   },
 ];
+
+export type McpServerConfig = {
+  name: string;
+  // Tools that run without asking first
+  auto_approve?: string[];
+} & (
+  | { type: 'stdio'; command: string; args?: string[]; env?: Record<string, string> }
+  | { type: 'http'; url: string; headers?: Record<string, string> }
+);
+
+export interface McpTool {
+  name: string;
+  description?: string;
+  inputSchema: Record<string, unknown>;
+}
+
+export interface McpResource {
+  uri: string;
+  name: string;
+  description?: string;
+  mimeType?: string;
+}
+
+export interface McpServerInfo {
+  name: string;
+  server_info?: { name: string; version: string };
+  tools: McpTool[];
+  resources: McpResource[];
+}
+
+export interface ResourceContents {
+  uri: string;
+  mimeType?: string;
+  text?: string;
+  blob?: string;
+}
+
+// Sent with the 'tool-approval-request' event; answer with resolve_tool_approval
+export interface ToolApprovalRequest {
+  id: string;
+  server: string;
+  tool: string;
+  arguments: Record<string, unknown>;
+}